@@ -1,30 +1,24 @@
 use map_service::{MapService, MapPoint, PlainMapCarPath};
 use map_service::osm_map::{OsmNode, InnerNode};
 use std::io::Write;
-use std::collections::HashMap;
-use map_service::graph::RoadGraph;
 use std::fs::File;
 
 
 fn main() {
-  let mut ms = MapService {
-    nodes: HashMap::new(),
-    ways: HashMap::new(),
-    graph: RoadGraph::new()
-  };
+  let mut ms = MapService::default();
   let st = std::time::Instant::now();
   // ms.load("map_smol.osm.gz".to_string());
-  ms.load("Moscow.osm.gz".to_string());
+  ms.load_rust("Moscow.osm.gz".to_string(), None).unwrap();
   println!("{}s", (std::time::Instant::now() - st).as_secs_f64());
 
 
   println!("sizeof Node: {}", std::mem::size_of::<OsmNode>());
   println!("sizeof InnerNode: {}", std::mem::size_of::<InnerNode>());
 
-  println!("nodes cnt: {}", ms.nodes.len());
-  println!("ways cnt: {}", ms.ways.len());
+  println!("nodes cnt: {}", ms.graph.nodes_num());
+  println!("links cnt: {}", ms.graph.links_num());
 
-  let path = vec![
+  let path = [
     MapPoint::new(0, 55.78501988250641, 37.73035526275635, None).unwrap(),
     MapPoint::new(0, 55.7865521887118, 37.6966667175293, None).unwrap()
   ];
@@ -196,15 +190,16 @@ fn main() {
   let car_path = PlainMapCarPath {
     id: 1,
     start_at: 0,
-    path: points.iter().collect()
+    path: points.iter().collect(),
+    seats: &vec![4; points.len() - 1]
   };
 
-  let res = ms.build_path_using_cars_rust(0, path.iter().collect(), vec![car_path]);
+  let foot = ms.graph.default_profile(map_service::TransportKind::Foot).unwrap();
+  ms.add_car_path_rust(&car_path).unwrap();
+  let res = ms.build_path_using_cars_rust(0, path.iter().collect(), Default::default(), Default::default(), foot);
   println!("{:?}", res);
   let s = serde_json::to_string_pretty(&res).unwrap();
   File::create("path.json").unwrap().write_all(s.as_bytes()).unwrap();
 
-  let avg_way_len = ms.ways.values().map(|v|v.nodes.len()).sum::<usize>() as f64 / ms.ways.len() as f64;
-  println!("avg_way_len: {}", avg_way_len);
   // std::io::stdin().read(&mut [0u8; 1]).unwrap();
 }
\ No newline at end of file
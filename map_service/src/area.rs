@@ -0,0 +1,207 @@
+//! Area the map is clipped to while loading: bounding box or polygon.
+//!
+//! Polygons are read from GeoJSON (`Polygon`, `MultiPolygon` or features with them)
+//! or osmosis `.poly` files. Rings are combined with the even-odd rule, so holes work
+//! without distinguishing inner and outer rings.
+use std::fs;
+use serde_json::Value;
+use crate::error::MapLoadError;
+
+/// Point as `(lat, lon)`.
+pub type LatLon = (f64, f64);
+
+#[derive(Debug, Clone)]
+pub struct Area {
+  rings: Vec<Vec<LatLon>>,
+  /// `(min, max)` corners of the area bounds
+  bounds: (LatLon, LatLon)
+}
+
+impl Area {
+  pub fn bbox(min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) -> Result<Self, MapLoadError> {
+    if !(min_lat < max_lat && min_lon < max_lon) {
+      return Err(MapLoadError::Area("empty bbox".to_string()));
+    }
+
+    Self::from_rings(vec![vec![
+      (min_lat, min_lon),
+      (min_lat, max_lon),
+      (max_lat, max_lon),
+      (max_lat, min_lon)
+    ]])
+  }
+
+  /// Loads polygon from `.poly` file or GeoJSON, format is chosen by the extension.
+  pub fn load(path: &str) -> Result<Self, MapLoadError> {
+    let content = fs::read_to_string(path)?;
+    if path.ends_with(".poly") {
+      Self::from_poly(&content)
+    } else {
+      Self::from_geojson(&content)
+    }
+  }
+
+  pub fn from_geojson(json: &str) -> Result<Self, MapLoadError> {
+    let value: Value = serde_json::from_str(json).map_err(|e| MapLoadError::Area(e.to_string()))?;
+    let mut rings = Vec::new();
+    geojson_rings(&value, &mut rings)?;
+    Self::from_rings(rings)
+  }
+
+  pub fn from_poly(poly: &str) -> Result<Self, MapLoadError> {
+    let mut lines = poly.lines().map(str::trim).filter(|l| !l.is_empty());
+    // the first line is the polygon name
+    lines.next();
+
+    let mut rings = Vec::new();
+    let mut ring: Option<Vec<LatLon>> = None;
+    for line in lines {
+      match (line, ring.as_mut()) {
+        ("END", Some(_)) => rings.extend(ring.take()),
+        ("END", None) => return Self::from_rings(rings),
+        (_, None) => ring = Some(Vec::new()),
+        (_, Some(points)) => {
+          let mut coords = line.split_whitespace().map(|v| v.parse::<f64>());
+          match (coords.next(), coords.next()) {
+            (Some(Ok(lon)), Some(Ok(lat))) => points.push((lat, lon)),
+            _ => return Err(MapLoadError::Area(format!("bad poly line \"{}\"", line)))
+          }
+        }
+      }
+    }
+
+    Err(MapLoadError::Area("poly file is not terminated by END".to_string()))
+  }
+
+  fn from_rings(mut rings: Vec<Vec<LatLon>>) -> Result<Self, MapLoadError> {
+    for ring in rings.iter_mut() {
+      if ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+      }
+    }
+    rings.retain(|r| r.len() >= 3);
+
+    let mut points = rings.iter().flatten();
+    let first = *points.next().ok_or_else(|| MapLoadError::Area("area has no polygons".to_string()))?;
+    let bounds = points.fold((first, first), |((min_lat, min_lon), (max_lat, max_lon)), (lat, lon)| {
+      ((min_lat.min(*lat), min_lon.min(*lon)), (max_lat.max(*lat), max_lon.max(*lon)))
+    });
+
+    Ok(Self { rings, bounds })
+  }
+
+  pub fn contains(&self, (lat, lon): LatLon) -> bool {
+    let ((min_lat, min_lon), (max_lat, max_lon)) = self.bounds;
+    if lat < min_lat || lat > max_lat || lon < min_lon || lon > max_lon {
+      return false;
+    }
+
+    let mut inside = false;
+    for (a, b) in self.edges() {
+      if (a.0 > lat) != (b.0 > lat) && lon < a.1 + (lat - a.0) / (b.0 - a.0) * (b.1 - a.1) {
+        inside = !inside;
+      }
+    }
+
+    inside
+  }
+
+  /// Cohen-Sutherland code of the point position relative to the area bounds:
+  /// segment can't cross the area if codes of its ends have a common bit.
+  pub fn outcode(&self, (lat, lon): LatLon) -> u8 {
+    let ((min_lat, min_lon), (max_lat, max_lon)) = self.bounds;
+    (lat < min_lat) as u8 | ((lat > max_lat) as u8) << 1 | ((lon < min_lon) as u8) << 2 | ((lon > max_lon) as u8) << 3
+  }
+
+  /// Parameters `t` in (0, 1) of the points where segment `p + t * (q - p)` crosses the border,
+  /// sorted ascending.
+  pub fn crossings(&self, p: LatLon, q: LatLon) -> Vec<f64> {
+    let ((min_lat, min_lon), (max_lat, max_lon)) = self.bounds;
+    if p.0.max(q.0) < min_lat || p.0.min(q.0) > max_lat || p.1.max(q.1) < min_lon || p.1.min(q.1) > max_lon {
+      return Vec::new();
+    }
+
+    let d = (q.0 - p.0, q.1 - p.1);
+    let mut res = Vec::new();
+    for (a, b) in self.edges() {
+      let e = (b.0 - a.0, b.1 - a.1);
+      let denom = d.0 * e.1 - d.1 * e.0;
+      if denom == 0.0 {
+        continue;
+      }
+      let ap = (a.0 - p.0, a.1 - p.1);
+      let t = (ap.0 * e.1 - ap.1 * e.0) / denom;
+      let u = (ap.0 * d.1 - ap.1 * d.0) / denom;
+      if t > 0.0 && t < 1.0 && (0.0..=1.0).contains(&u) {
+        res.push(t);
+      }
+    }
+    res.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    res.dedup();
+
+    res
+  }
+
+  fn edges(&self) -> impl Iterator<Item = (LatLon, LatLon)> + '_ {
+    self.rings.iter().flat_map(|r| r.iter().copied().zip(r.iter().copied().cycle().skip(1)))
+  }
+}
+
+fn geojson_rings(value: &Value, rings: &mut Vec<Vec<LatLon>>) -> Result<(), MapLoadError> {
+  match value["type"].as_str() {
+    Some("FeatureCollection") => {
+      for feature in value["features"].as_array().into_iter().flatten() {
+        geojson_rings(feature, rings)?;
+      }
+    },
+    Some("Feature") => geojson_rings(&value["geometry"], rings)?,
+    Some("Polygon") => rings.extend(polygon_rings(&value["coordinates"])?),
+    Some("MultiPolygon") => {
+      for polygon in value["coordinates"].as_array().into_iter().flatten() {
+        rings.extend(polygon_rings(polygon)?);
+      }
+    },
+    t => return Err(MapLoadError::Area(format!("unsupported geojson type {:?}", t)))
+  }
+
+  Ok(())
+}
+
+fn polygon_rings(coordinates: &Value) -> Result<Vec<Vec<LatLon>>, MapLoadError> {
+  let bad_coordinates = || MapLoadError::Area("bad polygon coordinates".to_string());
+  coordinates.as_array().ok_or_else(bad_coordinates)?.iter()
+    .map(|ring| ring.as_array().ok_or_else(bad_coordinates)?.iter()
+      .map(|p| match (p[0].as_f64(), p[1].as_f64()) {
+        (Some(lon), Some(lat)) => Ok((lat, lon)),
+        _ => Err(bad_coordinates())
+      })
+      .collect())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn poly_with_hole() {
+    let poly = "district\n1\n  37.0 55.0\n  38.0 55.0\n  38.0 56.0\n  37.0 56.0\nEND\n!2\n  37.4 55.4\n  37.6 55.4\n  37.6 55.6\n  37.4 55.6\nEND\nEND\n";
+    let area = Area::from_poly(poly).unwrap();
+    assert!(area.contains((55.2, 37.2)));
+    assert!(!area.contains((55.5, 37.5)));
+    assert!(!area.contains((56.5, 37.5)));
+    let crossings = area.crossings((55.5, 36.5), (55.5, 37.5));
+    assert_eq!(crossings.len(), 2);
+    assert!((crossings[0] - 0.5).abs() < 1e-9 && (crossings[1] - 0.9).abs() < 1e-9);
+  }
+
+  #[test]
+  fn geojson_polygon() {
+    let json = r#"{"type": "Feature", "properties": {}, "geometry": {"type": "Polygon",
+      "coordinates": [[[37.0, 55.0], [38.0, 55.0], [37.0, 56.0], [37.0, 55.0]]]}}"#;
+    let area = Area::from_geojson(json).unwrap();
+    assert!(area.contains((55.2, 37.2)));
+    assert!(!area.contains((55.8, 37.8)));
+    assert!(Area::from_geojson(r#"{"type": "Point", "coordinates": [37.0, 55.0]}"#).is_err());
+  }
+}
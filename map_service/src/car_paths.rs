@@ -0,0 +1,328 @@
+//! Car paths of drivers kept between queries.
+//!
+//! Points of the paths are stored in slots reused after the paths are removed, queries see the slots
+//! as nodes following the road nodes and the nodes they add. Points are indexed by the road node
+//! they are placed at, ordered by the time the car passes them.
+use std::collections::HashMap;
+use crate::{distance, distance_t, Kmh, MapPoint, PlainMapCarPath};
+use crate::graph::{RoadGraph, NodeId};
+use crate::error::CarPathError;
+use crate::reservation::Reservations;
+
+/// Speed cars are expected to move along their paths
+pub const CAR_PATH_SPEED: Kmh = Kmh(50);
+
+#[derive(Debug, Clone)]
+pub struct CarPoint {
+  pub path_id: u64,
+  /// index of the point in the path
+  pub index: usize,
+  pub osm_id: u64,
+  pub lat: f64,
+  pub lon: f64,
+  /// road node the point is placed at
+  pub node: NodeId,
+  /// time the car passes the point, ms since unix epoch
+  pub eta: i64,
+  /// seats on the segment to the next point
+  pub seats: u8,
+  /// length of the segment to the next point in cm
+  pub len: u32,
+  /// slot of the next point, the car goes from the point only there
+  pub next: Option<usize>
+}
+
+impl CarPoint {
+  pub fn map_point(&self) -> MapPoint {
+    MapPoint { id: self.osm_id, lat: self.lat, lon: self.lon, path_id: Some(self.path_id) }
+  }
+}
+
+#[derive(Default)]
+pub struct CarPaths {
+  points: Vec<Option<CarPoint>>,
+  free_slots: Vec<usize>,
+  /// slots of the points by path id
+  paths: HashMap<u64, Vec<usize>>,
+  /// slots of the points at road nodes, ordered by eta
+  by_node: HashMap<NodeId, Vec<usize>>
+}
+
+impl CarPaths {
+  /// Number of slots, free ones included.
+  pub fn slots(&self) -> usize {
+    self.points.len()
+  }
+
+  pub fn point(&self, slot: usize) -> Option<&CarPoint> {
+    self.points.get(slot).and_then(Option::as_ref)
+  }
+
+  /// Slots of the points at the road node ordered by eta.
+  pub fn at_node(&self, node: NodeId) -> &[usize] {
+    self.by_node.get(&node).map_or(&[], Vec::as_slice)
+  }
+
+  pub fn contains(&self, id: u64) -> bool {
+    self.paths.contains_key(&id)
+  }
+
+  /// Points of the path in the order of passing them.
+  pub fn path(&self, id: u64) -> Option<Vec<&CarPoint>> {
+    let slots = self.paths.get(&id)?;
+    Some(slots.iter().map(|s| self.points[*s].as_ref().unwrap()).collect())
+  }
+
+  /// Seats on the segments of the path.
+  pub fn seats(&self, id: u64) -> Option<Vec<u8>> {
+    let slots = self.paths.get(&id)?;
+    Some(slots[..slots.len() - 1].iter().map(|s| self.points[*s].as_ref().unwrap().seats).collect())
+  }
+
+  pub fn add(&mut self, graph: &RoadGraph, path: &PlainMapCarPath) -> Result<(), CarPathError> {
+    if self.contains(path.id) {
+      return Err(CarPathError::Duplicate(path.id));
+    }
+    let nodes = Self::road_nodes(graph, path)?;
+    self.insert(path, nodes);
+
+    Ok(())
+  }
+
+  /// Replaces the path with the same id.
+  pub fn update(&mut self, graph: &RoadGraph, path: &PlainMapCarPath) -> Result<(), CarPathError> {
+    if !self.contains(path.id) {
+      return Err(CarPathError::Unknown(path.id));
+    }
+    let nodes = Self::road_nodes(graph, path)?;
+    self.remove(path.id)?;
+    self.insert(path, nodes);
+
+    Ok(())
+  }
+
+  fn road_nodes(graph: &RoadGraph, path: &PlainMapCarPath) -> Result<Vec<NodeId>, CarPathError> {
+    if path.path.is_empty() {
+      return Err(CarPathError::Empty(path.id));
+    }
+    path.path.iter()
+      .map(|p| graph.node_id_by_osm_id(p.id).ok_or(CarPathError::UnknownNode { path_id: path.id, osm_id: p.id }))
+      .collect()
+  }
+
+  fn insert(&mut self, path: &PlainMapCarPath, nodes: Vec<NodeId>) {
+    let slots: Vec<usize> = (0..nodes.len()).map(|_| self.take_slot()).collect();
+    let mut eta = path.start_at * 1000;
+    for (i, (p, node)) in path.path.iter().zip(nodes).enumerate() {
+      let next = path.path.get(i + 1);
+      self.points[slots[i]] = Some(CarPoint {
+        path_id: path.id,
+        index: i,
+        osm_id: p.id,
+        lat: p.lat,
+        lon: p.lon,
+        node,
+        eta,
+        seats: path.seats.get(i).copied().unwrap_or(0),
+        len: next.map_or(0, |n| distance(*p, *n)),
+        next: slots.get(i + 1).copied()
+      });
+
+      let points = &self.points;
+      let at_node = self.by_node.entry(node).or_default();
+      let pos = at_node.partition_point(|s| points[*s].as_ref().unwrap().eta <= eta);
+      at_node.insert(pos, slots[i]);
+
+      eta += next.map_or(0, |n| distance_t(*p, *n, CAR_PATH_SPEED) as i64);
+    }
+    self.paths.insert(path.id, slots);
+  }
+
+  pub fn remove(&mut self, id: u64) -> Result<OwnedCarPath, CarPathError> {
+    let slots = self.paths.remove(&id).ok_or(CarPathError::Unknown(id))?;
+    let mut removed = OwnedCarPath { id, start_at: 0, points: Vec::new(), seats: Vec::new() };
+    for slot in slots {
+      let p = self.points[slot].take().unwrap();
+      if p.index == 0 {
+        removed.start_at = p.eta / 1000;
+      }
+      if p.next.is_some() {
+        removed.seats.push(p.seats);
+      }
+      let at_node = self.by_node.get_mut(&p.node).unwrap();
+      at_node.retain(|s| *s != slot);
+      if at_node.is_empty() {
+        self.by_node.remove(&p.node);
+      }
+      removed.points.push(MapPoint { path_id: None, ..p.map_point() });
+      self.free_slots.push(slot);
+    }
+
+    Ok(removed)
+  }
+
+  /// Places the paths on the new graph, paths with points missing in it are dropped.
+  /// Returns ids of the dropped paths.
+  pub fn rebind(&mut self, graph: &RoadGraph) -> Vec<u64> {
+    let ids: Vec<u64> = self.paths.keys().copied().collect();
+    let paths: Vec<OwnedCarPath> = ids.into_iter().map(|id| self.remove(id).unwrap()).collect();
+    *self = CarPaths::default();
+
+    paths.iter()
+      .filter(|p| self.add(graph, &p.as_plain()).is_err())
+      .map(|p| p.id)
+      .collect()
+  }
+
+  fn take_slot(&mut self) -> usize {
+    self.free_slots.pop().unwrap_or_else(|| {
+      self.points.push(None);
+      self.points.len() - 1
+    })
+  }
+}
+
+/// Car path removed from the registry.
+pub struct OwnedCarPath {
+  pub id: u64,
+  pub start_at: i64,
+  pub points: Vec<MapPoint>,
+  pub seats: Vec<u8>
+}
+
+impl OwnedCarPath {
+  pub fn as_plain(&self) -> PlainMapCarPath<'_> {
+    PlainMapCarPath { id: self.id, start_at: self.start_at, path: self.points.iter().collect(), seats: &self.seats }
+  }
+}
+
+/// How riders get in and out of cars, times are in ms.
+#[derive(Debug, Copy, Clone)]
+pub struct Boarding {
+  /// longest wait for a car at the boarding point
+  pub max_wait: u32,
+  /// time the car stands to let the rider in or out
+  pub dwell: u32,
+  /// time the driver could wait for a late rider
+  pub driver_tolerance: u32
+}
+
+impl Default for Boarding {
+  fn default() -> Self {
+    Boarding {
+      max_wait: 15 * 60 * 1000,
+      dwell: 30 * 1000,
+      driver_tolerance: 0
+    }
+  }
+}
+
+impl Boarding {
+  /// Time from the rider's arrival to the boarding point to the car leaving it with the rider,
+  /// `None` if they miss each other. The car passes the point at `car_eta`.
+  pub fn board_cost(&self, arrival: u32, car_eta: i64) -> Option<u32> {
+    let wait = car_eta - arrival as i64;
+    if wait > self.max_wait as i64 || -wait > self.driver_tolerance as i64 {
+      return None;
+    }
+    Some(wait.max(0) as u32 + self.dwell)
+  }
+}
+
+/// Limits of changing cars, a transfer is every ride after the first one.
+#[derive(Debug, Copy, Clone)]
+pub struct Transfers {
+  pub max: u32,
+  /// added to the cost of a path for every transfer, ms
+  pub penalty: u32
+}
+
+impl Default for Transfers {
+  fn default() -> Self {
+    Transfers {
+      max: 1,
+      penalty: 5 * 60 * 1000
+    }
+  }
+}
+
+/// Car paths and seats reserved on them as seen by a query.
+#[derive(Copy, Clone)]
+pub struct Cars<'a> {
+  pub paths: &'a CarPaths,
+  pub reservations: &'a Reservations,
+  /// query start, ms since unix epoch
+  pub start_at: i64,
+  pub boarding: Boarding,
+  pub transfers: Transfers
+}
+
+impl<'a> Cars<'a> {
+  /// Seats left on the segment to the next point.
+  pub fn free_seats(&self, p: &CarPoint) -> u8 {
+    p.seats.saturating_sub(self.reservations.taken(p.path_id, p.index))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::graph::{GraphBuilder, Access};
+  use crate::profile::default_profiles;
+
+  #[test]
+  fn boarding_windows() {
+    let boarding = Boarding { max_wait: 600, dwell: 10, driver_tolerance: 100 };
+    assert_eq!(boarding.board_cost(1000, 1500), Some(510));
+    assert_eq!(boarding.board_cost(1000, 1600), Some(610));
+    assert_eq!(boarding.board_cost(1000, 1601), None);
+    // the driver waits for the late rider
+    assert_eq!(boarding.board_cost(1000, 900), Some(10));
+    assert_eq!(boarding.board_cost(1000, 899), None);
+  }
+
+  #[test]
+  fn slots_are_reused_and_indexed_by_node() {
+    let mut builder = GraphBuilder::new();
+    for id in 1..=3 {
+      builder.add_node(id, 55.75, 37.6 + id as f64 * 0.001);
+    }
+    builder.connect_two_way(1, 2, 6300, Access { foot: true, car: true }, None);
+    builder.connect_two_way(2, 3, 6300, Access { foot: true, car: true }, None);
+    let graph = builder.build(default_profiles());
+    let point = |id: u64| MapPoint { id, lat: 55.75, lon: 37.6 + id as f64 * 0.001, path_id: None };
+    let points: Vec<MapPoint> = (1..=3).map(point).collect();
+    let path = |id, start_at, points: &[u64]| OwnedCarPath {
+      id,
+      start_at,
+      points: points.iter().map(|p| point(*p)).collect(),
+      seats: vec![3; points.len() - 1]
+    };
+
+    let mut paths = CarPaths::default();
+    let first = path(1, 100, &[1, 2, 3]);
+    paths.add(&graph, &first.as_plain()).unwrap();
+    let second = path(2, 50, &[3, 2]);
+    paths.add(&graph, &second.as_plain()).unwrap();
+    assert!(matches!(paths.add(&graph, &second.as_plain()), Err(CarPathError::Duplicate(2))));
+    let unknown = path(3, 0, &[1, 4]);
+    assert!(matches!(paths.add(&graph, &unknown.as_plain()), Err(CarPathError::UnknownNode { osm_id: 4, .. })));
+    assert_eq!(paths.slots(), 5);
+
+    // the second path passes the middle node earlier
+    let node = graph.node_id_by_osm_id(2).unwrap();
+    let at_node: Vec<u64> = paths.at_node(node).iter().map(|s| paths.point(*s).unwrap().path_id).collect();
+    assert_eq!(at_node, vec![2, 1]);
+    let first_at_node = paths.point(paths.at_node(node)[1]).unwrap();
+    assert_eq!(first_at_node.eta, 100_000 + distance_t(&points[0], &points[1], CAR_PATH_SPEED) as i64);
+    assert_eq!(paths.seats(1), Some(vec![3, 3]));
+
+    let removed = paths.remove(2).unwrap();
+    assert_eq!((removed.start_at, removed.points.len(), removed.seats), (50, 2, vec![3]));
+    assert!(matches!(paths.remove(2), Err(CarPathError::Unknown(2))));
+    assert_eq!(paths.at_node(node).len(), 1);
+    let third = path(3, 0, &[2, 3]);
+    paths.add(&graph, &third.as_plain()).unwrap();
+    assert_eq!(paths.slots(), 5);
+  }
+}
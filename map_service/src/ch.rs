@@ -0,0 +1,442 @@
+//! Contraction hierarchies for plain routing with a profile.
+//!
+//! Nodes are contracted one by one from the least important ones, shortcuts added between
+//! their neighbours keep the costs of the paths through them. A query searches only towards
+//! the nodes contracted later from both ends, so it settles a small part of the graph.
+//! Hierarchies know nothing about turn restrictions and car paths, searches with car paths and
+//! searches of paths breaking the restrictions use the graph itself.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use crate::csr::{Slab, Pod};
+use crate::graph::{RoadGraph, NodeId, ProfileId};
+
+/// `middle` of the links which are not shortcuts
+pub const NO_MIDDLE: u32 = u32::MAX;
+/// Nodes settled by a witness search, shortcuts are added if no witness is found within them.
+const WITNESS_SETTLED: usize = 500;
+
+/// Link of the hierarchy, a shortcut of the links through `middle` if it's set.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChLink {
+  pub to: u32,
+  /// search cost in ms
+  pub cost: u32,
+  pub middle: u32
+}
+
+unsafe impl Pod for ChLink {}
+
+pub struct Hierarchy {
+  /// name of the profile
+  pub profile: String,
+  /// checksum of the profile costs the hierarchy is built with, see [`RoadGraph::costs_checksum`]
+  pub costs_checksum: u32,
+  /// order of contraction of the nodes
+  pub ranks: Slab<u32>,
+  /// links to the nodes of higher rank
+  pub up_offsets: Slab<u32>,
+  pub up_links: Slab<ChLink>,
+  /// reversed links from the nodes of higher rank
+  pub down_offsets: Slab<u32>,
+  pub down_links: Slab<ChLink>
+}
+
+/// Links between not contracted nodes as a cost and a middle node by the other end of the link.
+type Links = Vec<HashMap<u32, (u32, u32)>>;
+
+impl Hierarchy {
+  pub fn build(graph: &RoadGraph, profile: ProfileId) -> Self {
+    let n = graph.nodes_num();
+    let mut out: Links = vec![HashMap::new(); n];
+    let mut inc: Links = vec![HashMap::new(); n];
+    for a in graph.road_nodes() {
+      for link in graph.links(a) {
+        if let (Some(cost), true) = (graph.link_cost(&link, profile), link.node != a) {
+          add_link(&mut out, &mut inc, a.0 as u32, link.node.0 as u32, cost, NO_MIDDLE);
+        }
+      }
+    }
+
+    let mut witness = Witness::new(n);
+    let mut deleted = vec![0i64; n];
+    let mut queue: BinaryHeap<Reverse<(i64, u32)>> = (0..n as u32)
+      .map(|v| Reverse((priority(&out, &inc, &mut witness, &deleted, v), v)))
+      .collect();
+    let mut ranks = vec![0u32; n];
+    let mut up: Vec<Vec<ChLink>> = vec![Vec::new(); n];
+    let mut down: Vec<Vec<ChLink>> = vec![Vec::new(); n];
+    let mut rank = 0;
+    while let Some(Reverse((_, v))) = queue.pop() {
+      // priorities change with contraction of the neighbours, they are updated lazily
+      let p = priority(&out, &inc, &mut witness, &deleted, v);
+      if queue.peek().is_some_and(|Reverse((next, _))| p > *next) {
+        queue.push(Reverse((p, v)));
+        continue;
+      }
+
+      let shortcuts = shortcuts(&out, &inc, &mut witness, v);
+      ranks[v as usize] = rank;
+      rank += 1;
+      for (w, (cost, middle)) in std::mem::take(&mut out[v as usize]) {
+        inc[w as usize].remove(&v);
+        deleted[w as usize] += 1;
+        up[v as usize].push(ChLink { to: w, cost, middle });
+      }
+      for (u, (cost, middle)) in std::mem::take(&mut inc[v as usize]) {
+        out[u as usize].remove(&v);
+        deleted[u as usize] += 1;
+        down[v as usize].push(ChLink { to: u, cost, middle });
+      }
+      for (u, w, cost) in shortcuts {
+        add_link(&mut out, &mut inc, u, w, cost, v);
+      }
+    }
+
+    let (up_offsets, up_links) = pack(up);
+    let (down_offsets, down_links) = pack(down);
+    Hierarchy {
+      profile: graph.profile(profile).name.clone(),
+      costs_checksum: graph.costs_checksum(profile),
+      ranks: Slab::Owned(ranks),
+      up_offsets,
+      up_links,
+      down_offsets,
+      down_links
+    }
+  }
+
+  fn up(&self, node: u32) -> &[ChLink] {
+    &self.up_links[self.up_offsets[node as usize] as usize..self.up_offsets[node as usize + 1] as usize]
+  }
+
+  fn down(&self, node: u32) -> &[ChLink] {
+    &self.down_links[self.down_offsets[node as usize] as usize..self.down_offsets[node as usize + 1] as usize]
+  }
+
+  /// Checks that the arrays describe a hierarchy of `nodes_num` nodes, mapped data is not trusted.
+  /// Links lead to the nodes of higher rank and shortcuts go through the nodes of lower rank
+  /// by existing links, so unpacking of the shortcuts ends.
+  pub fn validate(&self, nodes_num: usize) -> Result<(), String> {
+    if self.ranks.len() != nodes_num {
+      return Err("hierarchy ranks don't match the graph".to_string());
+    }
+    for (offsets, links) in [(&self.up_offsets, &self.up_links), (&self.down_offsets, &self.down_links)].iter() {
+      if offsets.len() != nodes_num + 1 || offsets[0] != 0 || offsets[nodes_num] as usize != links.len() || offsets.windows(2).any(|w| w[0] > w[1]) {
+        return Err("bad hierarchy link offsets".to_string());
+      }
+    }
+    for (is_up, offsets, links) in [(true, &self.up_offsets, &self.up_links), (false, &self.down_offsets, &self.down_links)].iter() {
+      for v in 0..nodes_num {
+        for l in links[offsets[v] as usize..offsets[v + 1] as usize].iter() {
+          let rank_ok = (l.to as usize) < nodes_num && self.ranks[l.to as usize] > self.ranks[v];
+          let middle_ok = l.middle == NO_MIDDLE || ((l.middle as usize) < nodes_num && self.ranks[l.middle as usize] < self.ranks[v]);
+          if !rank_ok || !middle_ok {
+            return Err("bad hierarchy link".to_string());
+          }
+          let (from, to) = if *is_up { (v as u32, l.to) } else { (l.to, v as u32) };
+          if l.middle != NO_MIDDLE && (self.find_link(from, l.middle).is_none() || self.find_link(l.middle, to).is_none()) {
+            return Err("bad hierarchy shortcut".to_string());
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Searches the path from any of the `sources` to any of the `targets` given with the costs
+  /// to reach them. Returns road nodes of the path with the costs to reach them, `None` if
+  /// no target is reachable.
+  pub fn query(&self, sources: &[(NodeId, u32)], targets: &[(NodeId, u32)]) -> Option<Vec<(NodeId, u32)>> {
+    let forward = self.upward_search(sources, |v| self.up(v));
+    let backward = self.upward_search(targets, |v| self.down(v));
+
+    let (_, meet) = forward.iter()
+      .filter_map(|(v, (cost, _))| backward.get(v).map(|(back, _)| (*cost as u64 + *back as u64, *v)))
+      .min()?;
+
+    let mut nodes = vec![meet];
+    let mut curr = meet;
+    while let Some(prev) = forward[&curr].1 {
+      nodes.push(prev);
+      curr = prev;
+    }
+    nodes.reverse();
+    let mut curr = meet;
+    while let Some(next) = backward[&curr].1 {
+      nodes.push(next);
+      curr = next;
+    }
+
+    let first = nodes[0];
+    let mut path = vec![(NodeId(first as usize), forward[&first].0)];
+    for pair in nodes.windows(2) {
+      self.unpack(pair[0], pair[1], &mut path);
+    }
+
+    Some(path)
+  }
+
+  /// Dijkstra search by the links to the nodes of higher rank, returns costs and previous nodes.
+  fn upward_search<'a>(&'a self, seeds: &[(NodeId, u32)], links: impl Fn(u32) -> &'a [ChLink]) -> HashMap<u32, (u32, Option<u32>)> {
+    let mut labels: HashMap<u32, (u32, Option<u32>)> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for (node, cost) in seeds.iter().copied() {
+      let v = node.0 as u32;
+      if labels.get(&v).is_none_or(|(c, _)| cost < *c) {
+        labels.insert(v, (cost, None));
+        queue.push(Reverse((cost, v)));
+      }
+    }
+
+    while let Some(Reverse((cost, v))) = queue.pop() {
+      if cost > labels[&v].0 {
+        continue;
+      }
+      for link in links(v) {
+        let next = cost + link.cost;
+        if labels.get(&link.to).is_none_or(|(c, _)| next < *c) {
+          labels.insert(link.to, (next, Some(v)));
+          queue.push(Reverse((next, link.to)));
+        }
+      }
+    }
+
+    labels
+  }
+
+  fn find_link(&self, from: u32, to: u32) -> Option<ChLink> {
+    if self.ranks[to as usize] > self.ranks[from as usize] {
+      self.up(from).iter().find(|l| l.to == to).copied()
+    } else {
+      self.down(to).iter().find(|l| l.to == from).map(|l| ChLink { to, ..*l })
+    }
+  }
+
+  /// Appends the road nodes of the link from `from` to `to` after `from`, costs continue the ones of the path.
+  fn unpack(&self, from: u32, to: u32, path: &mut Vec<(NodeId, u32)>) {
+    let mut stack = vec![(from, to)];
+    while let Some((a, b)) = stack.pop() {
+      // links of shortcuts exist in valid hierarchies
+      let link = self.find_link(a, b).unwrap();
+      if link.middle == NO_MIDDLE {
+        let cost = path.last().unwrap().1 + link.cost;
+        path.push((NodeId(b as usize), cost));
+      } else {
+        stack.push((link.middle, b));
+        stack.push((a, link.middle));
+      }
+    }
+  }
+}
+
+fn add_link(out: &mut Links, inc: &mut Links, a: u32, b: u32, cost: u32, middle: u32) {
+  let current = out[a as usize].get(&b).map_or(u32::MAX, |(c, _)| *c);
+  if cost < current {
+    out[a as usize].insert(b, (cost, middle));
+    inc[b as usize].insert(a, (cost, middle));
+  }
+}
+
+fn pack(links: Vec<Vec<ChLink>>) -> (Slab<u32>, Slab<ChLink>) {
+  let mut offsets = Vec::with_capacity(links.len() + 1);
+  offsets.push(0);
+  let mut packed = Vec::new();
+  for node_links in links {
+    packed.extend(node_links);
+    offsets.push(packed.len() as u32);
+  }
+
+  (Slab::Owned(offsets), Slab::Owned(packed))
+}
+
+/// Edge difference of the contraction plus contracted neighbours, the last spreads contraction over the graph.
+fn priority(out: &Links, inc: &Links, witness: &mut Witness, deleted: &[i64], v: u32) -> i64 {
+  let shortcuts = shortcuts(out, inc, witness, v).len() as i64;
+  shortcuts - (out[v as usize].len() + inc[v as usize].len()) as i64 + deleted[v as usize]
+}
+
+/// Shortcuts needed to contract the node as their ends and costs.
+fn shortcuts(out: &Links, inc: &Links, witness: &mut Witness, v: u32) -> Vec<(u32, u32, u32)> {
+  let mut found = Vec::new();
+  for (u, (to_v, _)) in inc[v as usize].iter() {
+    let targets: Vec<(u32, u32)> = out[v as usize].iter()
+      .filter(|(w, _)| *w != u)
+      .map(|(w, (from_v, _))| (*w, to_v + from_v))
+      .collect();
+    let max_cost = match targets.iter().map(|(_, c)| *c).max() {
+      Some(c) => c,
+      None => continue
+    };
+    witness.search(out, *u, v, max_cost);
+    found.extend(targets.into_iter().filter(|(w, cost)| witness.cost(*w) > *cost).map(|(w, cost)| (*u, w, cost)));
+  }
+
+  found
+}
+
+/// Limited search of the paths avoiding the contracted node.
+struct Witness {
+  costs: Vec<u32>,
+  touched: Vec<u32>
+}
+
+impl Witness {
+  fn new(n: usize) -> Self {
+    Witness { costs: vec![u32::MAX; n], touched: Vec::new() }
+  }
+
+  fn cost(&self, v: u32) -> u32 {
+    self.costs[v as usize]
+  }
+
+  fn search(&mut self, out: &Links, from: u32, avoid: u32, max_cost: u32) {
+    for v in self.touched.drain(..) {
+      self.costs[v as usize] = u32::MAX;
+    }
+    self.costs[from as usize] = 0;
+    self.touched.push(from);
+    let mut queue = BinaryHeap::new();
+    queue.push(Reverse((0, from)));
+
+    let mut settled = 0;
+    while let Some(Reverse((cost, v))) = queue.pop() {
+      if cost > self.costs[v as usize] {
+        continue;
+      }
+      settled += 1;
+      if cost > max_cost || settled > WITNESS_SETTLED {
+        break;
+      }
+      for (w, (link_cost, _)) in out[v as usize].iter() {
+        let next = cost + link_cost;
+        if *w != avoid && next < self.costs[*w as usize] {
+          if self.costs[*w as usize] == u32::MAX {
+            self.touched.push(*w);
+          }
+          self.costs[*w as usize] = next;
+          queue.push(Reverse((next, *w)));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{distance, MapPoint, TransportKind};
+  use crate::graph::{GraphBuilder, RoadClass, Access};
+  use crate::osm_map::RestrictionKind;
+  use crate::profile::default_profiles;
+
+  /// Simple lcg, the tests should be deterministic.
+  fn lcg(mut seed: u64) -> impl FnMut(u64) -> u64 {
+    move |n: u64| {
+      seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+      (seed >> 33) % n
+    }
+  }
+
+  fn id(x: u64, y: u64) -> u64 {
+    y * 100 + x + 1
+  }
+
+  /// Random 15x15 grid, some of its crossings have turn restrictions if `restricted` is set,
+  /// there are no footways then.
+  fn grid(restricted: bool) -> RoadGraph {
+    let mut builder = GraphBuilder::new();
+    let mut class = |highway: &str| Some(builder.road_class_id(RoadClass { highway: highway.to_string(), maxspeed: None, tags: Vec::new() }));
+    let classes = [class("residential"), class("primary"), class("footway")];
+    let mut random = lcg(7);
+    let point = |x: u64, y: u64| MapPoint { id: id(x, y), lat: 55.70 + y as f64 * 0.002, lon: 37.60 + x as f64 * 0.003, path_id: None };
+    for y in 0..15 {
+      for x in 0..15 {
+        let p = point(x, y);
+        builder.add_node(p.id, p.lat, p.lon);
+      }
+    }
+    for y in 0..15 {
+      for x in 0..15 {
+        for (nx, ny) in [(x + 1, y), (x, y + 1)].iter().copied().filter(|(nx, ny)| *nx < 15 && *ny < 15) {
+          // roads are not shorter than the straight lines
+          let len = distance(&point(x, y), &point(nx, ny)) + random(10_000) as u32;
+          let class = classes[random(if restricted { 2 } else { 3 }) as usize];
+          let access = Access { foot: true, car: true };
+          match random(4) {
+            0 => builder.connect_one_way(id(x, y), id(nx, ny), len, access, class),
+            1 => continue,
+            _ => builder.connect_two_way(id(x, y), id(nx, ny), len, access, class)
+          }
+        }
+      }
+    }
+    if restricted {
+      for y in 1..14 {
+        for x in 1..14 {
+          let (west, north, east) = (id(x - 1, y), id(x, y + 1), id(x + 1, y));
+          match random(4) {
+            0 => builder.add_turn_restriction(RestrictionKind::No, vec![west, id(x, y), north]),
+            1 => builder.add_turn_restriction(RestrictionKind::Only, vec![west, id(x, y), east]),
+            2 => builder.add_turn_restriction(RestrictionKind::No, vec![west, id(x, y), east, id(x + 1, y + 1)]),
+            _ => {}
+          }
+        }
+      }
+    }
+
+    builder.build(default_profiles())
+  }
+
+  /// Runs random queries of the profiles before and after preparing the hierarchies of them
+  /// and checks that the results are the same.
+  fn check_queries(graph: &mut RoadGraph, profiles: &[ProfileId], min_reachable: usize) {
+    let mut random = lcg(13);
+    let mut ws = graph.workspace();
+    let mut queries = Vec::new();
+    for profile in profiles.iter().copied() {
+      for _ in 0..40 {
+        let point = |r: &mut dyn FnMut(u64) -> u64| (55.70 + r(2800) as f64 * 0.00001, 37.60 + r(4200) as f64 * 0.00001);
+        let ((lat1, lon1), (lat2, lon2)) = (point(&mut random), point(&mut random));
+        ws.clear();
+        let (start, end) = match (graph.snap(&mut ws, lat1, lon1, profile), graph.snap(&mut ws, lat2, lon2, profile)) {
+          (Some(start), Some(end)) => (start, end),
+          _ => continue
+        };
+        let pr = graph.shortest_path(&mut ws, start.node, end.node, 0, profile);
+        queries.push((profile, (lat1, lon1), (lat2, lon2), pr.points.is_empty(), pr.total_time));
+      }
+    }
+    assert!(queries.iter().filter(|q| !q.3).count() >= min_reachable);
+
+    for profile in profiles.iter().copied() {
+      graph.prepare_hierarchy(profile);
+      assert!(graph.hierarchy(profile).is_some());
+    }
+    let mut ws = graph.workspace();
+    for (profile, (lat1, lon1), (lat2, lon2), unreachable, total_time) in queries {
+      ws.clear();
+      let start = graph.snap(&mut ws, lat1, lon1, profile).unwrap();
+      let end = graph.snap(&mut ws, lat2, lon2, profile).unwrap();
+      let pr = graph.shortest_path(&mut ws, start.node, end.node, 0, profile);
+      assert_eq!((pr.points.is_empty(), pr.total_time), (unreachable, total_time));
+      assert!(pr.eta_list.windows(2).all(|w| w[0] <= w[1]));
+    }
+  }
+
+  #[test]
+  fn queries_match_plain_search() {
+    let mut graph = grid(false);
+    let profiles: Vec<ProfileId> = [TransportKind::Car, TransportKind::Foot].iter().map(|k| graph.default_profile(*k).unwrap()).collect();
+    check_queries(&mut graph, &profiles, 30);
+  }
+
+  #[test]
+  fn queries_with_turn_restrictions_match_edge_based_search() {
+    let mut graph = grid(true);
+    assert!(graph.turn_restrictions.len() > 50);
+    let car = graph.default_profile(TransportKind::Car).unwrap();
+    check_queries(&mut graph, &[car], 25);
+  }
+}
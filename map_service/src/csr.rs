@@ -0,0 +1,139 @@
+//! Static road network in compressed sparse row form.
+//!
+//! Nodes are ordered by osm id, their ids, coordinates and link offsets are kept in separate arrays,
+//! links of node `i` are `links[offsets[i]..offsets[i + 1]]`. Arrays are either owned or point
+//! into a memory mapped snapshot, so processes mapping the same snapshot share one copy of the graph.
+use std::ops::Deref;
+use std::sync::Arc;
+use memmap2::Mmap;
+
+/// Types which could be read directly from the mapped little-endian file.
+///
+/// # Safety
+/// Implementors must be `repr(C)` without padding and valid for any bit pattern.
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for f64 {}
+unsafe impl Pod for RawLink {}
+
+/// Array either owned or pointing into mapped file.
+pub enum Slab<T: Pod> {
+  Owned(Vec<T>),
+  Mapped {
+    map: Arc<Mmap>,
+    /// offset of the first element in bytes
+    offset: usize,
+    len: usize
+  }
+}
+
+impl<T: Pod> Slab<T> {
+  /// Returns `None` if the array doesn't fit into the map or is misaligned.
+  pub fn mapped(map: Arc<Mmap>, offset: usize, len: usize) -> Option<Self> {
+    let end = len.checked_mul(std::mem::size_of::<T>())?.checked_add(offset)?;
+    if end > map.len() || !(map.as_ptr() as usize + offset).is_multiple_of(std::mem::align_of::<T>()) {
+      return None;
+    }
+
+    Some(Slab::Mapped { map, offset, len })
+  }
+}
+
+impl<T: Pod> Deref for Slab<T> {
+  type Target = [T];
+
+  fn deref(&self) -> &[T] {
+    match self {
+      Slab::Owned(v) => v,
+      // bounds and alignment are checked by `Slab::mapped`, `T` is `Pod`
+      Slab::Mapped { map, offset, len } => unsafe {
+        std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len)
+      }
+    }
+  }
+}
+
+impl<T: Pod> Default for Slab<T> {
+  fn default() -> Self {
+    Slab::Owned(Vec::new())
+  }
+}
+
+/// Link as it's stored in the graph and snapshot.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RawLink {
+  pub to: u32,
+  /// distance in cm
+  pub len: u32,
+  /// road class index or [`NO_CLASS`]
+  pub class: u32,
+  /// [`ACCESS_FOOT`] and [`ACCESS_CAR`] bits
+  pub access: u32
+}
+
+pub const NO_CLASS: u32 = u32::MAX;
+pub const ACCESS_FOOT: u32 = 1;
+pub const ACCESS_CAR: u32 = 2;
+
+pub struct Csr {
+  pub osm_ids: Slab<u64>,
+  pub lats: Slab<f64>,
+  pub lons: Slab<f64>,
+  /// `len() + 1` offsets into `links`
+  pub offsets: Slab<u32>,
+  pub links: Slab<RawLink>
+}
+
+impl Default for Csr {
+  fn default() -> Self {
+    Self {
+      osm_ids: Slab::default(),
+      lats: Slab::default(),
+      lons: Slab::default(),
+      offsets: Slab::Owned(vec![0]),
+      links: Slab::default()
+    }
+  }
+}
+
+impl Csr {
+  pub fn len(&self) -> usize {
+    self.osm_ids.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.osm_ids.is_empty()
+  }
+
+  pub fn links(&self, node: usize) -> &[RawLink] {
+    &self.links[self.offsets[node] as usize..self.offsets[node + 1] as usize]
+  }
+
+  /// Index of the node with the osm id.
+  pub fn find(&self, osm_id: u64) -> Option<usize> {
+    self.osm_ids.binary_search(&osm_id).ok()
+  }
+
+  /// Checks that offsets and links point inside the arrays, mapped data is not trusted.
+  pub fn validate(&self, classes_num: usize) -> Result<(), String> {
+    let n = self.len();
+    if self.lats.len() != n || self.lons.len() != n || self.offsets.len() != n + 1 {
+      return Err("node arrays have different length".to_string());
+    }
+    if self.offsets[0] != 0 || self.offsets[n] as usize != self.links.len() || self.offsets.windows(2).any(|w| w[0] > w[1]) {
+      return Err("bad link offsets".to_string());
+    }
+    if self.osm_ids.windows(2).any(|w| w[0] >= w[1]) {
+      return Err("nodes are not sorted by osm id".to_string());
+    }
+    let bad_link = |l: &RawLink| l.to as usize >= n || (l.class != NO_CLASS && l.class as usize >= classes_num);
+    if self.links.iter().any(bad_link) {
+      return Err("link points out of the graph".to_string());
+    }
+
+    Ok(())
+  }
+}
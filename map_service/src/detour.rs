@@ -0,0 +1,660 @@
+//! Matching riders with drivers who could leave their car paths to pick them up.
+//!
+//! The driver leaves the path at some point, drives to the pickup and the dropoff and returns
+//! to the path at the same or a later point, or makes such detours to the pickup and to the dropoff
+//! separately carrying the rider along the path between them. Driving times of the detours are taken
+//! at [`CAR_PATH_SPEED`] like the etas of the car path points, roads are chosen by a car profile.
+//! Roads between the path and every stop are found by one search in each direction, roads breaking
+//! turn restrictions are searched again only if the best detour uses them.
+//!
+//! A driver could take several riders of a batch while there are free seats, detours for the next
+//! riders are made from the route with the detours for the riders taken and don't skip their stops.
+use crate::{MapPoint, PathResult, TransportKind};
+use crate::graph::{RoadGraph, Workspace, NodeId, ProfileId, ReversedLinks};
+use crate::car_paths::{CarPoint, CAR_PATH_SPEED};
+
+/// Most nodes passed by a search between the path and the pickup or the dropoff,
+/// path points not reached before it are not used for the detour.
+const MAX_SETTLED: usize = 200_000;
+
+/// Limits of the time a driver could add to the path.
+#[derive(Debug, Copy, Clone)]
+pub struct Detour {
+  /// ms
+  pub max_time: Option<u32>,
+  /// percents of the path time
+  pub max_percent: Option<u32>
+}
+
+impl Default for Detour {
+  fn default() -> Self {
+    Detour {
+      max_time: Some(10 * 60 * 1000),
+      max_percent: None
+    }
+  }
+}
+
+impl Detour {
+  /// Time in ms which could be added to the path taking `path_time` ms.
+  pub fn allowed(&self, path_time: u32) -> u32 {
+    let by_percent = self.max_percent.map(|p| (path_time as u64 * p as u64 / 100) as u32);
+    self.max_time.into_iter().chain(by_percent).min().unwrap_or(u32::MAX)
+  }
+}
+
+#[derive(Debug)]
+pub struct DetourMatch {
+  pub path_id: u64,
+  pub pickup: MapPoint,
+  pub dropoff: MapPoint,
+  /// ms since unix epoch
+  pub pickup_at: i64,
+  /// ms since unix epoch
+  pub dropoff_at: i64,
+  /// ms
+  pub added_time: u32,
+  /// new path of the driver through the pickups and the dropoffs of all riders taken
+  pub path: Vec<MapPoint>
+}
+
+/// Rider to be picked up at `pickup` and dropped off at `dropoff`.
+#[derive(Debug, Copy, Clone)]
+pub struct DetourRequest {
+  pub pickup: NodeId,
+  pub dropoff: NodeId,
+  pub seats: u8
+}
+
+/// Roads of the graph usable with the car profile and the same roads reversed
+/// for the searches to the pickup.
+pub struct Roads<'a> {
+  pub graph: &'a RoadGraph,
+  pub profile: ProfileId,
+  reversed: ReversedLinks
+}
+
+impl<'a> Roads<'a> {
+  pub fn new(graph: &'a RoadGraph, profile: ProfileId) -> Self {
+    Roads { graph, profile, reversed: graph.reversed_links(profile) }
+  }
+}
+
+/// Road node usable with the car profile nearest to the point.
+pub fn meeting_node(graph: &RoadGraph, point: &MapPoint, profile: ProfileId) -> Option<NodeId> {
+  graph.nearest_nodes(point.lat, point.lon, 1, TransportKind::Car, |id| graph.is_accessible(id, profile))
+    .first()
+    .map(|(id, _)| *id)
+}
+
+/// Road node of the driver's route.
+#[derive(Debug)]
+struct RoutePoint {
+  node: NodeId,
+  point: MapPoint,
+  /// ms since unix epoch
+  eta: i64,
+  /// rider of the batch picked up or dropped off at the point, such points can't be skipped
+  rider: Option<usize>
+}
+
+/// Car path of a driver with the detours for the riders taken, the driver could leave it
+/// and return to it at any point.
+#[derive(Debug)]
+pub struct Route {
+  path_id: u64,
+  points: Vec<RoutePoint>,
+  /// free seats from every point to the next one
+  free_seats: Vec<u8>,
+  /// time the detours could still add, ms
+  allowed: u32,
+  /// time added by the detour of every rider taken, ms
+  added: Vec<(usize, u32)>
+}
+
+impl Route {
+  /// Route along the car path with the `points` and `free_seats` left on its segments,
+  /// detours could add time within the `detour` limits.
+  pub fn new(points: &[&CarPoint], free_seats: &[u8], detour: Detour) -> Self {
+    let path_time = match (points.first(), points.last()) {
+      (Some(first), Some(last)) => (last.eta - first.eta) as u32,
+      _ => 0
+    };
+
+    Route {
+      path_id: points.first().map_or(0, |p| p.path_id),
+      points: points.iter().map(|p| RoutePoint {
+        node: p.node,
+        point: MapPoint { path_id: None, ..p.map_point() },
+        eta: p.eta,
+        rider: None
+      }).collect(),
+      free_seats: free_seats.to_vec(),
+      allowed: detour.allowed(path_time),
+      added: Vec::new()
+    }
+  }
+
+  /// Adds the `detour` for the `rider`, points after its parts are passed later by the time they add.
+  pub fn insert(&mut self, graph: &RoadGraph, rider: usize, request: &DetourRequest, detour: Insertion) {
+    for part in detour.parts {
+      self.splice(graph, rider, part);
+    }
+    let stops: Vec<usize> = (0..self.points.len()).filter(|k| self.points[*k].rider == Some(rider)).collect();
+    for free in self.free_seats[stops[0]..stops[1]].iter_mut() {
+      *free = free.saturating_sub(request.seats);
+    }
+    self.allowed -= detour.added_time;
+    self.added.push((rider, detour.added_time));
+  }
+
+  /// Detour of the taken `rider`, the path goes through the pickups and the dropoffs of all riders taken.
+  pub fn detour_match(&self, rider: usize) -> Option<DetourMatch> {
+    let added_time = self.added.iter().find(|(r, _)| *r == rider)?.1;
+    let mut stops = self.points.iter().filter(|p| p.rider == Some(rider));
+    let (pickup, dropoff) = (stops.next()?, stops.next()?);
+    let mut path: Vec<MapPoint> = self.points.iter().map(|p| copy_point(&p.point)).collect();
+    // stops are separate points even if the road node is a point of the route already
+    path.dedup_by(|a, b| a.id == b.id);
+
+    Some(DetourMatch {
+      path_id: self.path_id,
+      pickup: copy_point(&pickup.point),
+      dropoff: copy_point(&dropoff.point),
+      pickup_at: pickup.eta,
+      dropoff_at: dropoff.eta,
+      added_time,
+      path
+    })
+  }
+
+  /// Replaces the points between `i` and `j` by the nodes of the roads of the `part`, the car carries
+  /// the passengers of the skipped segments there.
+  fn splice(&mut self, graph: &RoadGraph, rider: usize, part: Part) {
+    let Part { i, j, added_time, roads } = part;
+    let carried = self.carried_seats(i, j);
+
+    let mut points = Vec::new();
+    let mut eta = self.points[i].eta;
+    for (pr, stop) in roads.iter() {
+      for (p, meters) in pr.points.iter().zip(pr.distance_list.iter()).skip(1).take(pr.points.len().saturating_sub(2)) {
+        let node = graph.node_id_by_osm_id(p.id).unwrap();
+        points.push(RoutePoint { node, point: copy_point(p), eta: eta + meters_time(*meters) as i64, rider: None });
+      }
+      eta += drive_time(pr) as i64;
+      if let Some(node) = stop {
+        points.push(RoutePoint { node: *node, point: copy_point(pr.points.last().unwrap()), eta, rider: Some(rider) });
+      }
+    }
+    // the driver passes point `j` again if it returns to the point it left the route at
+    let back = &self.points[j];
+    let back = RoutePoint {
+      node: back.node,
+      point: copy_point(&back.point),
+      eta: back.eta + added_time as i64,
+      rider: if i == j { None } else { back.rider }
+    };
+    points.push(back);
+
+    for p in self.points[j + 1..].iter_mut() {
+      p.eta += added_time as i64;
+    }
+    self.free_seats.splice(i..j, vec![carried; points.len()]);
+    self.points.splice(i + 1..=j, points);
+  }
+
+  /// Seats free for the car leaving the route at point `i` and returning at point `j`: the least ones
+  /// of the skipped segments or of both segments around the point if it returns to the same one.
+  fn carried_seats(&self, i: usize, j: usize) -> u8 {
+    let segments = if i == j { i.saturating_sub(1)..(i + 1).min(self.free_seats.len()) } else { i..j };
+    self.free_seats[segments].iter().copied().min().unwrap_or(0)
+  }
+
+  /// Detours within the limits as `(added time, i, j)` leaving the route at point `i` by the road `to`
+  /// and returning at point `j` by the road `from` after `extra` ms, skipping no riders and carrying
+  /// `seats` more passengers.
+  fn detours(
+    &self,
+    to: &[Option<(PathResult, bool)>],
+    from: &[Option<(PathResult, bool)>],
+    extra: u32,
+    seats: u8
+  ) -> Vec<(u32, usize, usize)> {
+    let mut found = Vec::new();
+    for (i, to) in to.iter().enumerate() {
+      let to = match to {
+        Some((to, _)) => drive_time(to) + extra,
+        None => continue
+      };
+      for (j, from) in from.iter().enumerate().skip(i) {
+        if j > i + 1 && self.points[j - 1].rider.is_some() {
+          break;
+        }
+        // free seats of the skipped segments only decrease with `j`
+        if self.carried_seats(i, j) < seats {
+          if j > i {
+            break;
+          }
+          continue;
+        }
+        let from = match from {
+          Some((from, _)) => drive_time(from),
+          None => continue
+        };
+        let skipped = self.points[j].eta - self.points[i].eta;
+        let added = (to as i64 + from as i64 - skipped).max(0) as u32;
+        if added <= self.allowed {
+          found.push((added, i, j));
+        }
+      }
+    }
+
+    found
+  }
+
+  /// Plan for the rider adding the least time within the limits.
+  fn best_plan(&self, request: &DetourRequest, roads: &RiderRoads) -> Option<(u32, Plan)> {
+    let seats = request.seats;
+    let paths = |road: Road| &roads.paths[road as usize];
+    let mut best = self.detours(paths(Road::ToPickup), paths(Road::FromDropoff), drive_time(&roads.ride), seats)
+      .into_iter()
+      .min_by_key(|(added, _, _)| *added)
+      .map(|(added, i, j)| (added, Plan::Ride(i, j)));
+
+    // the least detours to the pickup by the point they return at and to the dropoff by the point they leave at
+    let mut pickups: Vec<Option<(u32, usize)>> = vec![None; self.points.len()];
+    for (added, i, j) in self.detours(paths(Road::ToPickup), paths(Road::FromPickup), 0, seats) {
+      if pickups[j].is_none_or(|(a, _)| added < a) {
+        pickups[j] = Some((added, i));
+      }
+    }
+    let mut dropoffs: Vec<Option<(u32, usize)>> = vec![None; self.points.len()];
+    for (added, i, j) in self.detours(paths(Road::ToDropoff), paths(Road::FromDropoff), 0, seats) {
+      if dropoffs[i].is_none_or(|(a, _)| added < a) {
+        dropoffs[i] = Some((added, j));
+      }
+    }
+
+    for (j1, pickup) in pickups.iter().enumerate() {
+      let (to_pickup, i1) = match pickup {
+        Some(pickup) => *pickup,
+        None => continue
+      };
+      for (i2, dropoff) in dropoffs.iter().enumerate().skip(j1) {
+        if i2 > j1 && self.free_seats[i2 - 1] < seats {
+          break;
+        }
+        if let Some((to_dropoff, j2)) = dropoff {
+          let added = to_pickup + to_dropoff;
+          if added <= self.allowed && best.is_none_or(|(b, _)| added < b) {
+            best = Some((added, Plan::Stops((i1, j1), (i2, *j2))));
+          }
+        }
+      }
+    }
+
+    best
+  }
+}
+
+/// Ways to take a rider, the driver leaves the route at point `i` and returns at point `j` of every `(i, j)`.
+#[derive(Debug, Copy, Clone)]
+enum Plan {
+  /// to the pickup, the dropoff and back
+  Ride(usize, usize),
+  /// to the pickup and back, along the route, to the dropoff and back
+  Stops((usize, usize), (usize, usize))
+}
+
+impl Plan {
+  /// Roads between the route and the stops taken with the route points they start or end at.
+  fn roads(self) -> Vec<(Road, usize)> {
+    match self {
+      Plan::Ride(i, j) => vec![(Road::ToPickup, i), (Road::FromDropoff, j)],
+      Plan::Stops((i1, j1), (i2, j2)) =>
+        vec![(Road::ToPickup, i1), (Road::FromPickup, j1), (Road::ToDropoff, i2), (Road::FromDropoff, j2)]
+    }
+  }
+}
+
+/// Roads between the stops of the rider and the points of the route.
+#[derive(Debug, Copy, Clone)]
+enum Road {
+  ToPickup,
+  FromPickup,
+  ToDropoff,
+  FromDropoff
+}
+
+/// Paths found for a rider.
+struct RiderRoads {
+  ride: PathResult,
+  /// paths of every [`Road`] by the route points, the flag is false for the paths breaking turn restrictions
+  paths: [Vec<Option<(PathResult, bool)>>; 4]
+}
+
+/// Part of the route from point `i` to point `j` replaced by the `roads` ending at the stops
+/// or at point `j` for the last one.
+#[derive(Debug)]
+struct Part {
+  i: usize,
+  j: usize,
+  /// ms
+  added_time: u32,
+  roads: Vec<(PathResult, Option<NodeId>)>
+}
+
+/// Detour for a rider to be added to a route.
+#[derive(Debug)]
+pub struct Insertion {
+  /// ms
+  pub added_time: u32,
+  /// the later parts of the route go first
+  parts: Vec<Part>
+}
+
+/// Finds the detour for the rider adding the least time to the route, `None` if every detour exceeds
+/// the limits. The driver either makes one detour to the pickup and the dropoff or two ones to each
+/// of them taking the rider along the route between, the rider is in the car with the passengers
+/// of the skipped parts of the route.
+pub fn find_detour(roads: &Roads, ws: &mut Workspace, route: &Route, request: &DetourRequest) -> Option<Insertion> {
+  let (graph, profile) = (roads.graph, roads.profile);
+  let (pickup, dropoff) = (request.pickup, request.dropoff);
+
+  let ride = drive(graph, ws, pickup, dropoff, profile)?;
+  let nodes: Vec<NodeId> = route.points.iter().map(|p| p.node).collect();
+  let mut found = RiderRoads {
+    ride,
+    paths: [
+      graph.paths_to_many(ws, pickup, &nodes, Some(&roads.reversed), MAX_SETTLED, profile),
+      graph.paths_to_many(ws, pickup, &nodes, None, MAX_SETTLED, profile),
+      graph.paths_to_many(ws, dropoff, &nodes, Some(&roads.reversed), MAX_SETTLED, profile),
+      graph.paths_to_many(ws, dropoff, &nodes, None, MAX_SETTLED, profile)
+    ]
+  };
+
+  // roads breaking turn restrictions are not longer than the allowed ones, so the best plan
+  // is the best one when its roads are allowed
+  let (added_time, plan) = loop {
+    let (added, plan) = route.best_plan(request, &found)?;
+    let mut allowed = true;
+    for (road, k) in plan.roads() {
+      let path = &mut found.paths[road as usize][k];
+      if path.as_ref().is_some_and(|(_, ok)| *ok) {
+        continue;
+      }
+      allowed = false;
+      *path = match road {
+        Road::ToPickup => drive(graph, ws, nodes[k], pickup, profile),
+        Road::FromPickup => drive(graph, ws, pickup, nodes[k], profile),
+        Road::ToDropoff => drive(graph, ws, nodes[k], dropoff, profile),
+        Road::FromDropoff => drive(graph, ws, dropoff, nodes[k], profile)
+      }.map(|pr| (pr, true));
+    }
+    if allowed {
+      break (added, plan);
+    }
+  };
+
+  let mut take = |road: Road, k: usize| found.paths[road as usize][k].take().unwrap().0;
+  let added = |to: &PathResult, from: &PathResult, extra: u32, i: usize, j: usize| {
+    (drive_time(to) as i64 + extra as i64 + drive_time(from) as i64 - (route.points[j].eta - route.points[i].eta)).max(0) as u32
+  };
+  let parts = match plan {
+    Plan::Ride(i, j) => {
+      let (to, from) = (take(Road::ToPickup, i), take(Road::FromDropoff, j));
+      let ride = std::mem::take(&mut found.ride);
+      vec![Part { i, j, added_time, roads: vec![(to, Some(pickup)), (ride, Some(dropoff)), (from, None)] }]
+    },
+    Plan::Stops((i1, j1), (i2, j2)) => {
+      let (to_pickup, from_pickup) = (take(Road::ToPickup, i1), take(Road::FromPickup, j1));
+      let (to_dropoff, from_dropoff) = (take(Road::ToDropoff, i2), take(Road::FromDropoff, j2));
+      vec![
+        Part { i: i2, j: j2, added_time: added(&to_dropoff, &from_dropoff, 0, i2, j2), roads: vec![(to_dropoff, Some(dropoff)), (from_dropoff, None)] },
+        Part { i: i1, j: j1, added_time: added(&to_pickup, &from_pickup, 0, i1, j1), roads: vec![(to_pickup, Some(pickup)), (from_pickup, None)] }
+      ]
+    }
+  };
+
+  Some(Insertion { added_time, parts })
+}
+
+/// Finds the detour for the rider alone on the route, see [`find_detour`].
+pub fn match_detour(roads: &Roads, ws: &mut Workspace, mut route: Route, request: &DetourRequest) -> Option<DetourMatch> {
+  let detour = find_detour(roads, ws, &route, request)?;
+  route.insert(roads.graph, 0, request, detour);
+  route.detour_match(0)
+}
+
+/// Assigns the riders to the drivers of the `routes` and returns the detour of every rider.
+///
+/// Riders are taken in rounds: the riders left are assigned by [`assign`] to the routes with the detours
+/// of the riders taken before, one rider per route, until no more riders could be taken.
+pub fn match_batch(roads: &Roads, ws: &mut Workspace, mut routes: Vec<Route>, requests: &[Option<DetourRequest>]) -> Vec<Option<DetourMatch>> {
+  let mut found: Vec<Vec<Option<Insertion>>> = requests.iter().map(|r| routes.iter().map(|route| {
+    r.as_ref().and_then(|r| find_detour(roads, ws, route, r))
+  }).collect()).collect();
+  let mut driver_of: Vec<Option<usize>> = vec![None; requests.len()];
+
+  loop {
+    let costs: Vec<Vec<Option<u32>>> = found.iter().map(|f| f.iter().map(|d| d.as_ref().map(|d| d.added_time)).collect()).collect();
+    let mut changed = vec![false; routes.len()];
+    for (r, d) in assign(&costs, routes.len()).into_iter().enumerate() {
+      if let (Some(d), Some(request)) = (d, requests[r].as_ref()) {
+        routes[d].insert(roads.graph, r, request, found[r][d].take().unwrap());
+        found[r].iter_mut().for_each(|f| *f = None);
+        driver_of[r] = Some(d);
+        changed[d] = true;
+      }
+    }
+    if !changed.contains(&true) {
+      break;
+    }
+
+    // detours to the changed routes are found again for the riders left
+    for (r, request) in requests.iter().enumerate().filter(|(r, _)| driver_of[*r].is_none()) {
+      for d in (0..routes.len()).filter(|d| changed[*d]) {
+        found[r][d] = request.as_ref().and_then(|request| find_detour(roads, ws, &routes[d], request));
+      }
+    }
+  }
+
+  driver_of.iter().enumerate().map(|(r, d)| d.and_then(|d| routes[d].detour_match(r))).collect()
+}
+
+fn drive(graph: &RoadGraph, ws: &mut Workspace, from: NodeId, to: NodeId, profile: ProfileId) -> Option<PathResult> {
+  Some(graph.shortest_path(ws, from, to, 0, profile)).filter(|pr| !pr.points.is_empty())
+}
+
+/// Assigns riders to drivers serving as many riders as possible with the least total cost of such
+/// assignments, `costs[r][d]` is `None` if driver `d` can't take rider `r`. Returns the driver of every rider.
+///
+/// Riders are added by the cheapest augmenting paths, each of them keeps the assignment the cheapest
+/// for its number of riders.
+pub fn assign(costs: &[Vec<Option<u32>>], drivers: usize) -> Vec<Option<usize>> {
+  let mut driver_of: Vec<Option<usize>> = vec![None; costs.len()];
+  let mut rider_of: Vec<Option<usize>> = vec![None; drivers];
+  let cost = |r: usize, d: usize| costs[r][d].map(|c| c as i64);
+
+  loop {
+    // distances to riders and drivers from the unassigned riders by the residual links:
+    // rider to an other driver and driver back to its rider
+    let mut to_rider: Vec<i64> = driver_of.iter().map(|d| if d.is_none() { 0 } else { i64::MAX }).collect();
+    let mut to_driver = vec![i64::MAX; drivers];
+    let mut prev_rider = vec![0; drivers];
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for r in 0..costs.len() {
+        if to_rider[r] == i64::MAX {
+          continue;
+        }
+        for d in (0..drivers).filter(|d| driver_of[r] != Some(*d)) {
+          if let Some(c) = cost(r, d) {
+            if to_rider[r] + c < to_driver[d] {
+              to_driver[d] = to_rider[r] + c;
+              prev_rider[d] = r;
+              changed = true;
+            }
+          }
+        }
+      }
+      for d in 0..drivers {
+        if let (Some(r), true) = (rider_of[d], to_driver[d] != i64::MAX) {
+          let back = to_driver[d] - cost(r, d).unwrap();
+          if back < to_rider[r] {
+            to_rider[r] = back;
+            changed = true;
+          }
+        }
+      }
+    }
+
+    let free = (0..drivers)
+      .filter(|d| rider_of[*d].is_none() && to_driver[*d] != i64::MAX)
+      .min_by_key(|d| to_driver[*d]);
+    let mut d = match free {
+      Some(d) => d,
+      None => break
+    };
+    loop {
+      let r = prev_rider[d];
+      let prev_driver = driver_of[r];
+      driver_of[r] = Some(d);
+      rider_of[d] = Some(r);
+      d = match prev_driver {
+        Some(prev) => prev,
+        None => break
+      };
+    }
+  }
+
+  driver_of
+}
+
+/// Time to drive the path at the speed of car paths.
+fn drive_time(pr: &PathResult) -> u32 {
+  meters_time(pr.total_distance)
+}
+
+/// Time to drive `meters` at the speed of car paths.
+fn meters_time(meters: u32) -> u32 {
+  (meters as f64 * 100.0 / CAR_PATH_SPEED.as_cm_per_millisecond()).round() as u32
+}
+
+fn copy_point(p: &MapPoint) -> MapPoint {
+  MapPoint { id: p.id, lat: p.lat, lon: p.lon, path_id: None }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::PlainMapCarPath;
+  use crate::car_paths::CarPaths;
+  use crate::graph::{GraphBuilder, RoadClass, Access};
+  use crate::osm_map::RestrictionKind;
+  use crate::profile::default_profiles;
+
+  fn point(id: u64) -> MapPoint {
+    match id {
+      6 => MapPoint { id, lat: 55.759, lon: 37.632, path_id: None },
+      7 => MapPoint { id, lat: 55.759, lon: 37.664, path_id: None },
+      _ => MapPoint { id, lat: 55.75, lon: 37.6 + (id - 1) as f64 * 0.016, path_id: None }
+    }
+  }
+
+  /// Street 1-5 with side streets from 3 to 6 and from 5 to 7, every link is about 1 km.
+  fn side_streets() -> GraphBuilder {
+    let mut builder = GraphBuilder::new();
+    let class = Some(builder.road_class_id(RoadClass { highway: "residential".to_string(), maxspeed: None, tags: Vec::new() }));
+    for id in 1..=7 {
+      let p = point(id);
+      builder.add_node(id, p.lat, p.lon);
+    }
+    for (a, b) in [(1, 2), (2, 3), (3, 4), (4, 5), (3, 6), (5, 7)].iter().copied() {
+      builder.connect_two_way(a, b, 100_000, Access { foot: true, car: true }, class);
+    }
+    builder
+  }
+
+  /// Car path along the street.
+  fn car_path(graph: &RoadGraph) -> CarPaths {
+    let street: Vec<MapPoint> = (1..=5).map(point).collect();
+    let mut paths = CarPaths::default();
+    paths.add(graph, &PlainMapCarPath { id: 7, start_at: 0, path: street.iter().collect(), seats: &[1, 1, 1, 1] }).unwrap();
+    paths
+  }
+
+  #[test]
+  fn detour_through_a_side_street() {
+    let graph = side_streets().build(default_profiles());
+    let car = graph.default_profile(TransportKind::Car).unwrap();
+    let paths = car_path(&graph);
+    let points = paths.path(7).unwrap();
+    let request = DetourRequest { pickup: meeting_node(&graph, &point(6), car).unwrap(), dropoff: meeting_node(&graph, &point(5), car).unwrap(), seats: 1 };
+    let mut ws = graph.workspace();
+    let roads = Roads::new(&graph, car);
+    let mut detour = |detour, free_seats: &[u8]| match_detour(&roads, &mut ws, Route::new(&points, free_seats, detour), &request);
+    let free = [1, 1, 1, 1];
+
+    // the driver goes to the side street and back, 2 km more
+    let found = detour(Detour { max_time: Some(3 * 60 * 1000), max_percent: None }, &free).unwrap();
+    assert_eq!(found.path.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2, 3, 6, 3, 4, 5]);
+    assert!((found.added_time as i64 - 144_000).abs() < 500);
+    assert!((found.pickup_at - (points[2].eta + 72_000)).abs() < 500);
+    assert_eq!(found.dropoff.id, 5);
+    assert!(detour(Detour { max_time: Some(2 * 60 * 1000), max_percent: None }, &free).is_none());
+    assert!(detour(Detour { max_time: None, max_percent: Some(60) }, &free).is_some());
+    assert!(detour(Detour { max_time: Some(3 * 60 * 1000), max_percent: Some(40) }, &free).is_none());
+    // the car is full after the side street, it could turn back to it from the fourth point
+    let five_minutes = Detour { max_time: Some(5 * 60 * 1000), max_percent: None };
+    assert!(detour(five_minutes, &[0, 0, 1, 1]).is_some());
+    assert_eq!(detour(five_minutes, &[1, 1, 0, 1]).unwrap().path.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2, 3, 4, 3, 6, 3, 4, 5]);
+    assert!(detour(five_minutes, &[1, 1, 0, 0]).is_none());
+
+    // the driver goes to the side street at the end of the path, the last segment should have a free seat
+    let request = DetourRequest { pickup: meeting_node(&graph, &point(7), car).unwrap(), ..request };
+    let three_minutes = Detour { max_time: Some(3 * 60 * 1000), max_percent: None };
+    let mut detour = |free_seats: &[u8]| match_detour(&roads, &mut ws, Route::new(&points, free_seats, three_minutes), &request);
+    assert_eq!(detour(&[1, 1, 1, 1]).unwrap().path.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 7, 5]);
+    assert!(detour(&[1, 1, 1, 0]).is_none());
+  }
+
+  #[test]
+  fn detour_follows_turn_restrictions() {
+    // the side street 3-6 can't be entered coming from 4
+    let mut builder = side_streets();
+    builder.add_turn_restriction(RestrictionKind::No, vec![4, 3, 6]);
+    let graph = builder.build(default_profiles());
+    let car = graph.default_profile(TransportKind::Car).unwrap();
+    let paths = car_path(&graph);
+    let points = paths.path(7).unwrap();
+    let request = DetourRequest { pickup: meeting_node(&graph, &point(6), car).unwrap(), dropoff: meeting_node(&graph, &point(5), car).unwrap(), seats: 1 };
+    let roads = Roads::new(&graph, car);
+    let mut ws = graph.workspace();
+    let mut detour = |minutes: u32| {
+      let detour = Detour { max_time: Some(minutes * 60 * 1000), max_percent: None };
+      match_detour(&roads, &mut ws, Route::new(&points, &[1, 1, 0, 1], detour), &request)
+    };
+
+    // the car is full after the side street and can't turn back into it from 4 as without the restriction,
+    // so the driver enters it from 2 and returns to 3 after the dropoff
+    assert!(detour(5).is_none());
+    let found = detour(10).unwrap();
+    assert_eq!(found.path.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2, 3, 6, 3, 4, 5, 4, 3, 4, 5]);
+    assert!((found.added_time as i64 - 432_000).abs() < 1000);
+  }
+
+  #[test]
+  fn assignment_serves_most_riders_cheapest() {
+    // the first rider is cheaper for both drivers, but only the first driver could take the second one
+    let costs = vec![vec![Some(1), Some(2)], vec![Some(5), None], vec![None, None]];
+    assert_eq!(assign(&costs, 2), vec![Some(1), Some(0), None]);
+
+    let costs = vec![vec![Some(3), Some(1)], vec![Some(1), Some(3)]];
+    assert_eq!(assign(&costs, 2), vec![Some(1), Some(0)]);
+    let costs = vec![vec![Some(4)], vec![Some(2)], vec![Some(3)]];
+    assert_eq!(assign(&costs, 1), vec![None, Some(0), None]);
+    assert!(assign(&[], 3).is_empty());
+  }
+}
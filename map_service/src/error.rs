@@ -0,0 +1,345 @@
+use std::fmt;
+use std::io;
+use pyo3::{PyErr, Python};
+use pyo3::create_exception;
+
+create_exception!(map_service, MapLoadException, pyo3::exceptions::Exception);
+create_exception!(map_service, ProfileLoadException, pyo3::exceptions::Exception);
+create_exception!(map_service, SnapshotException, pyo3::exceptions::Exception);
+create_exception!(map_service, ReservationException, pyo3::exceptions::Exception);
+create_exception!(map_service, CarPathException, pyo3::exceptions::Exception);
+
+/// Error happened during map file loading.
+/// Offsets are in bytes, for xml they are counted in decompressed stream.
+#[derive(Debug)]
+pub enum MapLoadError {
+  Io(io::Error),
+  Decompression {
+    offset: u64,
+    source: io::Error
+  },
+  Xml {
+    offset: usize,
+    source: quick_xml::Error
+  },
+  /// Malformed pbf structure
+  Pbf {
+    offset: u64,
+    reason: String
+  },
+  /// Attribute or field which value couldn't be parsed
+  BadAttribute {
+    offset: u64,
+    element_id: Option<u64>,
+    name: String,
+    value: String
+  },
+  /// Way references node which is not present in the file
+  MissingNode {
+    way_id: u64,
+    node_id: u64
+  },
+  /// Clipping area is malformed
+  Area(String)
+}
+
+impl MapLoadError {
+  pub fn bad_attribute(offset: u64, element_id: Option<u64>, name: &[u8], value: &[u8]) -> Self {
+    MapLoadError::BadAttribute {
+      offset,
+      element_id,
+      name: String::from_utf8_lossy(name).into_owned(),
+      value: String::from_utf8_lossy(value).into_owned()
+    }
+  }
+
+  /// Byte offset in the file where the error happened.
+  pub fn offset(&self) -> Option<u64> {
+    match self {
+      MapLoadError::Decompression { offset, .. } | MapLoadError::Pbf { offset, .. } |
+      MapLoadError::BadAttribute { offset, .. } => Some(*offset),
+      MapLoadError::Xml { offset, .. } => Some(*offset as u64),
+      _ => None
+    }
+  }
+
+  /// Id of the element which caused the error.
+  pub fn element_id(&self) -> Option<u64> {
+    match self {
+      MapLoadError::BadAttribute { element_id, .. } => *element_id,
+      MapLoadError::MissingNode { way_id, .. } => Some(*way_id),
+      _ => None
+    }
+  }
+
+  /// Id of the way which references a missing node.
+  pub fn way_id(&self) -> Option<u64> {
+    match self {
+      MapLoadError::MissingNode { way_id, .. } => Some(*way_id),
+      _ => None
+    }
+  }
+
+  /// Id of the node which is referenced but missing.
+  pub fn node_id(&self) -> Option<u64> {
+    match self {
+      MapLoadError::MissingNode { node_id, .. } => Some(*node_id),
+      _ => None
+    }
+  }
+}
+
+impl fmt::Display for MapLoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MapLoadError::Io(e) => write!(f, "I/O error: {}", e),
+      MapLoadError::Decompression { offset, source } =>
+        write!(f, "decompression error at offset {}: {}", offset, source),
+      MapLoadError::Xml { offset, source } =>
+        write!(f, "xml error at offset {}: {}", offset, source),
+      MapLoadError::Pbf { offset, reason } =>
+        write!(f, "malformed pbf at offset {}: {}", offset, reason),
+      MapLoadError::BadAttribute { offset, element_id: Some(id), name, value } =>
+        write!(f, "bad attribute {}=\"{}\" of element {} at offset {}", name, value, id, offset),
+      MapLoadError::BadAttribute { offset, element_id: None, name, value } =>
+        write!(f, "bad attribute {}=\"{}\" at offset {}", name, value, offset),
+      MapLoadError::MissingNode { way_id, node_id } =>
+        write!(f, "way {} references missing node {}", way_id, node_id),
+      MapLoadError::Area(reason) => write!(f, "bad clipping area: {}", reason)
+    }
+  }
+}
+
+impl std::error::Error for MapLoadError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      MapLoadError::Io(e) => Some(e),
+      MapLoadError::Decompression { source, .. } => Some(source),
+      MapLoadError::Xml { source, .. } => Some(source),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for MapLoadError {
+  fn from(e: io::Error) -> Self {
+    MapLoadError::Io(e)
+  }
+}
+
+/// Exception gets `offset`, `element_id`, `way_id` and `node_id` attributes, `None` when unknown.
+impl From<MapLoadError> for PyErr {
+  fn from(e: MapLoadError) -> Self {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let exc = match py.get_type::<MapLoadException>().call1((e.to_string(),)) {
+      Ok(exc) => exc,
+      Err(err) => return err
+    };
+    let attrs = [("offset", e.offset()), ("element_id", e.element_id()), ("way_id", e.way_id()),
+      ("node_id", e.node_id())];
+    for (name, value) in attrs.iter() {
+      if let Err(err) = exc.setattr(*name, *value) {
+        return err;
+      }
+    }
+    PyErr::from_instance(exc)
+  }
+}
+
+/// Error happened during routing profiles loading.
+#[derive(Debug)]
+pub enum ProfileLoadError {
+  Io(io::Error),
+  Json(serde_json::Error),
+  /// Profile is parsed but makes no sense
+  Invalid(String)
+}
+
+impl fmt::Display for ProfileLoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProfileLoadError::Io(e) => write!(f, "I/O error: {}", e),
+      ProfileLoadError::Json(e) => write!(f, "bad profiles json: {}", e),
+      ProfileLoadError::Invalid(reason) => write!(f, "invalid profile: {}", reason)
+    }
+  }
+}
+
+impl std::error::Error for ProfileLoadError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ProfileLoadError::Io(e) => Some(e),
+      ProfileLoadError::Json(e) => Some(e),
+      ProfileLoadError::Invalid(_) => None
+    }
+  }
+}
+
+impl From<io::Error> for ProfileLoadError {
+  fn from(e: io::Error) -> Self {
+    ProfileLoadError::Io(e)
+  }
+}
+
+impl From<serde_json::Error> for ProfileLoadError {
+  fn from(e: serde_json::Error) -> Self {
+    ProfileLoadError::Json(e)
+  }
+}
+
+impl From<ProfileLoadError> for PyErr {
+  fn from(e: ProfileLoadError) -> Self {
+    ProfileLoadException::py_err(e.to_string())
+  }
+}
+
+/// Error happened during saving or loading map snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+  Io(io::Error),
+  /// File is not a snapshot
+  BadMagic,
+  /// Snapshot was written by another version of the service
+  UnsupportedVersion(u32),
+  ChecksumMismatch,
+  /// Checksum is correct, but the content doesn't make sense
+  Malformed(String)
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SnapshotError::Io(e) => write!(f, "I/O error: {}", e),
+      SnapshotError::BadMagic => write!(f, "not a map snapshot"),
+      SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+      SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+      SnapshotError::Malformed(reason) => write!(f, "malformed snapshot: {}", reason)
+    }
+  }
+}
+
+impl std::error::Error for SnapshotError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      SnapshotError::Io(e) => Some(e),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for SnapshotError {
+  fn from(e: io::Error) -> Self {
+    SnapshotError::Io(e)
+  }
+}
+
+impl From<SnapshotError> for PyErr {
+  fn from(e: SnapshotError) -> Self {
+    SnapshotException::py_err(e.to_string())
+  }
+}
+
+/// Seats of a car path couldn't be reserved or released.
+#[derive(Debug)]
+pub enum ReservationError {
+  /// Not enough free seats on the segment
+  Conflict {
+    path_id: u64,
+    segment: usize,
+    free: u8,
+    requested: u8
+  },
+  /// Range of points is empty or outside of the path, or no seats are requested
+  BadRange {
+    path_id: u64,
+    from: usize,
+    to: usize,
+    segments: usize
+  },
+  UnknownReservation(u64)
+}
+
+impl fmt::Display for ReservationError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ReservationError::Conflict { path_id, segment, free, requested } =>
+        write!(f, "{} seats requested on segment {} of path {}, {} are free", requested, segment, path_id, free),
+      ReservationError::BadRange { path_id, from, to, segments } =>
+        write!(f, "bad range {}..{} of path {} with {} segments", from, to, path_id, segments),
+      ReservationError::UnknownReservation(id) => write!(f, "unknown reservation {}", id)
+    }
+  }
+}
+
+impl std::error::Error for ReservationError {}
+
+impl From<ReservationError> for PyErr {
+  fn from(e: ReservationError) -> Self {
+    ReservationException::py_err(e.to_string())
+  }
+}
+
+/// Car path couldn't be added to the registry or changed.
+#[derive(Debug)]
+pub enum CarPathError {
+  /// Path with the id is already added
+  Duplicate(u64),
+  Unknown(u64),
+  /// Path has no points
+  Empty(u64),
+  /// Point of the path is not a road node of the map
+  UnknownNode {
+    path_id: u64,
+    osm_id: u64
+  }
+}
+
+impl fmt::Display for CarPathError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CarPathError::Duplicate(id) => write!(f, "car path {} is already added", id),
+      CarPathError::Unknown(id) => write!(f, "unknown car path {}", id),
+      CarPathError::Empty(id) => write!(f, "car path {} has no points", id),
+      CarPathError::UnknownNode { path_id, osm_id } =>
+        write!(f, "point {} of car path {} is not a road node", osm_id, path_id)
+    }
+  }
+}
+
+impl std::error::Error for CarPathError {}
+
+impl From<CarPathError> for PyErr {
+  fn from(e: CarPathError) -> Self {
+    CarPathException::py_err(e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pyo3::ToPyObject;
+
+  #[test]
+  fn map_load_exception_has_context_attributes() {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let attr = |e: MapLoadError, name: &str| -> Option<u64> {
+      let err = PyErr::from(e);
+      assert!(err.is_instance::<MapLoadException>(py));
+      err.to_object(py).getattr(py, name).unwrap().extract(py).unwrap()
+    };
+
+    let bad = || MapLoadError::bad_attribute(120, Some(7), b"lat", b"north");
+    assert_eq!(attr(bad(), "offset"), Some(120));
+    assert_eq!(attr(bad(), "element_id"), Some(7));
+    assert_eq!(attr(bad(), "way_id"), None);
+    assert_eq!(attr(bad(), "node_id"), None);
+
+    let missing = || MapLoadError::MissingNode { way_id: 10, node_id: 3 };
+    assert_eq!(attr(missing(), "offset"), None);
+    assert_eq!(attr(missing(), "element_id"), Some(10));
+    assert_eq!(attr(missing(), "way_id"), Some(10));
+    assert_eq!(attr(missing(), "node_id"), Some(3));
+  }
+}
@@ -1,282 +1,1336 @@
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use serde::Serialize;
 use std::cmp::Ordering;
-use crate::{MapPoint, distance_t, Kmh, PathResult, EarthPoint, distance, TransportKind};
+use crate::{MapPoint, Kmh, PathResult, PathLeg, LegMode, distance, TransportKind};
+use crate::profile::{RoutingProfile, default_profiles};
+use crate::osm_map::RestrictionKind;
+use crate::csr::{Csr, RawLink, Slab, NO_CLASS, ACCESS_FOOT, ACCESS_CAR};
+use crate::spatial::SpatialIndex;
+use crate::car_paths::{Cars, CarPoint, CAR_PATH_SPEED};
+use crate::ch::Hierarchy;
+use crate::landmarks::{Landmarks, TargetBounds};
 
+/// Length of links between road nodes and car path points in cm
 pub const ROAD_TO_CAR: u32 = 1000;
 
-#[derive(Serialize, Default)]
+/// Links of the road nodes usable with a profile as the lists of their sources and costs,
+/// see [`RoadGraph::reversed_links`].
+pub type ReversedLinks = Vec<Vec<(NodeId, u32)>>;
+
+/// Road network, read-only after it's built.
+///
+/// Road nodes and links are [`Csr`] arrays, road node ids are their indexes in osm id order.
+/// Everything a query adds to the graph or writes while searching lives in its [`Workspace`],
+/// so any number of queries could run on the shared graph at once.
 pub struct RoadGraph {
-  pub node_map: HashMap<u64, NodeId>,
-  pub nodes: Vec<Node>,
-  pub additional_nodes_num: u32,
+  base: Csr,
+  pub road_classes: Vec<RoadClass>,
+  profiles: Vec<RoutingProfile>,
+  /// search cost of every road class in ms per cm for every profile, `None` if class is forbidden
+  class_costs: Vec<Vec<Option<f64>>>,
+  pub turn_restrictions: Vec<TurnRestriction>,
+  /// indexes of turn restrictions by the link leading to their last via node
+  restrictions_by_link: HashMap<(NodeId, NodeId), Vec<usize>>,
+  /// indexes of turn restrictions with via ways by their first link
+  restrictions_by_first_link: HashMap<(NodeId, NodeId), Vec<usize>>,
+  /// road nodes usable by transport kinds, depends on profiles
+  spatial: SpatialIndex,
+  /// contraction hierarchies of the profiles, ones built with other profile costs are not used
+  hierarchies: Vec<Hierarchy>,
+  /// landmarks of the profiles, ones built with other profile costs are not used
+  landmarks: Vec<Landmarks>
+}
+
+impl Default for RoadGraph {
+  fn default() -> Self {
+    Self::from_parts(Csr::default(), Vec::new(), Vec::new(), default_profiles())
+  }
 }
 
 impl RoadGraph {
   pub fn new() -> Self {
     Self::default()
   }
-  /// uber unsafe :(
-  pub fn node<'a, 'b>(&'a self, id: NodeId) -> &'b Node {
-    unsafe { std::mem::transmute::<_, &'b Node>(self.nodes.get(id.0).unwrap()) }
+
+  /// `restrictions` nodes must be the road nodes of `base`.
+  pub(crate) fn from_parts(base: Csr, road_classes: Vec<RoadClass>, restrictions: Vec<TurnRestriction>, profiles: Vec<RoutingProfile>) -> Self {
+    let mut graph = Self {
+      base,
+      road_classes,
+      profiles: Vec::new(),
+      class_costs: Vec::new(),
+      turn_restrictions: Vec::new(),
+      restrictions_by_link: HashMap::new(),
+      restrictions_by_first_link: HashMap::new(),
+      spatial: SpatialIndex::default(),
+      hierarchies: Vec::new(),
+      landmarks: Vec::new()
+    };
+    graph.set_profiles(profiles);
+    for r in restrictions {
+      graph.add_turn_restriction(r.kind, r.nodes);
+    }
+    graph
   }
-  /// uber unsafe :(
-  pub fn node_mut<'a, 'b>(&'a mut self, id: NodeId) -> &'b mut Node {
-    unsafe { std::mem::transmute::<_, &'b mut Node>(self.nodes.get_mut(id.0).unwrap()) }
+
+  pub(crate) fn csr(&self) -> &Csr {
+    &self.base
   }
 
-  pub fn add_node(&mut self, node: Node) -> NodeId {
-    let id = NodeId(self.nodes.len());
+  /// Number of road nodes.
+  pub fn nodes_num(&self) -> usize {
+    self.base.len()
+  }
 
-    self.node_map.insert(node.id, id);
-    self.nodes.push(node);
+  pub fn links_num(&self) -> usize {
+    self.base.links.len()
+  }
 
-    id
+  pub fn road_nodes(&self) -> impl Iterator<Item = NodeId> {
+    (0..self.base.len()).map(NodeId)
   }
 
-  pub fn add_map_point(&mut self, p: &MapPoint) -> NodeId {
-    let id = NodeId(self.nodes.len());
+  pub fn osm_id(&self, id: NodeId) -> u64 {
+    self.base.osm_ids[id.0]
+  }
 
-    self.node_map.insert(p.id, id);
-    self.nodes.push(Node {
-      id: p.id,
-      lat: p.lat,
-      lon: p.lon,
-      eta: u32::MAX,
-      kind: NodeKind::Plain,
-      nodes: Vec::new()
-    });
+  pub fn map_point(&self, id: NodeId) -> MapPoint {
+    MapPoint {
+      id: self.base.osm_ids[id.0],
+      lat: self.base.lats[id.0],
+      lon: self.base.lons[id.0],
+      path_id: None
+    }
+  }
 
-    id
+  /// Road links of the road node.
+  pub fn links(&self, id: NodeId) -> impl Iterator<Item = NodeLink> + '_ {
+    self.base.links(id.0).iter().map(NodeLink::from)
   }
 
-  pub fn add_car_map_point(&mut self, p: &MapPoint, free_seats: u8, path_id: u64) -> NodeId {
-    self.additional_nodes_num += 1;
-    self.nodes.push(Node {
-      id: p.id,
-      lat: p.lat,
-      lon: p.lon,
-      eta: u32::MAX,
-      kind: NodeKind::Car { eta: 0, free_seats, path_id },
-      nodes: Vec::new()
-    });
+  pub fn set_profiles(&mut self, profiles: Vec<RoutingProfile>) {
+    self.class_costs = profiles.iter()
+      .map(|p| self.road_classes.iter().map(|c| p.cost_per_cm(c)).collect())
+      .collect();
+    self.profiles = profiles;
+    self.spatial = SpatialIndex::build(self);
+  }
+
+  pub fn profiles(&self) -> &[RoutingProfile] {
+    &self.profiles
+  }
+
+  pub fn profile(&self, id: ProfileId) -> &RoutingProfile {
+    &self.profiles[id.0]
+  }
+
+  pub fn profile_id(&self, name: &str) -> Option<ProfileId> {
+    self.profiles.iter().position(|p| p.name == name).map(ProfileId)
+  }
+
+  /// First profile of the transport kind
+  pub fn default_profile(&self, kind: TransportKind) -> Option<ProfileId> {
+    self.profiles.iter().position(|p| p.mode == kind).map(ProfileId)
+  }
 
-    NodeId(self.nodes.len() - 1)
+  /// Search cost of the link in ms, `None` if the link can't be used with the profile.
+  pub(crate) fn link_cost(&self, link: &NodeLink, profile: ProfileId) -> Option<u32> {
+    let kind = self.profiles[profile.0].mode;
+    if !link.access.allows(kind) {
+      return None;
+    }
+
+    let cost_per_cm = match link.class {
+      Some(class) => self.class_costs[profile.0][class.0 as usize]?,
+      None => 1.0 / kind.get_speed().as_cm_per_millisecond()
+    };
+    Some((link.len as f64 * cost_per_cm).round() as u32)
   }
 
-  pub fn set_car_node_eta(&mut self, id: NodeId, eta: i64) {
-    if let NodeKind::Car { eta: ref mut orig_eta, .. } = self.node_mut(id).kind {
-      *orig_eta = eta;
+  /// Checksum of everything link costs of the profile depend on, tells if a hierarchy matches the profile.
+  pub fn costs_checksum(&self, profile: ProfileId) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[self.profile(profile).mode.is_car() as u8]);
+    for cost in self.class_costs[profile.0].iter() {
+      hasher.update(&cost.map_or(u64::MAX, f64::to_bits).to_le_bytes());
     }
+    hasher.finalize()
   }
 
-  pub fn connect_two_way(&mut self, n1_id: NodeId, n2_id: NodeId, len: u32, road_kind: TransportKind) {
-    let n1 = self.node_mut(n1_id);
-    n1.nodes.push(NodeLink {
-      node: n2_id,
-      len,
-      kind: road_kind
-    });
+  /// Builds contraction hierarchy of the profile, it replaces the previous one.
+  pub fn prepare_hierarchy(&mut self, profile: ProfileId) {
+    let hierarchy = Hierarchy::build(self, profile);
+    self.hierarchies.retain(|h| h.profile != hierarchy.profile);
+    self.hierarchies.push(hierarchy);
+  }
 
-    let n2 = self.node_mut(n2_id);
-    n2.nodes.push(NodeLink {
-      node: n1_id,
-      len,
-      kind: road_kind
-    });
+  pub fn hierarchies(&self) -> &[Hierarchy] {
+    &self.hierarchies
   }
 
-  pub fn node_id_by_osm_id(&self, id: u64) -> Option<NodeId> {
-    self.node_map.get(&id).copied()
+  pub(crate) fn set_hierarchies(&mut self, hierarchies: Vec<Hierarchy>) {
+    self.hierarchies = hierarchies;
+  }
+
+  /// Hierarchy of the profile if it's built with the current costs of the profile.
+  pub fn hierarchy(&self, profile: ProfileId) -> Option<&Hierarchy> {
+    let name = &self.profile(profile).name;
+    self.hierarchies.iter().find(|h| &h.profile == name && h.costs_checksum == self.costs_checksum(profile))
+  }
+
+  /// Picks `count` landmarks of the profile and computes costs to and from them,
+  /// they replace the previous ones of the profile.
+  pub fn prepare_landmarks(&mut self, profile: ProfileId, count: usize) {
+    let landmarks = Landmarks::build(self, profile, count);
+    self.landmarks.retain(|l| l.profile != landmarks.profile);
+    self.landmarks.push(landmarks);
+  }
+
+  pub fn all_landmarks(&self) -> &[Landmarks] {
+    &self.landmarks
+  }
+
+  pub(crate) fn set_landmarks(&mut self, landmarks: Vec<Landmarks>) {
+    self.landmarks = landmarks;
+  }
+
+  /// Landmarks of the profile if they're built with the current costs of the profile.
+  pub fn landmarks(&self, profile: ProfileId) -> Option<&Landmarks> {
+    let name = &self.profile(profile).name;
+    self.landmarks.iter().find(|l| &l.profile == name && l.costs_checksum == self.costs_checksum(profile))
+  }
+
+  /// Links usable with the profile reversed, for the searches to a node.
+  pub fn reversed_links(&self, profile: ProfileId) -> ReversedLinks {
+    let mut reversed = vec![Vec::new(); self.nodes_num()];
+    for id in self.road_nodes() {
+      for link in self.links(id) {
+        if let Some(cost) = self.link_cost(&link, profile) {
+          reversed[link.node.0].push((id, cost));
+        }
+      }
+    }
+
+    reversed
+  }
+
+  /// Min search cost of a centimeter of the links usable with the profile, links which are
+  /// not roads are passed at the speed of the profile mode.
+  fn min_cost_per_cm(&self, profile: ProfileId) -> f64 {
+    self.class_costs[profile.0].iter()
+      .flatten()
+      .copied()
+      .fold(1.0 / self.profile(profile).mode.get_speed().as_cm_per_millisecond(), f64::min)
+  }
+
+  /// Checks if the node has links usable with the profile.
+  pub fn is_accessible(&self, id: NodeId, profile: ProfileId) -> bool {
+    self.links(id).any(|l| l.class.is_some() && self.link_cost(&l, profile).is_some())
   }
 
-  fn reset_graph(&mut self) {
-    for _ in 0..self.additional_nodes_num {
-      let n = self.nodes.pop().unwrap();
-      let node_id = NodeId(self.nodes.len());
+  /// Checks if the node has links usable with any profile of the transport kind.
+  pub fn is_usable_by(&self, id: NodeId, kind: TransportKind) -> bool {
+    let profiles: Vec<ProfileId> = (0..self.profiles.len()).map(ProfileId).filter(|p| self.profile(*p).mode == kind).collect();
+    self.links(id).any(|l| l.class.is_some() && profiles.iter().any(|p| self.link_cost(&l, *p).is_some()))
+  }
 
-      for link in n.nodes.iter() {
-        let node = self.node_mut(link.node);
-        let ind = node.nodes.iter()
-          .position(|l| l.node == node_id).unwrap();
-        node.nodes.remove(ind);
+  /// Checks if any link between the nodes is usable with any profile of the transport kind.
+  pub fn is_edge_usable_by(&self, a: NodeId, b: NodeId, kind: TransportKind) -> bool {
+    (0..self.profiles.len()).map(ProfileId)
+      .filter(|p| self.profile(*p).mode == kind)
+      .any(|p| self.is_edge_accessible(a, b, p))
+  }
+
+  fn is_edge_accessible(&self, a: NodeId, b: NodeId, profile: ProfileId) -> bool {
+    let usable = |from: NodeId, to: NodeId| self.links(from)
+      .any(|l| l.node == to && l.class.is_some() && self.link_cost(&l, profile).is_some());
+    usable(a, b) || usable(b, a)
+  }
+
+  /// Snaps the point to the nearest road usable with the profile. If the nearest point of the road
+  /// is between its nodes a node is added there to the workspace, links of the road are split by it.
+  pub fn snap(&self, ws: &mut Workspace, lat: f64, lon: f64, profile: ProfileId) -> Option<Snap> {
+    let kind = self.profile(profile).mode;
+    let e = self.spatial.nearest_edge(self, lat, lon, kind, |a, b| self.is_edge_accessible(a, b, profile))?;
+    let node = match e.t {
+      t if t <= 0.0 => e.from,
+      t if t >= 1.0 => e.to,
+      t => {
+        let id = ws.add_node(OverlayNode {
+          lat: e.lat,
+          lon: e.lon,
+          links: Vec::new(),
+          edge: (e.from, e.to, t)
+        });
+        self.split_edge(ws, id, e.from, e.to, t);
+        id
       }
+    };
+
+    Some(Snap { node, lat: e.lat, lon: e.lon, distance: e.distance })
+  }
+
+  /// Links snapped point `id` at `t` of segment `a`-`b` with its ends and other points snapped to it.
+  fn split_edge(&self, ws: &mut Workspace, id: NodeId, a: NodeId, b: NodeId, t: f64) {
+    // other points on the segment, including the new one
+    let mut points: Vec<(f64, NodeId)> = ws.overlay.nodes.iter().enumerate()
+      .filter_map(|(i, n)| match n.edge {
+        (from, to, t) if (from, to) == (a, b) => Some((t, NodeId(ws.base_len + i))),
+        _ => None
+      })
+      .collect();
+    points.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
+    let pos = points.iter().position(|(_, n)| *n == id).unwrap();
+    let (prev, next) = (points.get(pos.wrapping_sub(1)).copied().unwrap_or((0.0, a)), points.get(pos + 1).copied().unwrap_or((1.0, b)));
+
+    let part = |len: u32, from: f64, to: f64| (len as f64 * (to - from)).round() as u32;
+    for link in self.links(a).filter(|l| l.node == b) {
+      ws.connect_one_way(prev.1, id, part(link.len, prev.0, t), link.access, link.class);
+      ws.connect_one_way(id, next.1, part(link.len, t, next.0), link.access, link.class);
+    }
+    for link in self.links(b).filter(|l| l.node == a) {
+      ws.connect_one_way(next.1, id, part(link.len, t, next.0), link.access, link.class);
+      ws.connect_one_way(id, prev.1, part(link.len, prev.0, t), link.access, link.class);
     }
-    self.additional_nodes_num = 0;
+  }
 
-    for n in self.nodes.iter_mut() {
-      n.eta = u32::MAX;
+  /// Up to `k` road nodes usable by the transport kind and passing the `filter` nearest to the point,
+  /// with distances in cm, ordered by distance.
+  pub fn nearest_nodes(&self, lat: f64, lon: f64, k: usize, kind: TransportKind, filter: impl Fn(NodeId) -> bool) -> Vec<(NodeId, u32)> {
+    self.spatial.nearest(self, lat, lon, k, kind, filter)
+  }
+
+  /// Adds turn restriction for car profiles, `nodes` are the node before via nodes,
+  /// via nodes and the node after them.
+  pub fn add_turn_restriction(&mut self, kind: RestrictionKind, nodes: Vec<NodeId>) {
+    assert!(nodes.len() >= 3, "turn restriction needs at least 3 nodes");
+
+    let last_via = nodes.len() - 2;
+    self.restrictions_by_link.entry((nodes[last_via - 1], nodes[last_via]))
+      .or_default()
+      .push(self.turn_restrictions.len());
+    if last_via > 1 {
+      self.restrictions_by_first_link.entry((nodes[0], nodes[1]))
+        .or_default()
+        .push(self.turn_restrictions.len());
     }
+    self.turn_restrictions.push(TurnRestriction { kind, nodes });
   }
 
-  pub fn shortest_path(&mut self, start: NodeId, end: NodeId, kind: TransportKind) -> PathResult {
-    let mut queue = BinaryHeap::new();
-    self.node_mut(start).eta = 0;
-    let start_node = self.node(start);
-    let end_node = self.node(end);
+  pub fn node_id_by_osm_id(&self, id: u64) -> Option<NodeId> {
+    self.base.find(id).map(NodeId)
+  }
 
-    let base_speed = kind.get_speed().as_cm_per_millisecond();
+  /// Empty workspace for queries on this graph.
+  pub fn workspace(&self) -> Workspace {
+    Workspace {
+      base_len: self.base.len(),
+      overlay: Overlay::default(),
+      search: SearchState::default()
+    }
+  }
 
-    queue.push(State {
-      cost: start_node.eta + distance_t(start_node, end_node, Kmh(50)),
-      node: start
-    });
+  /// Searches the path on the graph with the snapped points of the workspace.
+  /// The hierarchy of the profile is used if it's prepared. Car profiles use edge based search
+  /// if there are turn restrictions and the path found by the hierarchy breaks some of them.
+  ///
+  /// The path begins `depart` ms after the query start, etas of the result are relative to the departure.
+  pub fn shortest_path(&self, ws: &mut Workspace, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> PathResult {
+    self.search(ws, None, start, end, depart, profile)
+  }
 
-    while let Some(state) = queue.pop() {
-      if state.node == end {
-        debug!("queue len = {}", queue.len());
-        debug!("dist = {}", self.node(state.node).eta);
+  /// Searches the path which could use the cars, `depart` is relative to the query start of `cars`.
+  pub fn shortest_path_using_cars(&self, ws: &mut Workspace, cars: &Cars, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> PathResult {
+    self.search(ws, Some(cars), start, end, depart, profile)
+  }
+
+  /// Searches the paths using the cars which are the best by eta, walking distance or number of transfers,
+  /// each path is worse than every other one in some of them. Paths are ordered by eta.
+  pub fn journeys_using_cars(&self, ws: &mut Workspace, cars: &Cars, start: NodeId, end: NodeId, profile: ProfileId) -> Vec<PathResult> {
+    assert_eq!(ws.base_len, self.base.len(), "workspace belongs to another graph");
+    let query = Query { graph: self, overlay: &ws.overlay, cars: Some(*cars) };
+
+    query.pareto_search(start, end, 0, profile).into_iter()
+      .map(|path| query.path_result(path, 0, profile))
+      .collect()
+  }
+
+  /// Paths from `start` to every node of `targets` found by one search, or from every one of them
+  /// to `start` if the `reversed` links of the profile are given. The search stops when all targets
+  /// are reached or `max_settled` nodes are passed, `None` is returned for the targets not reached.
+  /// Road nodes only, turn restrictions are checked but not applied: paths breaking them are returned
+  /// with `false`, the allowed ones are not shorter and could be found by [`RoadGraph::shortest_path`].
+  pub fn paths_to_many(
+    &self,
+    ws: &mut Workspace,
+    start: NodeId,
+    targets: &[NodeId],
+    reversed: Option<&ReversedLinks>,
+    max_settled: usize,
+    profile: ProfileId
+  ) -> Vec<Option<(PathResult, bool)>> {
+    assert_eq!(ws.base_len, self.base.len(), "workspace belongs to another graph");
+    let search = &mut ws.search;
+    search.reset(self.nodes_num());
+    search.set(start.0, 0, None);
+    search.queue.push(State { cost: 0, node: start.0 });
+
+    let mut left: HashSet<usize> = targets.iter().map(|t| t.0).collect();
+    let mut settled = 0;
+    while let Some(state) = search.queue.pop() {
+      if left.is_empty() || settled == max_settled {
         break;
       }
+      if state.cost > search.etas[state.node] {
+        continue;
+      }
+      settled += 1;
+      left.remove(&state.node);
 
-      let node = self.node(state.node);
-
-      match node.kind {
-        NodeKind::Plain => {
-          for link in node.nodes.iter() {
-            if kind.is_foot() || kind.is_car() && link.kind.is_car() {
-              let next_node = self.node_mut(link.node);
-              let link_len_t = (link.len as f64 / base_speed).round() as u32;
-              match next_node.kind {
-                NodeKind::Plain => {
-                  if next_node.eta > node.eta + link_len_t {
-                    next_node.eta = node.eta + link_len_t;
-                    let dist = distance_t(next_node, end_node, Kmh(50));
-                    queue.push(State { cost: next_node.eta + dist, node: link.node });
-                  }
-                },
-
-                NodeKind::Car { eta, .. } => {
-                  if node.eta as i64 <= eta {
-                    let total_link_len = ROAD_TO_CAR + (eta - node.eta as i64) as u32;
-
-                    if next_node.eta > node.eta + total_link_len {
-                      next_node.eta = node.eta + total_link_len;
-                      let dist = distance_t(next_node, end_node, Kmh(50));
-                      queue.push(State { cost: next_node.eta + dist, node: link.node });
-                    }
-                  }
-                }
-              }
-            }
-          }
+      let node = NodeId(state.node);
+      match reversed {
+        Some(reversed) => for (prev, cost) in reversed[node.0].iter() {
+          search.relax(node.0, prev.0, state.cost + cost);
         },
-        NodeKind::Car {..} => {
-          for link in node.nodes.iter() {
-            if kind.is_foot() || kind.is_car() && link.kind.is_car() {
-              let next_node = self.node_mut(link.node);
-              let link_len_t = if let NodeKind::Plain {..} = next_node.kind {
-                ROAD_TO_CAR
-              } else {
-                (link.len as f64 / Kmh(50).as_cm_per_millisecond()).round() as u32
-              };
-              if next_node.eta > node.eta + link_len_t {
-                next_node.eta = node.eta + link_len_t;
-                let dist = distance_t(next_node, end_node, Kmh(50));
-                queue.push(State { cost: next_node.eta + dist, node: link.node });
-              }
-            }
+        None => for link in self.links(node) {
+          if let Some(cost) = self.link_cost(&link, profile) {
+            search.relax(node.0, link.node.0, state.cost + cost);
           }
         }
       }
     }
 
-    // assemble path from graph
-    let path_result = if end_node.eta == u32::MAX {
-      PathResult::default()
-    } else {
-      let mut path = vec![MapPoint::from(self.node(end))];
-      let mut path_etas = vec![self.node(end).eta];
-
-      let mut curr_node = end_node;
-      'main: while curr_node.eta != 0 {
-        trace!("\n---------------curr_node----------------");
-        trace!("id: {} kind: {:?} eta: {}\n----", curr_node.id, curr_node.kind, curr_node.eta);
-
-        match curr_node.kind {
-          NodeKind::Plain => {
-            for link in curr_node.nodes.iter() {
-              let n = self.node(link.node);
-              let link_len_t = if let NodeKind::Car{..} = n.kind {
-                ROAD_TO_CAR
-              } else {
-                (link.len as f64 / base_speed).round() as u32
-              };
-              trace!("id: {} kind: {:?} eta: {} = {} link_len: {}", n.id, n.kind, n.eta, curr_node.eta.overflowing_sub(link_len_t).0, link_len_t);
-              if n.eta < curr_node.eta && n.eta == curr_node.eta.overflowing_sub(link_len_t).0 {
-
-                curr_node = n;
-                path.push(MapPoint::from(n));
-                path_etas.push(n.eta);
-
-                continue 'main;
-              }
-            }
+    let query = Query { graph: self, overlay: &ws.overlay, cars: None };
+    let restricted = self.profile(profile).mode.is_car() && !self.turn_restrictions.is_empty();
+    targets.iter().map(|t| {
+      if left.contains(&t.0) {
+        return None;
+      }
+      let mut path = Vec::new();
+      let mut curr = Some(t.0);
+      while let Some(id) = curr {
+        path.push((NodeId(id), search.etas[id]));
+        curr = search.prevs[id];
+      }
+      match reversed {
+        // the path is already from the target, etas are counted from it
+        Some(_) => path.iter_mut().for_each(|(_, eta)| *eta = search.etas[t.0] - *eta),
+        None => path.reverse()
+      }
+      let allowed = !restricted || query.is_path_allowed(&path);
+      Some((query.path_result(path, 0, profile), allowed))
+    }).collect()
+  }
+
+  fn search(&self, ws: &mut Workspace, cars: Option<&Cars>, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> PathResult {
+    assert_eq!(ws.base_len, self.base.len(), "workspace belongs to another graph");
+    let query = Query { graph: self, overlay: &ws.overlay, cars: cars.copied() };
+
+    let restricted = self.profile(profile).mode.is_car() && !self.turn_restrictions.is_empty();
+    let hierarchy = if cars.is_none() { self.hierarchy(profile) } else { None };
+    let path = match hierarchy {
+      // the shortest path without restrictions is the shortest one with them if it's allowed
+      Some(hierarchy) => Some(query.hierarchy_search(hierarchy, start, end, depart, profile))
+        .filter(|path| !restricted || query.is_path_allowed(path)),
+      None => None
+    };
+    let path = match path {
+      Some(path) => path,
+      None if restricted => query.edge_based_search(&mut ws.search, start, end, depart, profile),
+      None => query.node_based_search(&mut ws.search, start, end, depart, profile)
+    };
+
+    query.path_result(path, depart, profile)
+  }
+}
+
+/// State of a query: snapped point nodes added to the graph and search buffers.
+///
+/// Workspace could be reused for the next query on the same graph after [`Workspace::clear`],
+/// search buffers are reset by every search.
+pub struct Workspace {
+  /// number of road nodes, ids of added nodes follow them
+  base_len: usize,
+  overlay: Overlay,
+  search: SearchState
+}
+
+impl Workspace {
+  /// Removes added nodes and their links.
+  pub fn clear(&mut self) {
+    self.overlay.nodes.clear();
+    self.overlay.extra_links.clear();
+  }
+
+  /// Point of a road node or a node added to the workspace.
+  pub fn map_point(&self, graph: &RoadGraph, id: NodeId) -> MapPoint {
+    Query { graph, overlay: &self.overlay, cars: None }.map_point(id)
+  }
+
+  fn add_node(&mut self, node: OverlayNode) -> NodeId {
+    self.overlay.nodes.push(node);
+    NodeId(self.base_len + self.overlay.nodes.len() - 1)
+  }
+
+  fn connect_one_way(&mut self, from: NodeId, to: NodeId, len: u32, access: Access, class: Option<RoadClassId>) {
+    let link = NodeLink {
+      node: to,
+      len,
+      access,
+      class
+    };
+    match from.0.checked_sub(self.base_len) {
+      Some(i) => self.overlay.nodes[i].links.push(link),
+      None => self.overlay.extra_links.entry(from).or_default().push(link)
+    }
+  }
+}
+
+/// Nodes and links added to the road graph by a query.
+#[derive(Default)]
+struct Overlay {
+  nodes: Vec<OverlayNode>,
+  /// links from road nodes
+  extra_links: HashMap<NodeId, Vec<NodeLink>>
+}
+
+/// Road graph with the query overlay and car paths.
+///
+/// Road nodes are followed by the overlay nodes and then by the slots of car paths points.
+struct Query<'a> {
+  graph: &'a RoadGraph,
+  overlay: &'a Overlay,
+  cars: Option<Cars<'a>>
+}
+
+/// Node of the query graph.
+enum QueryNode<'a> {
+  Road,
+  Overlay(&'a OverlayNode),
+  Car(&'a CarPoint)
+}
+
+impl<'a> Query<'a> {
+  fn cars_base(&self) -> usize {
+    self.graph.nodes_num() + self.overlay.nodes.len()
+  }
+
+  fn nodes_num(&self) -> usize {
+    self.cars_base() + self.cars.map_or(0, |c| c.paths.slots())
+  }
+
+  fn node(&self, id: NodeId) -> QueryNode<'a> {
+    match id.0.checked_sub(self.graph.nodes_num()) {
+      None => QueryNode::Road,
+      Some(i) if i < self.overlay.nodes.len() => QueryNode::Overlay(&self.overlay.nodes[i]),
+      Some(i) => QueryNode::Car(self.cars.unwrap().paths.point(i - self.overlay.nodes.len()).unwrap())
+    }
+  }
+
+  fn overlay_node(&self, id: NodeId) -> Option<&'a OverlayNode> {
+    match self.node(id) {
+      QueryNode::Overlay(n) => Some(n),
+      _ => None
+    }
+  }
+
+  fn car_node(&self, slot: usize) -> NodeId {
+    NodeId(self.cars_base() + slot)
+  }
+
+  /// Osm id of the road node or of the road node the car path point is placed at, 0 for snapped points.
+  fn osm_id(&self, id: NodeId) -> u64 {
+    match self.node(id) {
+      QueryNode::Road => self.graph.osm_id(id),
+      QueryNode::Overlay(_) => 0,
+      QueryNode::Car(p) => p.osm_id
+    }
+  }
+
+  fn kind(&self, id: NodeId) -> NodeKind {
+    match self.node(id) {
+      QueryNode::Car(p) => {
+        let cars = self.cars.unwrap();
+        NodeKind::Car { eta: p.eta - cars.start_at, free_seats: cars.free_seats(p), path_id: p.path_id }
+      },
+      _ => NodeKind::Plain
+    }
+  }
+
+  fn map_point(&self, id: NodeId) -> MapPoint {
+    match self.node(id) {
+      QueryNode::Road => self.graph.map_point(id),
+      QueryNode::Overlay(n) => MapPoint { id: 0, lat: n.lat, lon: n.lon, path_id: None },
+      QueryNode::Car(p) => p.map_point()
+    }
+  }
+
+  fn links(&self, id: NodeId) -> impl Iterator<Item = NodeLink> + 'a {
+    let node = self.node(id);
+    let (road, extra): (&[RawLink], &[NodeLink]) = match node {
+      QueryNode::Road => (self.graph.base.links(id.0), self.overlay.extra_links.get(&id).map_or(&[], Vec::as_slice)),
+      QueryNode::Overlay(n) => (&[], &n.links),
+      QueryNode::Car(_) => (&[], &[])
+    };
+
+    // cars passed the node before the query start are skipped
+    let boarding: &[usize] = match (&node, self.cars) {
+      (QueryNode::Road, Some(cars)) => {
+        let at_node = cars.paths.at_node(id);
+        let first_eta = cars.start_at - cars.boarding.driver_tolerance as i64;
+        &at_node[at_node.partition_point(|s| cars.paths.point(*s).unwrap().eta < first_eta)..]
+      },
+      _ => &[]
+    };
+    let cars_base = self.cars_base();
+    let to_car = boarding.iter().map(move |s| NodeLink { node: NodeId(cars_base + s), len: ROAD_TO_CAR, access: TransportKind::Foot.into(), class: None });
+
+    // car path points are linked in the direction of travel only
+    let along: [Option<NodeLink>; 2] = match node {
+      QueryNode::Car(p) => [
+        Some(NodeLink { node: p.node, len: ROAD_TO_CAR, access: TransportKind::Foot.into(), class: None }),
+        p.next.map(|s| NodeLink { node: self.car_node(s), len: p.len, access: TransportKind::Car.into(), class: None })
+      ],
+      _ => [None; 2]
+    };
+
+    road.iter().map(NodeLink::from)
+      .chain(extra.iter().copied())
+      .chain(to_car)
+      .chain(IntoIterator::into_iter(along).flatten())
+  }
+
+  /// Returns found path as nodes with their etas, empty if `end` is unreachable.
+  ///
+  /// With cars the search state is the node and the number of rides taken to it, paths with more
+  /// rides don't replace better paths with less rides. Every ride after the first one is a transfer,
+  /// its penalty is added to the path cost but not to the etas.
+  fn node_based_search(&self, search: &mut SearchState, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> Vec<(NodeId, u32)> {
+    let nodes_num = self.nodes_num();
+    let transfers = self.cars.map(|c| c.transfers);
+    // number of rides is the layer of the state
+    let layers = transfers.map_or(1, |t| t.max as usize + 2);
+    let penalty = |layer: usize| transfers.map_or(0, |t| layer.saturating_sub(1) as u32 * t.penalty);
+    search.reset(nodes_num * layers);
+
+    search.set(start.0, depart, None);
+
+    let heuristic = self.heuristic(end, profile);
+
+    search.queue.push(State {
+      cost: depart + heuristic(start),
+      node: start.0
+    });
+
+    let mut found = None;
+    while let Some(state) = search.queue.pop() {
+      let (node, layer) = (NodeId(state.node % nodes_num), state.node / nodes_num);
+      if node == end {
+        debug!("queue len = {}", search.queue.len());
+        debug!("dist = {}", search.etas[state.node]);
+        found = Some(state.node);
+        break;
+      }
+
+      let node_eta = search.etas[state.node];
+      if state.cost > node_eta + penalty(layer) + heuristic(node) {
+        continue;
+      }
+      for link in self.links(node) {
+        let next_layer = layer + self.is_boarding(node, link.node) as usize;
+        if next_layer == layers {
+          continue;
+        }
+        let link_len_t = match self.move_time(node, node_eta, &link, profile) {
+          Some(t) => t,
+          None => continue
+        };
+
+        let next = next_layer * nodes_num + link.node.0;
+        let next_eta = node_eta + link_len_t;
+        // the same node with less rides and not later eta is better
+        if (0..next_layer).any(|l| search.etas[l * nodes_num + link.node.0] <= next_eta) {
+          continue;
+        }
+        if search.etas[next] > next_eta {
+          search.set(next, next_eta, Some(state.node));
+          search.queue.push(State { cost: next_eta + penalty(next_layer) + heuristic(link.node), node: next });
+        }
+      }
+    }
+
+    let mut path = Vec::new();
+    let mut curr = found;
+    while let Some(state) = curr {
+      path.push((NodeId(state % nodes_num), search.etas[state]));
+      curr = search.prevs[state];
+    }
+    path.reverse();
+
+    path
+  }
+
+  /// Returns paths to `end` not worse than each other in all of eta, walking distance and number of transfers,
+  /// ordered by eta. Each label is a path to a node, labels of a node dominated by a new one are dropped.
+  /// Labels are compared by rides on the way, a path without rides yet could take a car without a transfer.
+  fn pareto_search(&self, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> Vec<Vec<(NodeId, u32)>> {
+    let max_rides = self.cars.map_or(0, |c| c.transfers.max + 1);
+    let heuristic = self.heuristic(end, profile);
+
+    let mut labels = vec![Label { node: start, eta: depart, walk: 0, rides: 0, prev: None, dropped: false }];
+    let mut bags: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    bags.insert(start, vec![0]);
+    let mut queue = BinaryHeap::new();
+    queue.push(State { cost: depart + heuristic(start), node: 0 });
+
+    let mut found: Vec<usize> = Vec::new();
+    while let Some(state) = queue.pop() {
+      let label = labels[state.node].clone();
+      // labels at the end are popped in the order of eta, later ones should be better otherwise
+      if label.dropped || found.iter().any(|f| labels[*f].walk <= label.walk && labels[*f].rides <= label.rides) {
+        continue;
+      }
+      if label.node == end {
+        found.push(state.node);
+        continue;
+      }
+
+      for link in self.links(label.node) {
+        let boards = self.is_boarding(label.node, link.node);
+        let walks = matches!((self.kind(label.node), self.kind(link.node)), (NodeKind::Plain, NodeKind::Plain));
+        let next = Label {
+          node: link.node,
+          eta: match self.move_time(label.node, label.eta, &link, profile) {
+            Some(t) => label.eta + t,
+            None => continue
           },
-          NodeKind::Car {eta, ..} => {
-            for link in curr_node.nodes.iter() {
-              let n = self.node(link.node);
-              if n.eta < curr_node.eta {
-                match n.kind {
-                  NodeKind::Plain => {
-                    trace!("id: {} kind: {:?} eta: {} = {} link_len: {}", n.id, n.kind, n.eta, curr_node.eta.overflowing_sub(ROAD_TO_CAR + (eta - n.eta as i64) as u32).0, ROAD_TO_CAR + (eta - n.eta as i64) as u32);
-
-                    if n.eta == curr_node.eta.overflowing_sub(ROAD_TO_CAR + (eta - n.eta as i64) as u32).0 && path[path.len() - 2].id != n.id {
-                      curr_node = n;
-                      path.push(MapPoint::from(n));
-                      path_etas.push(n.eta);
-
-                      continue 'main;
-                    }
-                  },
-                  NodeKind::Car { .. } => {
-                    let link_len_t = (link.len as f64 / Kmh(50).as_cm_per_millisecond()).round() as u32;
-                    trace!("id: {} kind: {:?} eta: {} = {} link_len: {}", n.id, n.kind, n.eta, curr_node.eta.overflowing_sub(link_len_t + ROAD_TO_CAR).0, link_len_t);
-
-                    if n.eta == curr_node.eta.overflowing_sub(link_len_t).0 {
-                      curr_node = n;
-                      path.push(MapPoint::from(n));
-                      path_etas.push(n.eta);
-
-                      continue 'main;
-                    }
-                  }
-                }
-              }
-            }
-          }
+          walk: label.walk + if walks { link.len } else { 0 },
+          rides: label.rides + boards as u32,
+          prev: Some(state.node),
+          dropped: false
+        };
+        if next.rides > max_rides {
+          continue;
         }
-        error!("Couldn't find path");
-        break;
+
+        let bag = bags.entry(link.node).or_default();
+        if bag.iter().any(|l| labels[*l].dominates(&next)) {
+          continue;
+        }
+        bag.retain(|l| {
+          let dominated = next.dominates(&labels[*l]);
+          labels[*l].dropped |= dominated;
+          !dominated
+        });
+        bag.push(labels.len());
+        queue.push(State { cost: next.eta + heuristic(link.node), node: labels.len() });
+        labels.push(next);
       }
+    }
+    debug!("labels = {}, journeys = {}", labels.len(), found.len());
 
+    // of the equal paths the first one is kept
+    let dominates = |a: usize, b: usize| {
+      let (a, b) = (&labels[a], &labels[b]);
+      a.eta <= b.eta && a.walk <= b.walk && a.rides.saturating_sub(1) <= b.rides.saturating_sub(1)
+    };
+    let found: Vec<usize> = found.iter().enumerate()
+      .filter(|(i, f)| !found.iter().enumerate().any(|(j, o)| j != *i && dominates(*o, **f) && (j < *i || !dominates(**f, *o))))
+      .map(|(_, f)| *f)
+      .collect();
+
+    found.into_iter().map(|f| {
+      let mut path = Vec::new();
+      let mut curr = Some(f);
+      while let Some(l) = curr {
+        path.push((labels[l].node, labels[l].eta));
+        curr = labels[l].prev;
+      }
       path.reverse();
-      path_etas.reverse();
+      path
+    }).collect()
+  }
 
-      let path_distances = path.iter().zip(path.iter().skip(1))
-        .fold(vec![0], |mut acc, (prev, next)| {
-          acc.push(*acc.last().unwrap() + (distance(prev, next) as f32 / 100.0).round() as u32);
-          acc
-        });
+  /// Search on the hierarchy of the road nodes, the snapped points are passed by small searches
+  /// from the start and to the end over the links of the snapped points.
+  fn hierarchy_search(&self, hierarchy: &Hierarchy, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> Vec<(NodeId, u32)> {
+    let from_start = self.overlay_reach(start, profile);
+    let sources: Vec<(NodeId, u32)> = from_start.iter()
+      .filter(|(id, _)| matches!(self.node(**id), QueryNode::Road))
+      .map(|(id, (cost, _))| (*id, *cost))
+      .collect();
+    // the end is reached from the ends of its road segment
+    let entries: Vec<NodeId> = match self.snapped_edge(end) {
+      Some((a, b, _)) => vec![a, b],
+      None => vec![end]
+    };
+    let to_end: Vec<(NodeId, Reach)> = entries.into_iter()
+      .map(|r| (r, self.overlay_reach(r, profile)))
+      .filter(|(_, reach)| reach.contains_key(&end))
+      .collect();
+    let targets: Vec<(NodeId, u32)> = to_end.iter().map(|(r, reach)| (*r, reach[&end].0)).collect();
+
+    let through_roads = hierarchy.query(&sources, &targets).map(|path| {
+      let last = path.last().unwrap().0;
+      (path.last().unwrap().1 + reach_cost(&to_end, last, end), path)
+    });
+    let direct = from_start.get(&end).map(|(cost, _)| *cost);
+
+    let mut path = match (through_roads, direct) {
+      (Some((cost, road_path)), direct) if direct.is_none_or(|d| cost < d) => {
+        let first = road_path[0].0;
+        let mut path = reach_path(&from_start, first);
+        path.pop();
+        path.extend(road_path.iter().copied());
+        let (_, reach) = to_end.iter().find(|(r, _)| *r == road_path.last().unwrap().0).unwrap();
+        let offset = path.last().unwrap().1;
+        path.extend(reach_path(reach, end).into_iter().skip(1).map(|(id, cost)| (id, offset + cost)));
+        path
+      },
+      (_, Some(_)) => reach_path(&from_start, end),
+      _ => return Vec::new()
+    };
+    for (_, eta) in path.iter_mut() {
+      *eta += depart;
+    }
+
+    path
+  }
 
-      PathResult {
-        total_time: *path_etas.last().unwrap(),
-        total_distance: *path_distances.last().unwrap(),
-        points: path,
-        eta_list: path_etas,
-        distance_list: path_distances,
+  /// Costs and previous nodes of the nodes reached from `from` over the links of the snapped points,
+  /// road nodes are reached but not passed.
+  fn overlay_reach(&self, from: NodeId, profile: ProfileId) -> Reach {
+    let mut reach: Reach = HashMap::new();
+    reach.insert(from, (0, None));
+    let mut queue = BinaryHeap::new();
+    queue.push(State { cost: 0, node: from.0 });
+    while let Some(State { cost, node }) = queue.pop() {
+      let node = NodeId(node);
+      if cost > reach[&node].0 || (node != from && matches!(self.node(node), QueryNode::Road)) {
+        continue;
+      }
+      for link in self.links(node) {
+        let next = match self.graph.link_cost(&link, profile) {
+          Some(link_cost) => cost + link_cost,
+          None => continue
+        };
+        if reach.get(&link.node).is_none_or(|(c, _)| next < *c) {
+          reach.insert(link.node, (next, Some(node)));
+          queue.push(State { cost: next, node: link.node.0 });
+        }
+      }
+    }
+
+    reach
+  }
+
+  /// Lower bound of the search cost from a node to `end` for A* searches. It's the distance at the max
+  /// speed of the profile roads, or of the car paths if they are faster. Road nodes get a better bound
+  /// from the landmarks of the profile if they are prepared and car paths aren't used.
+  fn heuristic(&self, end: NodeId, profile: ProfileId) -> impl Fn(NodeId) -> u32 + '_ {
+    let end_point = self.map_point(end);
+    let mut min_cost_per_cm = self.graph.min_cost_per_cm(profile);
+    if self.cars.is_some() {
+      min_cost_per_cm = min_cost_per_cm.min(1.0 / CAR_PATH_SPEED.as_cm_per_millisecond());
+    }
+    let landmarks = self.graph.landmarks(profile)
+      .filter(|_| self.cars.is_none())
+      .map(|l| (l, self.landmark_bounds(l, end, profile)));
+
+    move |id: NodeId| {
+      let by_distance = (distance(&self.map_point(id), &end_point) as f64 * min_cost_per_cm) as u32;
+      match (&landmarks, self.node(id)) {
+        (Some((landmarks, bounds)), QueryNode::Road) => by_distance.max(landmarks.lower_bound(id, bounds)),
+        _ => by_distance
       }
+    }
+  }
+
+  /// Bounds of the costs between the landmarks and `end`. A snapped point is entered from
+  /// the ends of its road segment and leaves to the road nodes reached over its links.
+  fn landmark_bounds(&self, landmarks: &Landmarks, end: NodeId, profile: ProfileId) -> Vec<TargetBounds> {
+    match self.snapped_edge(end) {
+      Some((a, b, _)) => {
+        let exits: Vec<(NodeId, u32)> = self.overlay_reach(end, profile).into_iter()
+          .filter(|(id, _)| matches!(self.node(*id), QueryNode::Road))
+          .map(|(id, (cost, _))| (id, cost))
+          .collect();
+        landmarks.point_bounds(&[a, b], &exits)
+      },
+      None => landmarks.node_bounds(end)
+    }
+  }
+
+  fn is_boarding(&self, from: NodeId, to: NodeId) -> bool {
+    matches!((self.kind(from), self.kind(to)), (NodeKind::Plain, NodeKind::Car { .. }))
+  }
+
+  /// Time to move along the link from the node reached at `node_eta`, `None` if the link can't be used:
+  /// it is closed for the profile, the car is missed or has no free seats.
+  fn move_time(&self, node: NodeId, node_eta: u32, link: &NodeLink, profile: ProfileId) -> Option<u32> {
+    let node_kind = self.kind(node);
+    match (node_kind, self.kind(link.node)) {
+      (NodeKind::Plain, NodeKind::Plain) => self.graph.link_cost(link, profile),
+      (NodeKind::Plain, NodeKind::Car { eta, .. }) => {
+        self.graph.link_cost(link, profile)?;
+        self.cars.unwrap().boarding.board_cost(node_eta, eta)
+      },
+      _ if !link.access.allows(self.graph.profile(profile).mode) => None,
+      (_, NodeKind::Plain) => Some(self.cars.unwrap().boarding.dwell),
+      (NodeKind::Car { free_seats, .. }, NodeKind::Car { .. }) => {
+        if free_seats == 0 {
+          return None;
+        }
+        Some((link.len as f64 / CAR_PATH_SPEED.as_cm_per_millisecond()).round() as u32)
+      }
+    }
+  }
+
+  /// Search over links instead of nodes: the best way to reach a node could be useless
+  /// if the turn we need next is forbidden after it.
+  /// Links to car path nodes are not used, cars don't switch to other cars.
+  ///
+  /// Paths following restrictions with via ways are kept apart from other paths to the same link,
+  /// so a faster restricted path doesn't hide a legal one.
+  fn edge_based_search(&self, search: &mut SearchState, start: NodeId, end: NodeId, depart: u32, profile: ProfileId) -> Vec<(NodeId, u32)> {
+    let heuristic = self.heuristic(end, profile);
+
+    let mut states = EdgeStates::new(self, start);
+    search.reset(states.plain_num());
+    // start is reached by virtual link from itself
+    search.set(states.start_id(), depart, None);
+    search.queue.push(State { cost: depart + heuristic(start), node: states.start_id() });
+
+    let mut found = None;
+    while let Some(state) = search.queue.pop() {
+      let ((from, node), progress) = states.state(self, state.node);
+      let progress = progress.to_vec();
+      let eta = search.etas[state.node];
+      if state.cost > eta + heuristic(node) {
+        continue;
+      }
+      if node == end {
+        debug!("queue len = {}", search.queue.len());
+        debug!("dist = {}", eta);
+        found = Some(state.node);
+        break;
+      }
+
+      // there are no turns at the start and in the middle of a road segment
+      let turn = Some(from).filter(|from| *from != node && self.snapped_edge(node).is_none()).map(|from| self.road_link((from, node)));
+      for (i, link) in self.links(node).enumerate() {
+        if !matches!(self.kind(link.node), NodeKind::Plain) {
+          continue;
+        }
+        let link_len_t = match self.graph.link_cost(&link, profile) {
+          Some(cost) => cost,
+          None => continue
+        };
+        let road_link = self.road_link((node, link.node));
+        if turn.is_some_and(|turn| !self.is_turn_allowed(turn, &progress, road_link.1)) {
+          continue;
+        }
+
+        let next_progress = if self.snapped_edge(link.node).is_some() { progress.clone() } else { self.advance(&progress, road_link) };
+        let next = states.id(states.link_id(self, node, i), next_progress);
+        search.grow(next + 1);
+        let next_eta = eta + link_len_t;
+        if search.etas[next] > next_eta {
+          search.set(next, next_eta, Some(state.node));
+          search.queue.push(State { cost: next_eta + heuristic(link.node), node: next });
+        }
+      }
+    }
+
+    let mut path = Vec::new();
+    let mut curr = found;
+    while let Some(state) = curr {
+      path.push((states.state(self, state).0 .1, search.etas[state]));
+      curr = search.prevs[state];
+    }
+    path.reverse();
+
+    path
+  }
+
+  fn snapped_edge(&self, id: NodeId) -> Option<(NodeId, NodeId, f64)> {
+    self.overlay_node(id).map(|n| n.edge)
+  }
+
+  /// Road link the link is a part of, links of snapped points are parts of their road segments.
+  fn road_link(&self, (from, to): (NodeId, NodeId)) -> (NodeId, NodeId) {
+    match (self.snapped_edge(from), self.snapped_edge(to)) {
+      (Some((a, b, from_t)), Some((_, _, to_t))) => if from_t < to_t { (a, b) } else { (b, a) },
+      (Some((a, b, _)), None) if to == b => (a, b),
+      (Some((a, b, _)), None) if to == a => (b, a),
+      (None, Some((a, b, _))) if from == a => (a, b),
+      (None, Some((a, b, _))) if from == b => (b, a),
+      _ => (from, to)
+    }
+  }
+
+  /// Checks restrictions of the turn from the road `link` to the road node `next`,
+  /// `progress` has the restrictions with via ways followed up to the turn.
+  fn is_turn_allowed(&self, link: (NodeId, NodeId), progress: &[(usize, usize)], next: NodeId) -> bool {
+    let restrictions = match self.graph.restrictions_by_link.get(&link) {
+      Some(r) => r,
+      None => return true
+    };
+
+    let mut only_turns = Vec::new();
+    for i in restrictions.iter().copied() {
+      let r = &self.graph.turn_restrictions[i];
+      let (to, before) = r.nodes.split_last().unwrap();
+      // via ways should be passed before the turn
+      let last_via = before.len() - 1;
+      if last_via > 1 && !progress.contains(&(i, last_via)) {
+        continue;
+      }
+
+      match r.kind {
+        RestrictionKind::No if *to == next => return false,
+        RestrictionKind::No => {},
+        RestrictionKind::Only => only_turns.push(*to)
+      }
+    }
+
+    only_turns.is_empty() || only_turns.contains(&next)
+  }
+
+  /// Restrictions with via ways followed after passing the road `link` when `progress` were followed before it.
+  /// Progress of a restriction is the index of its last passed node.
+  fn advance(&self, progress: &[(usize, usize)], (from, to): (NodeId, NodeId)) -> Progress {
+    let restrictions = &self.graph.turn_restrictions;
+    let mut next: Progress = progress.iter().copied()
+      .filter(|(r, p)| {
+        let nodes = &restrictions[*r].nodes;
+        nodes[*p] == from && nodes[p + 1] == to && p + 1 < nodes.len() - 1
+      })
+      .map(|(r, p)| (r, p + 1))
+      .collect();
+    if let Some(started) = self.graph.restrictions_by_first_link.get(&(from, to)) {
+      next.extend(started.iter().map(|r| (*r, 1)));
+    }
+    next.sort_unstable();
+    next.dedup();
+    next
+  }
+
+  /// Checks if the path follows the turn restrictions the same way as [`Query::edge_based_search`].
+  fn is_path_allowed(&self, path: &[(NodeId, u32)]) -> bool {
+    let mut progress = Progress::new();
+    for (i, pair) in path.windows(2).enumerate() {
+      let (node, next) = (pair[0].0, pair[1].0);
+      let road_link = self.road_link((node, next));
+      if i > 0 && self.snapped_edge(node).is_none() {
+        let turn = self.road_link((path[i - 1].0, node));
+        if !self.is_turn_allowed(turn, &progress, road_link.1) {
+          return false;
+        }
+      }
+      if self.snapped_edge(next).is_none() {
+        progress = self.advance(&progress, road_link);
+      }
+    }
+
+    true
+  }
+
+  fn is_car_node(&self, id: NodeId) -> bool {
+    matches!(self.node(id), QueryNode::Car(_))
+  }
+
+  /// Splits the path into runs of road nodes and of car nodes of a ride. A ride is boarded
+  /// at the road node before its run and left at the road node after it, paths begin and end
+  /// at road nodes.
+  fn path_legs(&self, path: &[(NodeId, u32)], etas: &[u32], distances: &[u32], profile: ProfileId) -> Vec<PathLeg> {
+    let plain_mode = if self.graph.profile(profile).mode.is_car() { LegMode::Drive } else { LegMode::Walk };
+    let mut legs = Vec::new();
+    let mut first = 0;
+    for i in 1..=path.len() {
+      let is_car = self.is_car_node(path[first].0);
+      if i < path.len() && self.is_car_node(path[i].0) == is_car {
+        continue;
+      }
+      let last = i - 1;
+      let (from, to) = (self.map_point(path[first].0), self.map_point(path[last].0));
+      if is_car {
+        let (board, alight) = (first - 1, i);
+        let dwell = self.cars.map_or(0, |c| c.boarding.dwell);
+        legs.push(PathLeg {
+          mode: LegMode::Ride,
+          path_id: from.path_id,
+          from,
+          to,
+          start_time: etas[board],
+          end_time: etas[alight],
+          distance: distances[last] - distances[first],
+          wait_time: (etas[first] - etas[board]).saturating_sub(dwell)
+        });
+      } else if last > first || path.len() == 1 {
+        legs.push(PathLeg {
+          mode: plain_mode,
+          path_id: None,
+          from,
+          to,
+          start_time: etas[first],
+          end_time: etas[last],
+          distance: distances[last] - distances[first],
+          wait_time: 0
+        });
+      }
+      first = i;
+    }
+
+    legs
+  }
+
+  fn path_result(&self, path: Vec<(NodeId, u32)>, depart: u32, profile: ProfileId) -> PathResult {
+    if path.is_empty() {
+      return PathResult::default();
+    }
+
+    let mut points = Vec::with_capacity(path.len());
+    let mut path_etas = Vec::with_capacity(path.len());
+    for (id, eta) in path.iter().copied() {
+      trace!("id: {} kind: {:?} eta: {}", self.osm_id(id), self.kind(id), eta);
+      points.push(self.map_point(id));
+      path_etas.push(eta - depart);
+    }
+
+    let path_distances = points.iter().zip(points.iter().skip(1))
+      .fold(vec![0], |mut acc, (prev, next)| {
+        acc.push(*acc.last().unwrap() + (distance(prev, next) as f32 / 100.0).round() as u32);
+        acc
+      });
+
+    let rides = path.iter().zip(path.iter().skip(1))
+      .filter(|((prev, _), (next, _))| self.is_boarding(*prev, *next))
+      .count() as u32;
+
+    let walk_distance = (1..path.len())
+      .filter(|&i| matches!((self.kind(path[i - 1].0), self.kind(path[i].0)), (NodeKind::Plain, NodeKind::Plain)))
+      .map(|i| path_distances[i] - path_distances[i - 1])
+      .sum();
+
+    PathResult {
+      legs: self.path_legs(&path, &path_etas, &path_distances, profile),
+      total_time: *path_etas.last().unwrap(),
+      transfers: rides.saturating_sub(1),
+      walk_distance,
+      total_distance: *path_distances.last().unwrap(),
+      points,
+      eta_list: path_etas,
+      distance_list: path_distances,
+      ..PathResult::default()
+    }
+  }
+}
+
+/// Collects road network by osm ids and packs it into [`RoadGraph`].
+#[derive(Default)]
+pub struct GraphBuilder {
+  /// osm id, lat, lon
+  nodes: Vec<(u64, f64, f64)>,
+  /// osm ids of the link ends and the link with unset `to`
+  links: Vec<(u64, u64, RawLink)>,
+  road_classes: Vec<RoadClass>,
+  road_class_map: HashMap<RoadClass, RoadClassId>,
+  restrictions: Vec<(RestrictionKind, Vec<u64>)>
+}
+
+impl GraphBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn add_node(&mut self, id: u64, lat: f64, lon: f64) {
+    self.nodes.push((id, lat, lon));
+  }
+
+  /// Returns id of the road class, registering it if it's new.
+  pub fn road_class_id(&mut self, class: RoadClass) -> RoadClassId {
+    if let Some(id) = self.road_class_map.get(&class) {
+      return *id;
+    }
+
+    let id = RoadClassId(self.road_classes.len() as u32);
+    self.road_classes.push(class.clone());
+    self.road_class_map.insert(class, id);
+    id
+  }
+
+  pub fn connect_one_way(&mut self, from: u64, to: u64, len: u32, access: Access, class: Option<RoadClassId>) {
+    self.links.push((from, to, RawLink {
+      to: 0,
+      len,
+      class: class.map_or(NO_CLASS, |c| c.0),
+      access: access.bits()
+    }));
+  }
+
+  pub fn connect_two_way(&mut self, n1: u64, n2: u64, len: u32, access: Access, class: Option<RoadClassId>) {
+    self.connect_one_way(n1, n2, len, access, class);
+    self.connect_one_way(n2, n1, len, access, class);
+  }
+
+  /// Same as [`RoadGraph::add_turn_restriction`] with osm ids of the nodes.
+  pub fn add_turn_restriction(&mut self, kind: RestrictionKind, nodes: Vec<u64>) {
+    assert!(nodes.len() >= 3, "turn restriction needs at least 3 nodes");
+    self.restrictions.push((kind, nodes));
+  }
+
+  /// Links and restrictions with unknown nodes are dropped.
+  pub fn build(mut self, profiles: Vec<RoutingProfile>) -> RoadGraph {
+    self.nodes.sort_by_key(|n| n.0);
+    self.nodes.dedup_by_key(|n| n.0);
+    let nodes = self.nodes;
+    let index = |id: u64| nodes.binary_search_by_key(&id, |n| n.0).ok();
+
+    let mut links: Vec<(usize, RawLink)> = self.links.into_iter()
+      .filter_map(|(from, to, link)| Some((index(from)?, RawLink { to: index(to)? as u32, ..link })))
+      .collect();
+    links.sort_by_key(|l| l.0);
+
+    let mut offsets = vec![0u32; nodes.len() + 1];
+    for (from, _) in links.iter() {
+      offsets[from + 1] += 1;
+    }
+    for i in 1..offsets.len() {
+      offsets[i] += offsets[i - 1];
+    }
+
+    let restrictions = self.restrictions.into_iter()
+      .filter_map(|(kind, nodes)| Some(TurnRestriction {
+        kind,
+        nodes: nodes.into_iter().map(|id| index(id).map(NodeId)).collect::<Option<_>>()?
+      }))
+      .collect();
+
+    let base = Csr {
+      osm_ids: Slab::Owned(nodes.iter().map(|n| n.0).collect()),
+      lats: Slab::Owned(nodes.iter().map(|n| n.1).collect()),
+      lons: Slab::Owned(nodes.iter().map(|n| n.2).collect()),
+      offsets: Slab::Owned(offsets),
+      links: Slab::Owned(links.into_iter().map(|l| l.1).collect())
+    };
+
+    RoadGraph::from_parts(base, self.road_classes, restrictions, profiles)
+  }
+}
+
+/// Restrictions with via ways followed by a path as pairs of the restriction and the index of its last passed node.
+type Progress = Vec<(usize, usize)>;
+
+/// Ids of the edge based search states. A state is a link of the query with the restrictions followed
+/// by the path to it. Road links have ids of their csr indexes, links added by the workspace and
+/// the virtual link to the start follow them. Links with followed restrictions get ids after them.
+struct EdgeStates {
+  road: usize,
+  /// first id of the added links of the node
+  added: HashMap<NodeId, usize>,
+  added_links: Vec<(NodeId, NodeId)>,
+  start: NodeId,
+  following: Vec<(usize, Progress)>,
+  following_ids: HashMap<(usize, Progress), usize>
+}
+
+impl EdgeStates {
+  fn new(query: &Query, start: NodeId) -> Self {
+    let mut added = HashMap::new();
+    let mut added_links = Vec::new();
+    let overlay_ids = (0..query.overlay.nodes.len()).map(|i| NodeId(query.graph.nodes_num() + i));
+    for id in query.overlay.extra_links.keys().copied().chain(overlay_ids) {
+      added.insert(id, added_links.len());
+      added_links.extend(query.links(id).skip(Self::road_num(query, id)).map(|l| (id, l.node)));
+    }
+
+    EdgeStates { road: query.graph.links_num(), added, added_links, start, following: Vec::new(), following_ids: HashMap::new() }
+  }
+
+  fn start_id(&self) -> usize {
+    self.road + self.added_links.len()
+  }
+
+  fn road_num(query: &Query, node: NodeId) -> usize {
+    match query.node(node) {
+      QueryNode::Road => query.graph.links(node).count(),
+      _ => 0
+    }
+  }
+
+  /// Number of states without followed restrictions.
+  fn plain_num(&self) -> usize {
+    self.start_id() + 1
+  }
+
+  /// Id of the `i`-th link of the node.
+  fn link_id(&self, query: &Query, node: NodeId, i: usize) -> usize {
+    let road_num = Self::road_num(query, node);
+    if i < road_num {
+      query.graph.base.offsets[node.0] as usize + i
+    } else {
+      self.road + self.added[&node] + i - road_num
+    }
+  }
+
+  /// Id of the state of the link with the followed restrictions.
+  fn id(&mut self, link_id: usize, progress: Progress) -> usize {
+    if progress.is_empty() {
+      return link_id;
+    }
+    let base = self.plain_num();
+    let following = &mut self.following;
+    *self.following_ids.entry((link_id, progress)).or_insert_with_key(|key| {
+      following.push(key.clone());
+      base + following.len() - 1
+    })
+  }
+
+  /// Link of the state and the restrictions followed to it.
+  fn state(&self, query: &Query, id: usize) -> ((NodeId, NodeId), &[(usize, usize)]) {
+    let (link_id, progress) = match id.checked_sub(self.plain_num()) {
+      Some(i) => (self.following[i].0, self.following[i].1.as_slice()),
+      None => (id, &[][..])
+    };
+    let link = if link_id < self.road {
+      let offsets = &query.graph.base.offsets;
+      let from = offsets.partition_point(|o| *o as usize <= link_id) - 1;
+      (NodeId(from), NodeId(query.graph.base.links[link_id].to as usize))
+    } else if link_id < self.start_id() {
+      self.added_links[link_id - self.road]
+    } else {
+      (self.start, self.start)
     };
+    (link, progress)
+  }
+}
 
-    self.reset_graph();
+/// Costs and previous nodes of a small search over the links of the snapped points.
+type Reach = HashMap<NodeId, (u32, Option<NodeId>)>;
 
-    path_result
+/// Cost of reaching `end` from the road node `from` by one of the small searches.
+fn reach_cost(reaches: &[(NodeId, Reach)], from: NodeId, end: NodeId) -> u32 {
+  reaches.iter().find(|(r, _)| *r == from).map_or(0, |(_, reach)| reach[&end].0)
+}
+
+/// Path to `to` found by a small search as nodes with their costs.
+fn reach_path(reach: &Reach, to: NodeId) -> Vec<(NodeId, u32)> {
+  let mut path = Vec::new();
+  let mut curr = Some(to);
+  while let Some(id) = curr {
+    let (cost, prev) = reach[&id];
+    path.push((id, cost));
+    curr = prev;
   }
+  path.reverse();
+  path
 }
 
 struct State {
   cost: u32,
-  node: NodeId
+  /// node or search state of it
+  node: usize
 }
 
 impl Ord for State {
@@ -293,44 +1347,283 @@ impl PartialEq for State {
 }
 impl PartialOrd for State {
   fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    self.cost.partial_cmp(&other.cost).map(|o| o.reverse())
+    Some(self.cmp(other))
   }
 }
-#[derive(Copy, Clone, Serialize, Debug, PartialEq, Eq)]
-pub struct NodeId(usize);
+/// Path to a node in the multi-criteria search.
+#[derive(Clone)]
+struct Label {
+  node: NodeId,
+  eta: u32,
+  /// walked distance in cm
+  walk: u32,
+  rides: u32,
+  /// previous label of the path
+  prev: Option<usize>,
+  /// dominated by a label found later, not expanded
+  dropped: bool
+}
 
-#[derive(Debug, Serialize)]
-pub struct Node {
-  pub nodes: Vec<NodeLink>,
-  pub eta: u32,
-  pub kind: NodeKind,
-  pub id: u64,
-  pub lon: f64,
-  pub lat: f64
+impl Label {
+  fn dominates(&self, other: &Label) -> bool {
+    self.eta <= other.eta && self.walk <= other.walk && self.rides <= other.rides
+  }
+}
+
+/// Distances and parents of the search states, a state is a node or a node with the number of rides.
+#[derive(Default)]
+struct SearchState {
+  etas: Vec<u32>,
+  /// previous state on the found path
+  prevs: Vec<Option<usize>>,
+  /// states reached by the last search, only they are reset before the next one
+  touched: Vec<usize>,
+  queue: BinaryHeap<State>
 }
-impl EarthPoint for Node {
-  fn lat(&self) -> f64 {
-    self.lat
+
+impl SearchState {
+  fn reset(&mut self, states_num: usize) {
+    for id in self.touched.drain(..) {
+      if id < self.etas.len() {
+        self.etas[id] = u32::MAX;
+        self.prevs[id] = None;
+      }
+    }
+    self.etas.resize(states_num, u32::MAX);
+    self.prevs.resize(states_num, None);
+    self.queue.clear();
   }
 
-  fn lon(&self) -> f64 {
-    self.lon
+  /// Adds states up to `states_num` after the reset.
+  fn grow(&mut self, states_num: usize) {
+    if self.etas.len() < states_num {
+      self.etas.resize(states_num, u32::MAX);
+      self.prevs.resize(states_num, None);
+    }
+  }
+
+  fn set(&mut self, id: usize, eta: u32, prev: Option<usize>) {
+    if self.etas[id] == u32::MAX {
+      self.touched.push(id);
+    }
+    self.etas[id] = eta;
+    self.prevs[id] = prev;
+  }
+
+  /// Updates the state reached from `from` if `eta` is better and queues it.
+  fn relax(&mut self, from: usize, id: usize, eta: u32) {
+    if eta < self.etas[id] {
+      self.set(id, eta, Some(from));
+      self.queue.push(State { cost: eta, node: id });
+    }
   }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Copy, Clone, Serialize, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(pub(crate) usize);
+
+/// Point of the road network a query point is snapped to.
+#[derive(Debug, Copy, Clone)]
+pub struct Snap {
+  pub node: NodeId,
+  pub lat: f64,
+  pub lon: f64,
+  /// distance from the query point in cm
+  pub distance: u32
+}
+
+/// Point snapped to a road by a query.
+struct OverlayNode {
+  lat: f64,
+  lon: f64,
+  links: Vec<NodeLink>,
+  /// road segment of the point and position on it
+  edge: (NodeId, NodeId, f64)
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
 pub enum NodeKind {
   Plain,
   Car {
     eta: i64,
+    /// free seats on the segment to the next point of the path
     free_seats: u8,
     path_id: u64
   }
 }
-#[derive(Debug, Serialize)]
+#[derive(Debug, Copy, Clone, Serialize)]
 pub struct NodeLink {
-  node: NodeId,
+  pub(crate) node: NodeId,
   /// distance in cm
-  len: u32,
-  kind: TransportKind
-}
\ No newline at end of file
+  pub(crate) len: u32,
+  pub(crate) access: Access,
+  /// `None` for links not being a part of the road network
+  pub(crate) class: Option<RoadClassId>
+}
+
+impl From<&RawLink> for NodeLink {
+  fn from(l: &RawLink) -> Self {
+    NodeLink {
+      node: NodeId(l.to as usize),
+      len: l.len,
+      access: Access { foot: l.access & ACCESS_FOOT != 0, car: l.access & ACCESS_CAR != 0 },
+      class: Some(l.class).filter(|c| *c != NO_CLASS).map(RoadClassId)
+    }
+  }
+}
+
+/// Road properties checked by routing profiles.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+pub struct RoadClass {
+  pub highway: String,
+  pub maxspeed: Option<Kmh>,
+  /// Sorted [`ROUTING_TAGS`](crate::profile::ROUTING_TAGS) of the road
+  pub tags: Vec<(String, String)>
+}
+
+impl RoadClass {
+  pub fn tag(&self, key: &str) -> Option<&str> {
+    self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+  }
+}
+
+#[derive(Copy, Clone, Serialize, Debug, PartialEq, Eq, Hash)]
+pub struct RoadClassId(pub(crate) u32);
+
+/// Turn restriction as graph nodes: the node before via nodes, via nodes and the node after them.
+#[derive(Debug, Serialize)]
+pub struct TurnRestriction {
+  pub kind: RestrictionKind,
+  pub nodes: Vec<NodeId>
+}
+
+#[derive(Copy, Clone, Serialize, Debug, PartialEq, Eq)]
+pub struct ProfileId(usize);
+
+/// Transport kinds allowed to go through the link.
+#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq)]
+pub struct Access {
+  pub foot: bool,
+  pub car: bool
+}
+
+impl Access {
+  pub fn allows(&self, kind: TransportKind) -> bool {
+    match kind {
+      TransportKind::Foot => self.foot,
+      TransportKind::Car => self.car
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    !self.foot && !self.car
+  }
+
+  fn bits(&self) -> u32 {
+    (ACCESS_FOOT * self.foot as u32) | (ACCESS_CAR * self.car as u32)
+  }
+}
+
+/// Road of the kind is accessible for it and all slower kinds.
+impl From<TransportKind> for Access {
+  fn from(kind: TransportKind) -> Self {
+    Access {
+      foot: true,
+      car: kind.is_car()
+    }
+  }
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn builder_packs_links_by_osm_id() {
+    let mut builder = GraphBuilder::new();
+    builder.add_node(30, 55.752, 37.600);
+    builder.add_node(10, 55.750, 37.600);
+    builder.add_node(20, 55.751, 37.600);
+    let class = Some(builder.road_class_id(RoadClass { highway: "residential".to_string(), maxspeed: None, tags: Vec::new() }));
+    builder.connect_two_way(30, 20, 100, Access::from(TransportKind::Car), class);
+    builder.connect_one_way(10, 20, 100, Access::from(TransportKind::Foot), None);
+    // unknown node
+    builder.connect_one_way(10, 40, 100, Access::from(TransportKind::Foot), None);
+    let graph = builder.build(default_profiles());
+
+    assert_eq!(graph.nodes_num(), 3);
+    assert_eq!(graph.links_num(), 3);
+    let ids = |id: u64| graph.node_id_by_osm_id(id).unwrap();
+    assert_eq!(ids(10), NodeId(0));
+    assert_eq!(graph.map_point(ids(30)).lat, 55.752);
+    let targets = |id: u64| graph.links(ids(id)).map(|l| graph.osm_id(l.node)).collect::<Vec<_>>();
+    assert_eq!(targets(10), vec![20]);
+    assert_eq!(targets(20), vec![30]);
+    assert_eq!(targets(30), vec![20]);
+    let link = graph.links(ids(20)).next().unwrap();
+    assert!(link.access.car && link.class == class);
+    assert!(graph.node_id_by_osm_id(40).is_none());
+  }
+
+  /// Two-way street 1-4 with one-way links 4 -> 5 -> 1 by the side of it.
+  fn street_with_loop() -> GraphBuilder {
+    let mut builder = GraphBuilder::new();
+    for id in 1..=4 {
+      builder.add_node(id, 55.75 + id as f64 * 0.001, 37.600);
+    }
+    builder.add_node(5, 55.7525, 37.602);
+    let class = Some(builder.road_class_id(RoadClass { highway: "residential".to_string(), maxspeed: None, tags: Vec::new() }));
+    let car = Access::from(TransportKind::Car);
+    for (a, b) in [(1, 2), (2, 3), (3, 4)].iter().copied() {
+      builder.connect_two_way(a, b, 20_000, car, class);
+    }
+    builder.connect_one_way(4, 5, 50_000, car, class);
+    builder.connect_one_way(5, 1, 50_000, car, class);
+    builder
+  }
+
+  #[test]
+  fn paths_to_many_match_single_searches() {
+    let graph = street_with_loop().build(default_profiles());
+    let profile = graph.default_profile(TransportKind::Car).unwrap();
+    let nodes: Vec<NodeId> = graph.road_nodes().collect();
+    let reversed = graph.reversed_links(profile);
+    let mut ws = graph.workspace();
+    let ids = |pr: &PathResult| pr.points.iter().map(|p| p.id).collect::<Vec<_>>();
+
+    for start in nodes.iter().copied() {
+      let from_start = graph.paths_to_many(&mut ws, start, &nodes, None, usize::MAX, profile);
+      let to_start = graph.paths_to_many(&mut ws, start, &nodes, Some(&reversed), usize::MAX, profile);
+      for (i, other) in nodes.iter().copied().enumerate() {
+        for (found, (from, to)) in [(&from_start[i], (start, other)), (&to_start[i], (other, start))].iter() {
+          let expected = graph.shortest_path(&mut ws, *from, *to, 0, profile);
+          let (found, allowed) = found.as_ref().unwrap();
+          assert!(allowed);
+          assert_eq!(ids(found), ids(&expected));
+          assert_eq!(found.total_time, expected.total_time);
+        }
+      }
+    }
+
+    // only 1 and 2 are passed before the search stops
+    let start = graph.node_id_by_osm_id(1).unwrap();
+    let found = graph.paths_to_many(&mut ws, start, &nodes, None, 2, profile);
+    assert_eq!(found.iter().filter(|p| p.is_some()).count(), 2);
+  }
+
+  #[test]
+  fn paths_to_many_mark_restricted_paths() {
+    let mut builder = street_with_loop();
+    builder.add_turn_restriction(RestrictionKind::No, vec![3, 2, 1]);
+    let graph = builder.build(default_profiles());
+    let profile = graph.default_profile(TransportKind::Car).unwrap();
+    let ids = |osm_ids: &[u64]| osm_ids.iter().map(|id| graph.node_id_by_osm_id(*id).unwrap()).collect::<Vec<_>>();
+    let points = |pr: &PathResult| pr.points.iter().map(|p| p.id).collect::<Vec<_>>();
+    let mut ws = graph.workspace();
+
+    let found = graph.paths_to_many(&mut ws, ids(&[3])[0], &ids(&[2, 1]), None, usize::MAX, profile);
+    let allowed: Vec<(Vec<u64>, bool)> = found.iter().map(|f| f.as_ref().map(|(pr, ok)| (points(pr), *ok)).unwrap()).collect();
+    assert_eq!(allowed, vec![(vec![3, 2], true), (vec![3, 2, 1], false)]);
+    let path = graph.shortest_path(&mut ws, ids(&[3])[0], ids(&[1])[0], 0, profile);
+    assert_eq!(points(&path), vec![3, 4, 5, 1]);
+  }
+}
@@ -0,0 +1,253 @@
+//! Landmark (ALT) lower bounds of search costs.
+//!
+//! Search costs from and to a few landmarks are known for every road node, by the triangle
+//! inequality `cost(v, t) >= cost(L, t) - cost(L, v)` and `cost(v, t) >= cost(v, L) - cost(t, L)`.
+//! Landmarks far from each other on the border of the map give the best bounds.
+//! The costs are of the road links only, so bounds don't hold for searches using car paths.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use crate::graph::{RoadGraph, NodeId, ProfileId};
+use crate::csr::Slab;
+
+/// Cost of the nodes unreachable from or not reaching the landmark
+const UNREACHABLE: u32 = u32::MAX;
+
+pub struct Landmarks {
+  /// name of the profile
+  pub profile: String,
+  /// checksum of the profile costs the landmarks are built with, see [`RoadGraph::costs_checksum`]
+  pub costs_checksum: u32,
+  pub nodes: Vec<NodeId>,
+  /// costs from the landmarks to the nodes, landmark by landmark
+  pub from: Slab<u32>,
+  /// costs from the nodes to the landmarks, landmark by landmark
+  pub to: Slab<u32>
+}
+
+/// Bounds of the costs between the landmarks and the end of a search, one pair per landmark.
+/// Costs to the end are not greater and costs from it are not less than the real ones.
+#[derive(Debug, Copy, Clone)]
+pub struct TargetBounds {
+  pub from_landmark: u32,
+  pub to_landmark: u32
+}
+
+impl Landmarks {
+  /// Picks `count` landmarks, every next one is the farthest from the picked ones.
+  pub fn build(graph: &RoadGraph, profile: ProfileId, count: usize) -> Self {
+    let n = graph.nodes_num();
+    let reversed = graph.reversed_links(profile);
+    let mut nodes = Vec::new();
+    let mut costs_from = Vec::with_capacity(n * count);
+    let mut costs_to = Vec::with_capacity(n * count);
+
+    if let Some(seed) = graph.road_nodes().find(|id| graph.is_accessible(*id, profile)) {
+      // the first landmark is the farthest from an arbitrary node
+      let mut closest = costs(graph, profile, &reversed, seed, false);
+      while nodes.len() < count {
+        let next = (0..n)
+          .filter(|v| closest[*v] != UNREACHABLE && !nodes.contains(&NodeId(*v)))
+          .max_by_key(|v| closest[*v]);
+        let landmark = match next {
+          Some(v) if closest[v] > 0 || nodes.is_empty() => NodeId(v),
+          _ => break
+        };
+        let from = costs(graph, profile, &reversed, landmark, false);
+        let to = costs(graph, profile, &reversed, landmark, true);
+        if nodes.is_empty() {
+          closest = from.clone();
+        } else {
+          closest.iter_mut().zip(from.iter()).for_each(|(c, f)| *c = (*c).min(*f));
+        }
+        nodes.push(landmark);
+        costs_from.extend(from);
+        costs_to.extend(to);
+      }
+    }
+
+    Landmarks {
+      profile: graph.profile(profile).name.clone(),
+      costs_checksum: graph.costs_checksum(profile),
+      nodes,
+      from: Slab::Owned(costs_from),
+      to: Slab::Owned(costs_to)
+    }
+  }
+
+  fn costs_from(&self, i: usize) -> &[u32] {
+    let n = self.from.len() / self.nodes.len();
+    &self.from[i * n..(i + 1) * n]
+  }
+
+  fn costs_to(&self, i: usize) -> &[u32] {
+    let n = self.to.len() / self.nodes.len();
+    &self.to[i * n..(i + 1) * n]
+  }
+
+  /// Bounds of the costs between the landmarks and the road node.
+  pub fn node_bounds(&self, id: NodeId) -> Vec<TargetBounds> {
+    (0..self.nodes.len())
+      .map(|i| TargetBounds { from_landmark: self.costs_from(i)[id.0], to_landmark: self.costs_to(i)[id.0] })
+      .collect()
+  }
+
+  /// Bounds of a point reached from one of the road nodes `entries` with the costs to reach them
+  /// and reaching the road nodes `exits` with the costs to reach them.
+  pub fn point_bounds(&self, entries: &[NodeId], exits: &[(NodeId, u32)]) -> Vec<TargetBounds> {
+    (0..self.nodes.len())
+      .map(|i| TargetBounds {
+        from_landmark: entries.iter().map(|id| self.costs_from(i)[id.0]).min().unwrap_or(UNREACHABLE),
+        to_landmark: exits.iter()
+          .map(|(id, cost)| self.costs_to(i)[id.0].saturating_add(*cost))
+          .min()
+          .unwrap_or(UNREACHABLE)
+      })
+      .collect()
+  }
+
+  /// Lower bound of the cost from the road node to the end with the `bounds`.
+  pub fn lower_bound(&self, id: NodeId, bounds: &[TargetBounds]) -> u32 {
+    bounds.iter().enumerate()
+      .map(|(i, b)| {
+        let (from, to) = (self.costs_from(i)[id.0], self.costs_to(i)[id.0]);
+        let ahead = if from != UNREACHABLE && b.from_landmark != UNREACHABLE { b.from_landmark.saturating_sub(from) } else { 0 };
+        let behind = if to != UNREACHABLE && b.to_landmark != UNREACHABLE { to.saturating_sub(b.to_landmark) } else { 0 };
+        ahead.max(behind)
+      })
+      .max()
+      .unwrap_or(0)
+  }
+}
+
+/// Dijkstra search costs of the road nodes from `landmark` or to it if `to_landmark` is set.
+fn costs(graph: &RoadGraph, profile: ProfileId, reversed: &[Vec<(NodeId, u32)>], landmark: NodeId, to_landmark: bool) -> Vec<u32> {
+  let mut costs = vec![UNREACHABLE; graph.nodes_num()];
+  costs[landmark.0] = 0;
+  let mut queue = BinaryHeap::new();
+  queue.push(Reverse((0, landmark.0)));
+  while let Some(Reverse((cost, v))) = queue.pop() {
+    if cost > costs[v] {
+      continue;
+    }
+    if to_landmark {
+      for (next, link_cost) in reversed[v].iter() {
+        relax(&mut costs, &mut queue, *next, cost + link_cost);
+      }
+    } else {
+      for link in graph.links(NodeId(v)) {
+        if let Some(link_cost) = graph.link_cost(&link, profile) {
+          relax(&mut costs, &mut queue, link.node, cost + link_cost);
+        }
+      }
+    }
+  }
+
+  costs
+}
+
+fn relax(costs: &mut [u32], queue: &mut BinaryHeap<Reverse<(u32, usize)>>, node: NodeId, cost: u32) {
+  if cost < costs[node.0] {
+    costs[node.0] = cost;
+    queue.push(Reverse((cost, node.0)));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{distance, MapPoint, TransportKind};
+  use crate::graph::{GraphBuilder, RoadClass, Access};
+  use crate::profile::default_profiles;
+
+  /// Random 12x12 grid with fast and slow roads, some of them are one-way.
+  fn grid() -> RoadGraph {
+    let mut builder = GraphBuilder::new();
+    let mut class = |highway: &str| Some(builder.road_class_id(RoadClass { highway: highway.to_string(), maxspeed: None, tags: Vec::new() }));
+    let classes = [class("residential"), class("motorway"), class("trunk"), class("footway")];
+    // simple lcg, the test should be deterministic
+    let mut seed = 11u64;
+    let mut random = move |n: u64| {
+      seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+      (seed >> 33) % n
+    };
+    let point = |x: u64, y: u64| MapPoint { id: y * 100 + x + 1, lat: 55.70 + y as f64 * 0.002, lon: 37.60 + x as f64 * 0.003, path_id: None };
+    for y in 0..12 {
+      for x in 0..12 {
+        let p = point(x, y);
+        builder.add_node(p.id, p.lat, p.lon);
+      }
+    }
+    for y in 0..12 {
+      for x in 0..12 {
+        for (nx, ny) in [(x + 1, y), (x, y + 1)].iter().copied().filter(|(nx, ny)| *nx < 12 && *ny < 12) {
+          let (a, b) = (point(x, y), point(nx, ny));
+          let len = distance(&a, &b) + random(3_000) as u32;
+          let class = classes[random(4) as usize];
+          let access = Access { foot: true, car: true };
+          match random(5) {
+            0 => builder.connect_one_way(a.id, b.id, len, access, class),
+            1 => continue,
+            _ => builder.connect_two_way(a.id, b.id, len, access, class)
+          }
+        }
+      }
+    }
+
+    builder.build(default_profiles())
+  }
+
+  #[test]
+  fn bounds_do_not_exceed_costs() {
+    let graph = grid();
+    let car = graph.default_profile(TransportKind::Car).unwrap();
+    let landmarks = Landmarks::build(&graph, car, 4);
+    assert_eq!(landmarks.nodes.len(), 4);
+    let reversed = graph.reversed_links(car);
+
+    let targets = graph.road_nodes().step_by(7).chain(landmarks.nodes.iter().copied());
+    for t in targets {
+      let to_target = costs(&graph, car, &reversed, t, true);
+      let bounds = landmarks.node_bounds(t);
+      for v in graph.road_nodes().filter(|v| to_target[v.0] != UNREACHABLE) {
+        let bound = landmarks.lower_bound(v, &bounds);
+        assert!(bound <= to_target[v.0]);
+        // the bound to a landmark is exact
+        if landmarks.nodes.contains(&t) {
+          assert_eq!(bound, to_target[v.0]);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn searches_are_optimal() {
+    let mut graph = grid();
+    let profiles: Vec<ProfileId> = [TransportKind::Car, TransportKind::Foot].iter().map(|k| graph.default_profile(*k).unwrap()).collect();
+    let pairs: Vec<(NodeId, NodeId)> = graph.road_nodes()
+      .flat_map(|s| graph.road_nodes().step_by(3).map(move |t| (s, t)))
+      .collect();
+
+    for with_landmarks in [false, true].iter().copied() {
+      let mut reachable = 0;
+      for profile in profiles.iter().copied() {
+        if with_landmarks {
+          graph.prepare_landmarks(profile, 4);
+          assert!(graph.landmarks(profile).is_some());
+        }
+        let mut ws = graph.workspace();
+        for (s, t) in pairs.iter().copied() {
+          // plain Dijkstra search
+          let cost = costs(&graph, profile, &[], s, false)[t.0];
+          let pr = graph.shortest_path(&mut ws, s, t, 0, profile);
+          if cost == UNREACHABLE {
+            assert!(pr.points.is_empty());
+          } else {
+            assert_eq!(pr.total_time, cost);
+            reachable += 1;
+          }
+        }
+      }
+      assert!(reachable > pairs.len() / 2);
+    }
+  }
+}
@@ -1,41 +1,46 @@
 use pyo3::prelude::*;
-use std::collections::{HashMap, HashSet};
-use crate::osm_map::{OsmNode, OsmWay};
-use crate::graph::{RoadGraph, Node, NodeKind, ROAD_TO_CAR};
+use crate::osm_map::{OsmNode, MapData};
+use crate::graph::{RoadGraph, GraphBuilder, Workspace, Snap, Access, RoadClass, ProfileId};
+use crate::error::{MapLoadError, MapLoadException, ProfileLoadException, SnapshotError, SnapshotException, ReservationException, CarPathError, CarPathException};
+use crate::reservation::Reservations;
+use crate::car_paths::{CarPaths, Cars, Boarding, Transfers};
+use crate::detour::{Detour, DetourMatch, DetourRequest, Route};
+use crate::area::Area;
 use pyo3::types::PyDict;
+use pyo3::exceptions::ValueError;
 use std::ops::Deref;
+use std::sync::{Mutex, RwLock};
 use pyo3::{PyGCProtocol, PyVisit, PyTraverseError};
-use serde::Serialize;
-use std::rc::Rc;
+use serde::{Serialize, Deserialize};
 
 #[macro_use] extern crate log;
-#[macro_use] extern crate lazy_static;
 
 pub mod osm_map;
+pub mod pbf;
 pub mod graph;
+pub mod csr;
 pub mod utils;
-
-lazy_static! {
-    static ref PEDESTRIAN_HIGHWAY: HashSet<&'static str> = {
-        let mut set = HashSet::new();
-        set.insert("footway");
-        set.insert("construction");
-        set.insert("bridleway");
-        set.insert("steps");
-        set.insert("corridor");
-        set.insert("path");
-        set.insert("cycleway");
-        set
-    };
-}
-
-#[derive(Debug, Copy, Clone, Serialize)]
+pub mod error;
+pub mod speed;
+pub mod profile;
+pub mod area;
+pub mod snapshot;
+pub mod spatial;
+pub mod reservation;
+pub mod car_paths;
+pub mod detour;
+pub mod ch;
+pub mod landmarks;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TransportKind {
   Foot,
   Car
 }
 
 impl TransportKind {
+  /// Speed used for links which are not roads
   pub fn get_speed(&self) -> Kmh {
     match self {
       TransportKind::Foot => Kmh(5),
@@ -44,28 +49,10 @@ impl TransportKind {
   }
 
   pub fn is_foot(&self) -> bool {
-    if let TransportKind::Foot = self {
-      true
-    } else {
-      false
-    }
+    matches!(self, TransportKind::Foot)
   }
   pub fn is_car(&self) -> bool {
-    if let TransportKind::Car = self {
-      true
-    } else {
-      false
-    }
-  }
-}
-
-impl From<&str> for TransportKind {
-  fn from(s: &str) -> Self {
-    return if PEDESTRIAN_HIGHWAY.contains(s) {
-      TransportKind::Foot
-    } else {
-      TransportKind::Car
-    }
+    matches!(self, TransportKind::Car)
   }
 }
 
@@ -94,6 +81,7 @@ pub fn distance_t<T: EarthPoint>(n1: &T, n2: &T, speed: Kmh) -> u32 {
 }
 
 /// Km/h
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Kmh(u32);
 impl From<u32> for Kmh {
   fn from(v: u32) -> Self {
@@ -106,10 +94,18 @@ impl Kmh{
   }
 }
 
+pub struct PlainMapRideRequest<'a> {
+  pub start: &'a MapPoint,
+  pub end: &'a MapPoint,
+  pub seats: u8
+}
+
 pub struct PlainMapCarPath<'a> {
   pub id: u64,
   pub start_at: i64,
-  pub path: Vec<&'a MapPoint>
+  pub path: Vec<&'a MapPoint>,
+  /// free seats on the segments between the points
+  pub seats: &'a [u8]
 }
 
 #[pyclass]
@@ -124,7 +120,17 @@ pub struct PathResultObject {
   #[pyo3(get)]
   pub eta_list: Vec<u32>,
   #[pyo3(get)]
-  pub distance_list: Vec<u32>
+  pub distance_list: Vec<u32>,
+  #[pyo3(get)]
+  pub snapped_points: Vec<Py<MapPoint>>,
+  #[pyo3(get)]
+  pub snap_distances: Vec<u32>,
+  #[pyo3(get)]
+  pub transfers: u32,
+  #[pyo3(get)]
+  pub walk_distance: u32,
+  #[pyo3(get)]
+  pub legs: Vec<Py<PathLegObject>>
 }
 #[pymethods]
 impl PathResultObject {
@@ -139,6 +145,17 @@ impl PathResultObject {
     }).collect::<PyResult<Vec<&PyDict>>>()?)?;
     d.set_item("eta_list", &self.eta_list)?;
     d.set_item("distance_list", &self.distance_list)?;
+    d.set_item("snapped_points", &self.snapped_points.iter().map(|p| {
+      let v = (p.as_ref(py) as &PyCell<MapPoint>).borrow();
+      v.to_json(py)
+    }).collect::<PyResult<Vec<&PyDict>>>()?)?;
+    d.set_item("snap_distances", &self.snap_distances)?;
+    d.set_item("transfers", self.transfers)?;
+    d.set_item("walk_distance", self.walk_distance)?;
+    d.set_item("legs", &self.legs.iter().map(|l| {
+      let v = (l.as_ref(py) as &PyCell<PathLegObject>).borrow();
+      v.to_json(py)
+    }).collect::<PyResult<Vec<&PyDict>>>()?)?;
 
     Ok(d)
   }
@@ -150,7 +167,12 @@ impl PathResultObject {
       total_distance: pr.total_distance,
       eta_list: pr.eta_list,
       distance_list: pr.distance_list,
-      points: pr.points.into_iter().map(|p| Py::new(py, p).unwrap()).collect()
+      points: pr.points.into_iter().map(|p| Py::new(py, p).unwrap()).collect(),
+      snapped_points: pr.snapped_points.into_iter().map(|p| Py::new(py, p).unwrap()).collect(),
+      snap_distances: pr.snap_distances,
+      transfers: pr.transfers,
+      walk_distance: pr.walk_distance,
+      legs: pr.legs.into_iter().map(|l| Py::new(py, PathLegObject::from_path_leg(py, l)).unwrap()).collect()
     }
   }
 }
@@ -161,7 +183,106 @@ pub struct PathResult {
   pub total_distance: u32,
   pub points: Vec<MapPoint>,
   pub eta_list: Vec<u32>,
-  pub distance_list: Vec<u32>
+  pub distance_list: Vec<u32>,
+  /// points of the road network the query points are snapped to
+  pub snapped_points: Vec<MapPoint>,
+  /// distances from the query points to the snapped points in meters
+  pub snap_distances: Vec<u32>,
+  /// changes from one car to another
+  pub transfers: u32,
+  /// distance walked outside of cars in meters
+  pub walk_distance: u32,
+  /// parts of the path moved by the same mode
+  pub legs: Vec<PathLeg>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LegMode {
+  Walk,
+  Drive,
+  /// in a car of a car path
+  Ride
+}
+
+impl LegMode {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      LegMode::Walk => "walk",
+      LegMode::Drive => "drive",
+      LegMode::Ride => "ride"
+    }
+  }
+}
+
+/// Part of a path moved by the same mode, times are in ms since the path start, distance is in meters.
+#[derive(Debug, Serialize)]
+pub struct PathLeg {
+  pub mode: LegMode,
+  /// car path of the ride
+  pub path_id: Option<u64>,
+  /// boarding point for rides
+  pub from: MapPoint,
+  /// alighting point for rides
+  pub to: MapPoint,
+  /// rides start when the rider comes to the boarding point
+  pub start_time: u32,
+  pub end_time: u32,
+  pub distance: u32,
+  /// time waiting for the car
+  pub wait_time: u32
+}
+
+#[pyclass]
+#[derive(Debug)]
+pub struct PathLegObject {
+  #[pyo3(get)]
+  pub mode: String,
+  #[pyo3(get)]
+  pub path_id: Option<u64>,
+  #[pyo3(get)]
+  pub from: Py<MapPoint>,
+  #[pyo3(get)]
+  pub to: Py<MapPoint>,
+  #[pyo3(get)]
+  pub start_time: u32,
+  #[pyo3(get)]
+  pub end_time: u32,
+  #[pyo3(get)]
+  pub distance: u32,
+  #[pyo3(get)]
+  pub wait_time: u32
+}
+#[pymethods]
+impl PathLegObject {
+  pub fn to_json<'a>(&self, py: Python<'a>) -> PyResult<&'a PyDict> {
+    let d = PyDict::new(py);
+
+    d.set_item("mode", &self.mode)?;
+    d.set_item("path_id", self.path_id)?;
+    d.set_item("from", (self.from.as_ref(py) as &PyCell<MapPoint>).borrow().to_json(py)?)?;
+    d.set_item("to", (self.to.as_ref(py) as &PyCell<MapPoint>).borrow().to_json(py)?)?;
+    d.set_item("start_time", self.start_time)?;
+    d.set_item("end_time", self.end_time)?;
+    d.set_item("distance", self.distance)?;
+    d.set_item("wait_time", self.wait_time)?;
+
+    Ok(d)
+  }
+}
+impl PathLegObject {
+  pub fn from_path_leg(py: Python, leg: PathLeg) -> Self {
+    Self {
+      mode: leg.mode.as_str().to_string(),
+      path_id: leg.path_id,
+      from: Py::new(py, leg.from).unwrap(),
+      to: Py::new(py, leg.to).unwrap(),
+      start_time: leg.start_time,
+      end_time: leg.end_time,
+      distance: leg.distance,
+      wait_time: leg.wait_time
+    }
+  }
 }
 
 #[pyclass]
@@ -172,23 +293,44 @@ pub struct MapCarPath {
   #[pyo3(get)]
   start_at: i64,
   #[pyo3(get)]
-  path: Vec<Py<MapPoint>>
+  path: Vec<Py<MapPoint>>,
+  /// free seats on the segments between the points
+  #[pyo3(get)]
+  seats: Vec<u8>
 }
 
 #[pymethods]
 impl MapCarPath {
+  /// `seats` are free seats on each segment of the path, unlimited if not set.
   #[new]
-  pub fn new(id: u64, start_at: i64, path: Vec<Py<MapPoint>>) -> Self {
-    Self {
+  #[args(seats = "None")]
+  pub fn new(id: u64, start_at: i64, path: Vec<Py<MapPoint>>, seats: Option<Vec<u8>>) -> PyResult<Self> {
+    let segments = path.len().saturating_sub(1);
+    let seats = seats.unwrap_or_else(|| vec![u8::MAX; segments]);
+    if seats.len() != segments {
+      return Err(ValueError::py_err(format!("expected seats for {} segments, got {}", segments, seats.len())));
+    }
+
+    Ok(Self {
       id,
       start_at,
-      path
-    }
+      path,
+      seats
+    })
   }
 }
 
 impl MapCarPath {
-  pub fn points<'a>(&'a self, py: Python<'a>) -> PyResult<Vec<PyRef<MapPoint>>> {
+  pub fn as_plain<'a>(&'a self, points: &'a [PyRef<MapPoint>]) -> PlainMapCarPath<'a> {
+    PlainMapCarPath {
+      id: self.id,
+      start_at: self.start_at,
+      path: points.iter().map(|p| p.deref()).collect(),
+      seats: &self.seats
+    }
+  }
+
+  pub fn points<'a>(&'a self, py: Python<'a>) -> PyResult<Vec<PyRef<'a, MapPoint>>> {
     let mut res: Vec<PyRef<MapPoint>> = Vec::new();
     for o in self.path.iter() {
       res.push((o.as_ref(py) as &PyCell<MapPoint>).borrow());
@@ -198,6 +340,87 @@ impl MapCarPath {
   }
 }
 
+/// Rider asking to be taken from `start` to `end`.
+#[pyclass]
+#[derive(Debug)]
+pub struct MapRideRequest {
+  #[pyo3(get)]
+  start: Py<MapPoint>,
+  #[pyo3(get)]
+  end: Py<MapPoint>,
+  #[pyo3(get)]
+  seats: u8
+}
+
+#[pymethods]
+impl MapRideRequest {
+  #[new]
+  #[args(seats = "1")]
+  pub fn new(start: Py<MapPoint>, end: Py<MapPoint>, seats: u8) -> Self {
+    Self { start, end, seats }
+  }
+}
+
+impl MapRideRequest {
+  pub fn points<'a>(&'a self, py: Python<'a>) -> (PyRef<'a, MapPoint>, PyRef<'a, MapPoint>) {
+    let borrow = |p: &'a Py<MapPoint>| (p.as_ref(py) as &PyCell<MapPoint>).borrow();
+    (borrow(&self.start), borrow(&self.end))
+  }
+}
+
+/// Detour of a driver to pick up a rider, times are in seconds since unix epoch, `added_time` is in ms.
+#[pyclass]
+#[derive(Debug)]
+pub struct DetourMatchObject {
+  #[pyo3(get)]
+  pub path_id: u64,
+  #[pyo3(get)]
+  pub pickup: Py<MapPoint>,
+  #[pyo3(get)]
+  pub dropoff: Py<MapPoint>,
+  #[pyo3(get)]
+  pub pickup_at: i64,
+  #[pyo3(get)]
+  pub dropoff_at: i64,
+  #[pyo3(get)]
+  pub added_time: u32,
+  /// new path of the driver
+  #[pyo3(get)]
+  pub path: Vec<Py<MapPoint>>
+}
+#[pymethods]
+impl DetourMatchObject {
+  pub fn to_json<'a>(&self, py: Python<'a>) -> PyResult<&'a PyDict> {
+    let d = PyDict::new(py);
+
+    d.set_item("path_id", self.path_id)?;
+    d.set_item("pickup", (self.pickup.as_ref(py) as &PyCell<MapPoint>).borrow().to_json(py)?)?;
+    d.set_item("dropoff", (self.dropoff.as_ref(py) as &PyCell<MapPoint>).borrow().to_json(py)?)?;
+    d.set_item("pickup_at", self.pickup_at)?;
+    d.set_item("dropoff_at", self.dropoff_at)?;
+    d.set_item("added_time", self.added_time)?;
+    d.set_item("path", &self.path.iter().map(|p| {
+      let v = (p.as_ref(py) as &PyCell<MapPoint>).borrow();
+      v.to_json(py)
+    }).collect::<PyResult<Vec<&PyDict>>>()?)?;
+
+    Ok(d)
+  }
+}
+impl DetourMatchObject {
+  pub fn from_detour_match(py: Python, m: DetourMatch) -> Self {
+    Self {
+      path_id: m.path_id,
+      pickup: Py::new(py, m.pickup).unwrap(),
+      dropoff: Py::new(py, m.dropoff).unwrap(),
+      pickup_at: m.pickup_at / 1000,
+      dropoff_at: m.dropoff_at / 1000,
+      added_time: m.added_time,
+      path: m.path.into_iter().map(|p| Py::new(py, p).unwrap()).collect()
+    }
+  }
+}
+
 #[pyproto]
 impl PyGCProtocol for MapCarPath {
   fn __traverse__(&self, visit: PyVisit) -> Result<(), PyTraverseError> {
@@ -282,132 +505,423 @@ impl From<&OsmNode> for MapPoint {
     }
   }
 }
-impl From<&Node> for MapPoint {
-  fn from(n: &Node) -> Self {
-    MapPoint {
-      id: n.id,
-      lat: n.lat,
-      lon: n.lon,
-      path_id: if let NodeKind::Car {path_id, ..} = n.kind { Some(path_id) } else { None }
-    }
-  }
-}
 
 #[pyclass]
 #[derive(Default)]
 pub struct MapService {
-  pub nodes: HashMap<u64, OsmNode>,
-  pub ways: HashMap<u64, OsmWay>,
   pub graph: RoadGraph,
-  pub node_ways: HashMap<u64, Vec<OsmWay>>
+  /// workspaces of finished queries, reused to keep their search buffers
+  workspaces: Mutex<Vec<Workspace>>,
+  /// car paths of drivers, locked before `reservations`
+  pub car_paths: RwLock<CarPaths>,
+  /// seats taken on car paths
+  pub reservations: RwLock<Reservations>
 }
 
 #[pymethods]
 impl MapService {
+  /// Routing profiles are loaded from `profiles_path` json, built-in ones are used if it's not set.
   #[new]
-  pub fn new() -> Self {
-    Self::default()
-  }
+  #[args(profiles_path = "None")]
+  pub fn new(profiles_path: Option<String>) -> PyResult<Self> {
+    let mut ms = Self::default();
+    if let Some(path) = profiles_path {
+      ms.graph.set_profiles(crate::profile::load_profiles(&path)?);
+    }
 
-  pub fn load(&mut self, path: String) {
-    let (nodes, ways) = crate::osm_map::load(path);
-    self.nodes = nodes;
-    self.ways = ways;
+    Ok(ms)
+  }
 
-    for w in self.ways.values() {
-      for n in w.nodes.iter() {
-        self.node_ways.entry(n.id).or_insert(Vec::new()).push(w.clone());
-      }
-    }
+  #[getter]
+  pub fn profiles(&self) -> Vec<String> {
+    self.graph.profiles().iter().map(|p| p.name.clone()).collect()
+  }
 
-    let cnt = self.nodes.values().filter(|v| Rc::strong_count(&v.0) == 2).count();
-    warn!("useless nodes: {}/{}", cnt, self.nodes.len());
-    self.build_graph();
-  }
-
-  fn build_graph(&mut self) {
-    for node in self.nodes.values() {
-      self.graph.add_node(Node {
-        nodes: Vec::new(),
-        eta: u32::MAX,
-        id: node.id,
-        kind: NodeKind::Plain,
-        lat: node.lat,
-        lon: node.lon
-      });
+  /// Loads the map, only the part inside of `bbox` as `(min_lon, min_lat, max_lon, max_lat)`
+  /// or `polygon` from `.poly` or GeoJSON file is loaded if one of them is set.
+  /// Raises `MapLoadError` with `offset`, `element_id`, `way_id` and `node_id` attributes on failure.
+  #[args(bbox = "None", polygon = "None")]
+  pub fn load(&mut self, path: String, bbox: Option<(f64, f64, f64, f64)>, polygon: Option<String>) -> PyResult<()> {
+    let area = match (bbox, polygon) {
+      (Some(_), Some(_)) => return Err(ValueError::py_err("bbox and polygon can't be used together")),
+      (Some((min_lon, min_lat, max_lon, max_lat)), None) => Some(Area::bbox(min_lon, min_lat, max_lon, max_lat)?),
+      (None, Some(path)) => Some(Area::load(&path)?),
+      (None, None) => None
     };
 
-    for way in self.ways.values() {
-      let mut prev_node_id = *self.graph.node_map.get(&way.nodes[0].id).unwrap();
+    Ok(self.load_rust(path, area)?)
+  }
 
-      for node in &way.nodes[1..] {
-        let curr_node_id = *self.graph.node_map.get(&node.id).unwrap();
-        self.graph.connect_two_way(
-          prev_node_id,
-          curr_node_id,
-          distance(self.graph.node(prev_node_id), self.graph.node(curr_node_id)),
-          way.road_kind
-        );
+  /// Saves the loaded map to be restored by `load_snapshot` instead of parsing osm file again.
+  pub fn save_snapshot(&self, path: String) -> PyResult<()> {
+    Ok(self.save_snapshot_rust(&path)?)
+  }
 
-        prev_node_id = curr_node_id;
-      }
-    }
+  /// Replaces the map by the one from snapshot, routing profiles are kept.
+  pub fn load_snapshot(&mut self, path: String) -> PyResult<()> {
+    Ok(self.load_snapshot_rust(&path)?)
+  }
 
-    self.graph.nodes.iter_mut().for_each(|n| n.nodes.shrink_to_fit());
+  /// Prepares contraction hierarchies of the profiles, of all profiles if `profiles` aren't set.
+  /// Paths without car paths are searched on them much faster unless they break turn restrictions,
+  /// they are saved with snapshots and dropped when the costs of the profile change.
+  #[args(profiles = "None")]
+  pub fn prepare_hierarchies(&mut self, profiles: Option<Vec<String>>) -> PyResult<()> {
+    let ids: Vec<ProfileId> = profiles.unwrap_or_else(|| self.profiles()).into_iter()
+      .map(|name| self.find_profile(Some(name), TransportKind::Car))
+      .collect::<PyResult<_>>()?;
+    self.prepare_hierarchies_rust(&ids);
+
+    Ok(())
   }
 
-  pub fn build_path(&mut self, py: Python, points: Vec<PyRef<MapPoint>>) -> PathResultObject {
-    let pr = self.build_path_rust(points.iter().map(|p| p.deref()).collect());
+  /// Picks `count` landmarks for the profiles, for all profiles if `profiles` aren't set, and computes
+  /// costs to and from them. Paths without car paths are searched faster with them,
+  /// they are saved with snapshots and dropped when the costs of the profile change.
+  #[args(profiles = "None", count = "8")]
+  pub fn prepare_landmarks(&mut self, profiles: Option<Vec<String>>, count: usize) -> PyResult<()> {
+    let ids: Vec<ProfileId> = profiles.unwrap_or_else(|| self.profiles()).into_iter()
+      .map(|name| self.find_profile(Some(name), TransportKind::Car))
+      .collect::<PyResult<_>>()?;
+    self.prepare_landmarks_rust(&ids, count);
 
-    PathResultObject::from_path_result(py, pr)
+    Ok(())
   }
 
-  pub fn build_path_using_cars(&mut self, py: Python, start_at: i64, points: Vec<PyRef<MapPoint>>, car_paths: Vec<PyRef<MapCarPath>>) -> PyResult<PathResultObject> {
+  /// Up to `k` road nodes usable by `mode` ("foot" or "car") nearest to the point,
+  /// as pairs of the node and distance to it in meters.
+  pub fn nearest_nodes(&self, lat: f64, lon: f64, k: usize, mode: String) -> PyResult<Vec<(MapPoint, u32)>> {
+    let kind = match mode.as_str() {
+      "foot" => TransportKind::Foot,
+      "car" => TransportKind::Car,
+      _ => return Err(ValueError::py_err(format!("unknown mode {}", mode)))
+    };
+
+    Ok(self.graph.nearest_nodes(lat, lon, k, kind, |_| true).into_iter()
+      .map(|(id, dist)| (self.graph.map_point(id), (dist as f32 / 100.0).round() as u32))
+      .collect())
+  }
+
+  /// Builds path through `points` using the `profile`, the first car profile by default.
+  #[args(profile = "None")]
+  pub fn build_path(&self, py: Python, points: Vec<PyRef<MapPoint>>, profile: Option<String>) -> PyResult<PathResultObject> {
+    let profile = self.find_profile(profile, TransportKind::Car)?;
     let points: Vec<&MapPoint> = points.iter().map(|p| p.deref()).collect();
-    let mut plain_car_paths = Vec::new();
+    // the graph is not changed by queries, other threads could use it meanwhile
+    let pr = py.allow_threads(|| self.build_path_rust(points, profile));
 
-    let tmp: Vec<Vec<PyRef<MapPoint>>> = car_paths.iter()
-        .map(|p| p.points(py))
-        .collect::<PyResult<Vec<_>>>()?;
+    Ok(PathResultObject::from_path_result(py, pr))
+  }
 
-    for a in car_paths.iter().zip(tmp.iter()) {
-      let pmcp = PlainMapCarPath {
-        id: a.0.id,
-        start_at: a.0.start_at,
-        path: a.1.iter().map(|v| v.deref()).collect()
-      };
-      plain_car_paths.push(pmcp);
-    }
+  /// Builds path using the added car paths, `profile` is used for walking, the first foot profile by default.
+  /// Boarding times are in seconds: `max_wait` for a car, `dwell` of the car to let the rider in or out
+  /// and `driver_tolerance` the driver could wait for a late rider. Up to `max_transfers` changes of cars
+  /// are made between two points, each one costs as `transfer_penalty` seconds more of the path.
+  #[args(
+    profile = "None",
+    max_wait = "None",
+    dwell = "None",
+    driver_tolerance = "None",
+    max_transfers = "None",
+    transfer_penalty = "None"
+  )]
+  #[allow(clippy::too_many_arguments)]
+  pub fn build_path_using_cars(
+    &self,
+    py: Python,
+    start_at: i64,
+    points: Vec<PyRef<MapPoint>>,
+    profile: Option<String>,
+    max_wait: Option<u32>,
+    dwell: Option<u32>,
+    driver_tolerance: Option<u32>,
+    max_transfers: Option<u32>,
+    transfer_penalty: Option<u32>
+  ) -> PyResult<PathResultObject> {
+    let profile = self.find_profile(profile, TransportKind::Foot)?;
+    let points: Vec<&MapPoint> = points.iter().map(|p| p.deref()).collect();
+    let boarding = Self::boarding(max_wait, dwell, driver_tolerance);
+    let default = Transfers::default();
+    let transfers = Transfers {
+      max: max_transfers.unwrap_or(default.max),
+      penalty: transfer_penalty.map_or(default.penalty, |t| t * 1000)
+    };
+    let pr = py.allow_threads(|| self.build_path_using_cars_rust(start_at, points, boarding, transfers, profile));
 
-    Ok(PathResultObject::from_path_result(py, self.build_path_using_cars_rust(start_at, points, plain_car_paths)))
+    Ok(PathResultObject::from_path_result(py, pr))
   }
-}
 
-#[derive(Clone)]
-struct ClosestNode {
-  id: u64,
-  dist: f64
+  /// Builds the paths from `start` to `end` using the added car paths which are the best by arrival time,
+  /// walking distance or number of transfers, ordered by arrival time. Arguments are the same as of
+  /// `build_path_using_cars`, transfers are not penalized.
+  #[args(profile = "None", max_wait = "None", dwell = "None", driver_tolerance = "None", max_transfers = "None")]
+  #[allow(clippy::too_many_arguments)]
+  pub fn build_journeys_using_cars(
+    &self,
+    py: Python,
+    start_at: i64,
+    start: PyRef<MapPoint>,
+    end: PyRef<MapPoint>,
+    profile: Option<String>,
+    max_wait: Option<u32>,
+    dwell: Option<u32>,
+    driver_tolerance: Option<u32>,
+    max_transfers: Option<u32>
+  ) -> PyResult<Vec<PathResultObject>> {
+    let profile = self.find_profile(profile, TransportKind::Foot)?;
+    let boarding = Self::boarding(max_wait, dwell, driver_tolerance);
+    let transfers = Transfers { max: max_transfers.unwrap_or(Transfers::default().max), penalty: 0 };
+    let (start, end) = (start.deref(), end.deref());
+    let journeys = py.allow_threads(|| self.build_journeys_using_cars_rust(start_at, start, end, boarding, transfers, profile));
+
+    Ok(journeys.into_iter().map(|pr| PathResultObject::from_path_result(py, pr)).collect())
+  }
+
+  /// Keeps the car path for the next queries. Raises CarPathError if the path is already added
+  /// or its points are not road nodes.
+  pub fn add_car_path(&self, py: Python, car_path: PyRef<MapCarPath>) -> PyResult<()> {
+    let points = car_path.points(py)?;
+    self.add_car_path_rust(&car_path.as_plain(&points))?;
+    Ok(())
+  }
+
+  /// Replaces the added car path with the same id, its reservations are kept.
+  pub fn update_car_path(&self, py: Python, car_path: PyRef<MapCarPath>) -> PyResult<()> {
+    let points = car_path.points(py)?;
+    self.update_car_path_rust(&car_path.as_plain(&points))?;
+    Ok(())
+  }
+
+  /// Removes the car path with its reservations.
+  pub fn remove_car_path(&self, id: u64) -> PyResult<()> {
+    self.remove_car_path_rust(id)?;
+    Ok(())
+  }
+
+  /// Finds how the driver of the added car path could pick up the rider at `start` and drop off at `end`
+  /// leaving the path for at most `max_detour` seconds or `max_detour_percent` of the path time,
+  /// 10 minutes if none is set. Returns None if the detour is longer.
+  #[args(seats = "1", max_detour = "None", max_detour_percent = "None", profile = "None")]
+  #[allow(clippy::too_many_arguments)]
+  pub fn match_detour(
+    &self,
+    py: Python,
+    path_id: u64,
+    start: PyRef<MapPoint>,
+    end: PyRef<MapPoint>,
+    seats: u8,
+    max_detour: Option<u32>,
+    max_detour_percent: Option<u32>,
+    profile: Option<String>
+  ) -> PyResult<Option<DetourMatchObject>> {
+    let profile = self.find_profile(profile, TransportKind::Car)?;
+    let detour = Self::detour(max_detour, max_detour_percent);
+    let request = PlainMapRideRequest { start: start.deref(), end: end.deref(), seats };
+    let found = py.allow_threads(|| self.match_detour_rust(path_id, &request, detour, profile))?;
+
+    Ok(found.map(|m| DetourMatchObject::from_detour_match(py, m)))
+  }
+
+  /// Assigns the riders to the drivers of the added car paths with `path_ids`, a driver takes riders
+  /// while there are free seats and the detours for all of them are within the limits of `match_detour`.
+  /// Riders are taken in rounds, each round serves as many riders as possible with the least total
+  /// added time. Returns the detour of every request or None if it is not served.
+  #[args(max_detour = "None", max_detour_percent = "None", profile = "None")]
+  #[allow(clippy::too_many_arguments)]
+  pub fn match_batch(
+    &self,
+    py: Python,
+    path_ids: Vec<u64>,
+    requests: Vec<PyRef<MapRideRequest>>,
+    max_detour: Option<u32>,
+    max_detour_percent: Option<u32>,
+    profile: Option<String>
+  ) -> PyResult<Vec<Option<DetourMatchObject>>> {
+    let profile = self.find_profile(profile, TransportKind::Car)?;
+    let detour = Self::detour(max_detour, max_detour_percent);
+    let points: Vec<(PyRef<MapPoint>, PyRef<MapPoint>)> = requests.iter().map(|r| r.points(py)).collect();
+    let requests: Vec<PlainMapRideRequest> = requests.iter().zip(points.iter())
+      .map(|(r, (start, end))| PlainMapRideRequest { start: start.deref(), end: end.deref(), seats: r.seats })
+      .collect();
+    let found = py.allow_threads(|| self.match_batch_rust(&path_ids, &requests, detour, profile))?;
+
+    Ok(found.into_iter().map(|m| m.map(|m| DetourMatchObject::from_detour_match(py, m))).collect())
+  }
+
+  /// Takes `seats` on the added car path between its points `from` and `to`, returns the reservation id.
+  /// Raises ReservationError if some segment hasn't enough free seats.
+  #[args(seats = "1")]
+  pub fn commit_reservation(&self, path_id: u64, from: usize, to: usize, seats: u8) -> PyResult<u64> {
+    let car_paths = self.car_paths.read().unwrap();
+    let capacity = car_paths.seats(path_id).ok_or(CarPathError::Unknown(path_id))?;
+    let id = self.reservations.write().unwrap().commit(path_id, &capacity, from, to, seats)?;
+    Ok(id)
+  }
+
+  pub fn cancel_reservation(&self, id: u64) -> PyResult<()> {
+    self.reservations.write().unwrap().cancel(id)?;
+    Ok(())
+  }
 }
 
 impl MapService {
-  pub fn build_path_rust(&mut self, points: Vec<&MapPoint>) -> PathResult {
+  fn find_profile(&self, name: Option<String>, default_kind: TransportKind) -> PyResult<ProfileId> {
+    match name {
+      Some(name) => self.graph.profile_id(&name)
+        .ok_or_else(|| ValueError::py_err(format!("unknown profile {}", name))),
+      None => self.graph.default_profile(default_kind)
+        .ok_or_else(|| ValueError::py_err(format!("no {:?} profile", default_kind)))
+    }
+  }
+
+  pub fn load_rust(&mut self, path: String, area: Option<Area>) -> Result<(), MapLoadError> {
+    let data = crate::osm_map::load(path, area)?;
+    self.build_graph(data);
+
+    Ok(())
+  }
+
+  /// Osm data is dropped after the graph is built, only the graph is kept.
+  fn build_graph(&mut self, data: MapData) {
+    let mut builder = GraphBuilder::new();
+    for node in data.nodes.values() {
+      builder.add_node(node.id, node.lat, node.lon);
+    }
+
+    for way in data.ways.values().filter(|w| !w.nodes.is_empty()) {
+      let car_direction = way.direction(TransportKind::Car);
+      let foot_direction = way.direction(TransportKind::Foot);
+      let forward = Access {
+        foot: foot_direction.allows_forward(),
+        car: car_direction.allows_forward()
+      };
+      let backward = Access {
+        foot: foot_direction.allows_backward(),
+        car: car_direction.allows_backward()
+      };
+
+      let mut tags = way.tags.clone();
+      tags.sort();
+      let class = Some(builder.road_class_id(RoadClass {
+        highway: way.highway.clone(),
+        maxspeed: way.maxspeed,
+        tags
+      }));
+
+      for (prev, curr) in way.nodes.iter().zip(way.nodes.iter().skip(1)) {
+        let len = distance(&**prev, &**curr);
+        if forward == backward {
+          builder.connect_two_way(prev.id, curr.id, len, forward, class);
+        } else {
+          if !forward.is_empty() {
+            builder.connect_one_way(prev.id, curr.id, len, forward, class);
+          }
+          if !backward.is_empty() {
+            builder.connect_one_way(curr.id, prev.id, len, backward, class);
+          }
+        }
+      }
+    }
+
+    for r in data.restrictions.iter() {
+      let turns = r.turns(&data.ways);
+      if turns.is_empty() {
+        debug!("restriction {} doesn't match loaded ways", r.id);
+      }
+      for turn in turns {
+        builder.add_turn_restriction(r.kind, turn);
+      }
+    }
+
+    self.set_graph(builder.build(self.graph.profiles().to_vec()));
+  }
+
+  /// Car paths are moved to the new graph, paths not fitting it are removed.
+  fn set_graph(&mut self, graph: RoadGraph) {
+    self.graph = graph;
+    self.workspaces.get_mut().unwrap().clear();
+    for id in self.car_paths.get_mut().unwrap().rebind(&self.graph) {
+      warn!("car path {} is removed, it doesn't fit the new map", id);
+      self.reservations.get_mut().unwrap().remove_path(id);
+    }
+  }
+
+  /// Runs `f` with a cleared workspace of the graph, the workspace is returned to the pool after it.
+  fn with_workspace<T>(&self, f: impl FnOnce(&mut Workspace) -> T) -> T {
+    let ws = self.workspaces.lock().unwrap().pop();
+    let mut ws = ws.unwrap_or_else(|| self.graph.workspace());
+    ws.clear();
+    let res = f(&mut ws);
+    self.workspaces.lock().unwrap().push(ws);
+    res
+  }
+
+  pub fn save_snapshot_rust(&self, path: &str) -> Result<(), SnapshotError> {
+    crate::snapshot::save(path, &self.graph)
+  }
+
+  /// The graph arrays are mapped from the snapshot file and shared with other processes mapping it.
+  pub fn load_snapshot_rust(&mut self, path: &str) -> Result<(), SnapshotError> {
+    let graph = crate::snapshot::load(path, self.graph.profiles().to_vec())?;
+    self.set_graph(graph);
+
+    Ok(())
+  }
+
+  pub fn prepare_hierarchies_rust(&mut self, profiles: &[ProfileId]) {
+    for profile in profiles.iter().copied() {
+      let st = std::time::Instant::now();
+      self.graph.prepare_hierarchy(profile);
+      info!("Prepared hierarchy of {} in {}s.", self.graph.profile(profile).name, st.elapsed().as_secs_f64());
+    }
+  }
+
+  pub fn prepare_landmarks_rust(&mut self, profiles: &[ProfileId], count: usize) {
+    for profile in profiles.iter().copied() {
+      let st = std::time::Instant::now();
+      self.graph.prepare_landmarks(profile, count);
+      info!("Prepared landmarks of {} in {}s.", self.graph.profile(profile).name, st.elapsed().as_secs_f64());
+    }
+  }
+
+  pub fn build_path_rust(&self, points: Vec<&MapPoint>, profile: ProfileId) -> PathResult {
     let st = std::time::Instant::now();
+    let path_result = self.with_workspace(|ws| {
+      let snaps = match self.snap_points(ws, &points, profile) {
+        Some(snaps) => snaps,
+        None => return PathResult::default()
+      };
+      let path_result = self.build_legs(ws, None, &snaps, profile);
+      Self::with_snaps(path_result, &snaps)
+    });
 
-    let closest = self.get_closest_list(points, TransportKind::Car);
+    let en = std::time::Instant::now();
+    info!("Build path in {}s.", (en - st).as_secs_f64());
+    path_result
+  }
 
-    let start_node_id = closest.get(0).unwrap().id;
-    let mut prev = self.graph.node_id_by_osm_id(start_node_id).unwrap();
+  /// Joins paths between the consecutive snapped points, every leg departs at the arrival time
+  /// of the previous one. Empty result if some leg has no path.
+  fn build_legs(&self, ws: &mut Workspace, cars: Option<&Cars>, snaps: &[Snap], profile: ProfileId) -> PathResult {
+    let mut prev = match snaps.first() {
+      Some(s) => s.node,
+      None => return PathResult::default()
+    };
     let mut path_result = PathResult {
-      points: vec![MapPoint::from(self.nodes.get(&start_node_id).unwrap())],
+      points: vec![ws.map_point(&self.graph, prev)],
       eta_list: vec![0],
       distance_list: vec![0],
-      total_time: 0,
-      total_distance: 0
+      ..PathResult::default()
     };
 
-    for cl in closest.iter().skip(1) {
-      let curr = self.graph.node_id_by_osm_id(cl.id).unwrap();
-      let pr = self.graph.shortest_path(prev, curr, TransportKind::Car);
+    for curr in snaps.iter().skip(1).map(|s| s.node) {
+      let pr = match cars {
+        Some(cars) => self.graph.shortest_path_using_cars(ws, cars, prev, curr, path_result.total_time, profile),
+        None => self.graph.shortest_path(ws, prev, curr, path_result.total_time, profile)
+      };
+      if pr.points.is_empty() {
+        return PathResult::default();
+      }
       let prev_total_time = path_result.total_time;
       let prev_total_distance = path_result.total_distance;
 
@@ -423,104 +937,148 @@ impl MapService {
 
       path_result.total_time = prev_total_time + pr.total_time;
       path_result.total_distance = prev_total_distance + pr.total_distance;
+      path_result.transfers += pr.transfers;
+      path_result.walk_distance += pr.walk_distance;
+      path_result.legs.extend(pr.legs.into_iter().map(|l| PathLeg {
+        start_time: prev_total_time + l.start_time,
+        end_time: prev_total_time + l.end_time,
+        ..l
+      }));
 
       prev = curr;
     }
 
-    let en = std::time::Instant::now();
-    info!("Build path in {}s.", (en - st).as_secs_f64());
     path_result
   }
 
-  fn get_closest_list(&self, points: Vec<&MapPoint>, kind: TransportKind) -> Vec<ClosestNode> {
-    let mut closest = vec![ClosestNode { id: 0, dist: f64::MAX }; points.len()];
-
-    for (k, v) in self.nodes.iter() {
-      if self.node_ways.get(k)
-        .map(|v| v.iter().any(|w| kind.is_foot() || kind.is_car() && w.road_kind.is_car()))
-        .unwrap_or(false)
-      {
-        for (ind, point) in points.iter().enumerate() {
-          let mut cl = &mut closest[ind];
-          let d = (v.lat - point.lat).powi(2) + (v.lon - point.lon).powi(2);
-          if cl.dist > d {
-            cl.dist = d;
-            cl.id = *k;
-          }
-        }
-      }
-    }
-
-    closest
+  /// Snaps the points to the nearest roads usable with the profile, `None` if some point has no such road.
+  fn snap_points(&self, ws: &mut Workspace, points: &[&MapPoint], profile: ProfileId) -> Option<Vec<Snap>> {
+    points.iter()
+      .map(|p| self.graph.snap(ws, p.lat, p.lon, profile))
+      .collect()
   }
 
-  pub fn build_path_using_cars_rust(&mut self, start_at: i64, points: Vec<&MapPoint>, car_paths: Vec<PlainMapCarPath>) -> PathResult {
-    let st = std::time::Instant::now();
-    for p in car_paths.iter() {
-      let first_point = p.path.first().unwrap().deref();
-      let mut prev_car_eta = (p.start_at - start_at) * 1000;
-      let mut prev_car_dist = 0;
-      let mut prev_node_id = self.graph.add_car_map_point(first_point, 255, p.id);
-
-      self.graph.set_car_node_eta(prev_node_id, prev_car_eta);
-      self.graph.connect_two_way(
-        prev_node_id,
-        *self.graph.node_map.get(&first_point.id).unwrap(),
-        ROAD_TO_CAR,
-        TransportKind::Foot
-      );
+  fn with_snaps(mut path_result: PathResult, snaps: &[Snap]) -> PathResult {
+    path_result.snapped_points = snaps.iter().map(|s| MapPoint { id: 0, lat: s.lat, lon: s.lon, path_id: None }).collect();
+    path_result.snap_distances = snaps.iter().map(|s| (s.distance as f32 / 100.0).round() as u32).collect();
+    path_result
+  }
 
-      for curr_point in p.path.iter().skip(1) {
-        let curr_node_id = self.graph.add_car_map_point(curr_point, 255, p.id);
-
-        let curr_car_dist = prev_car_dist + distance(
-          self.graph.node(prev_node_id),
-          self.graph.node(curr_node_id)
-        ) as i64;
-        let curr_car_eta = prev_car_eta + distance_t(
-          self.graph.node(prev_node_id),
-          self.graph.node(curr_node_id),
-            Kmh(50)
-        ) as i64;
-        self.graph.set_car_node_eta(curr_node_id, curr_car_eta);
-        // connect to road node
-        self.graph.connect_two_way(
-          curr_node_id,
-          *self.graph.node_map.get(&curr_point.id).unwrap(),
-          ROAD_TO_CAR,
-          TransportKind::Foot
-        );
-
-        // connect to prev TODO: connect one way
-        self.graph.connect_two_way(
-          curr_node_id,
-          prev_node_id,
-          (curr_car_dist - prev_car_dist) as u32,
-          TransportKind::Car
-        );
-
-        prev_car_eta = curr_car_eta;
-        prev_car_dist = curr_car_dist;
-        prev_node_id = curr_node_id;
-      }
+  /// Detour limits given in seconds and percents, the default limit if none is set.
+  fn detour(max_detour: Option<u32>, max_detour_percent: Option<u32>) -> Detour {
+    match (max_detour, max_detour_percent) {
+      (None, None) => Detour::default(),
+      (time, percent) => Detour { max_time: time.map(|t| t * 1000), max_percent: percent }
     }
+  }
 
-    let closest = self.get_closest_list(points, TransportKind::Foot);
-    let n1 = *self.graph.node_map.get(&closest[0].id).unwrap();
-    let n2 = *self.graph.node_map.get(&closest[1].id).unwrap();
+  /// Boarding with times given in seconds, defaults for the missing ones.
+  fn boarding(max_wait: Option<u32>, dwell: Option<u32>, driver_tolerance: Option<u32>) -> Boarding {
+    let default = Boarding::default();
+    Boarding {
+      max_wait: max_wait.map_or(default.max_wait, |t| t * 1000),
+      dwell: dwell.map_or(default.dwell, |t| t * 1000),
+      driver_tolerance: driver_tolerance.map_or(default.driver_tolerance, |t| t * 1000)
+    }
+  }
 
-    let res = self.graph.shortest_path(n1, n2, TransportKind::Foot);
+  /// `start_at` is in seconds since unix epoch like the starts of car paths.
+  pub fn build_path_using_cars_rust(
+    &self,
+    start_at: i64,
+    points: Vec<&MapPoint>,
+    boarding: Boarding,
+    transfers: Transfers,
+    profile: ProfileId
+  ) -> PathResult {
+    let st = std::time::Instant::now();
+    let car_paths = self.car_paths.read().unwrap();
+    let reservations = self.reservations.read().unwrap();
+    let cars = Cars { paths: &car_paths, reservations: &reservations, start_at: start_at * 1000, boarding, transfers };
+    let res = self.with_workspace(|ws| {
+      let snaps = match self.snap_points(ws, &points, profile) {
+        Some(snaps) => snaps,
+        None => return PathResult::default()
+      };
+      let path_result = self.build_legs(ws, Some(&cars), &snaps, profile);
+      Self::with_snaps(path_result, &snaps)
+    });
 
     let en = std::time::Instant::now();
     info!("Build path in {}s.", (en - st).as_secs_f64());
 
     res
   }
+
+  pub fn build_journeys_using_cars_rust(
+    &self,
+    start_at: i64,
+    start: &MapPoint,
+    end: &MapPoint,
+    boarding: Boarding,
+    transfers: Transfers,
+    profile: ProfileId
+  ) -> Vec<PathResult> {
+    let car_paths = self.car_paths.read().unwrap();
+    let reservations = self.reservations.read().unwrap();
+    let cars = Cars { paths: &car_paths, reservations: &reservations, start_at: start_at * 1000, boarding, transfers };
+    self.with_workspace(|ws| {
+      let snaps = match self.snap_points(ws, &[start, end], profile) {
+        Some(snaps) => snaps,
+        None => return Vec::new()
+      };
+      self.graph.journeys_using_cars(ws, &cars, snaps[0].node, snaps[1].node, profile).into_iter()
+        .map(|pr| Self::with_snaps(pr, &snaps))
+        .collect()
+    })
+  }
+
+  pub fn add_car_path_rust(&self, path: &PlainMapCarPath) -> Result<(), CarPathError> {
+    self.car_paths.write().unwrap().add(&self.graph, path)
+  }
+
+  pub fn update_car_path_rust(&self, path: &PlainMapCarPath) -> Result<(), CarPathError> {
+    self.car_paths.write().unwrap().update(&self.graph, path)
+  }
+
+  /// `None` if there are no car roads near the points or the detour is longer than allowed.
+  pub fn match_detour_rust(&self, path_id: u64, request: &PlainMapRideRequest, detour: Detour, profile: ProfileId) -> Result<Option<DetourMatch>, CarPathError> {
+    Ok(self.match_batch_rust(&[path_id], std::slice::from_ref(request), detour, profile)?.pop().unwrap())
+  }
+
+  /// Assigns the riders to the drivers of the car paths, a driver takes riders while there are free seats
+  /// and the detours are within the limits. Returns the detour of every rider.
+  pub fn match_batch_rust(&self, path_ids: &[u64], requests: &[PlainMapRideRequest], detour: Detour, profile: ProfileId) -> Result<Vec<Option<DetourMatch>>, CarPathError> {
+    let car_paths = self.car_paths.read().unwrap();
+    let reservations = self.reservations.read().unwrap();
+    let mut drivers = Vec::with_capacity(path_ids.len());
+    for id in path_ids.iter().copied() {
+      let points = car_paths.path(id).ok_or(CarPathError::Unknown(id))?;
+      let free_seats = reservations.free_seats(id, &car_paths.seats(id).unwrap());
+      drivers.push((points, free_seats));
+    }
+    let requests: Vec<Option<DetourRequest>> = requests.iter().map(|r| Some(DetourRequest {
+      pickup: crate::detour::meeting_node(&self.graph, r.start, profile)?,
+      dropoff: crate::detour::meeting_node(&self.graph, r.end, profile)?,
+      seats: r.seats
+    })).collect();
+
+    let roads = crate::detour::Roads::new(&self.graph, profile);
+    let routes = drivers.iter().map(|(points, free_seats)| Route::new(points, free_seats, detour)).collect();
+    Ok(self.with_workspace(|ws| crate::detour::match_batch(&roads, ws, routes, &requests)))
+  }
+
+  pub fn remove_car_path_rust(&self, id: u64) -> Result<(), CarPathError> {
+    let mut car_paths = self.car_paths.write().unwrap();
+    car_paths.remove(id)?;
+    self.reservations.write().unwrap().remove_path(id);
+    Ok(())
+  }
 }
 
 /// MapService responsible for working with map data and paths.
 #[pymodule]
-fn map_service(_py: Python, m: &PyModule) -> PyResult<()> {
+fn map_service(py: Python, m: &PyModule) -> PyResult<()> {
   if env_logger::try_init().is_ok() {
     warn!("LOGGER INITED");
   }
@@ -528,16 +1086,520 @@ fn map_service(_py: Python, m: &PyModule) -> PyResult<()> {
   m.add_class::<MapService>()?;
   m.add_class::<MapPoint>()?;
   m.add_class::<MapCarPath>()?;
+  m.add_class::<MapRideRequest>()?;
+  m.add("MapLoadError", py.get_type::<MapLoadException>())?;
+  m.add("ProfileLoadError", py.get_type::<ProfileLoadException>())?;
+  m.add("SnapshotError", py.get_type::<SnapshotException>())?;
+  m.add("ReservationError", py.get_type::<ReservationException>())?;
+  m.add("CarPathError", py.get_type::<CarPathException>())?;
 
   Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::sum_as_string;
+  use crate::{distance, distance_t, LegMode, MapPoint, MapService, PathResult, PlainMapCarPath, PlainMapRideRequest, TransportKind};
+  use crate::car_paths::{Boarding, Transfers, CAR_PATH_SPEED};
+  use crate::error::{SnapshotError, CarPathError};
+  use crate::detour::Detour;
+  use crate::graph::ProfileId;
+  use std::ops::Range;
+
+  /// Loads service from osm xml written to a temporary file.
+  fn service_from_xml(name: &str, xml: &str) -> MapService {
+    let path = std::env::temp_dir().join(format!("map_service_test_{}_{}.osm", name, std::process::id()));
+    std::fs::write(&path, xml).unwrap();
+    let mut ms = MapService::default();
+    ms.load_rust(path.to_str().unwrap().to_string(), None).unwrap();
+    std::fs::remove_file(path).unwrap();
+    ms
+  }
+
+  fn path_ids(ms: &mut MapService, from: u64, to: u64, kind: TransportKind) -> Vec<u64> {
+    let from = ms.graph.node_id_by_osm_id(from).unwrap();
+    let to = ms.graph.node_id_by_osm_id(to).unwrap();
+    let profile = ms.graph.default_profile(kind).unwrap();
+    ms.graph.shortest_path(&mut ms.graph.workspace(), from, to, 0, profile).points.iter().map(|p| p.id).collect()
+  }
+
+  const ONEWAY_XML: &str = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.601"/>
+ <node id="3" lat="55.750" lon="37.602"/>
+ <node id="4" lat="55.751" lon="37.601"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/><tag k="oneway" v="yes"/></way>
+ <way id="11"><nd ref="3"/><nd ref="4"/><nd ref="1"/><tag k="highway" v="residential"/></way>
+</osm>"#;
 
   #[test]
-  fn it_works() {
-    assert_eq!(sum_as_string(452,785).unwrap(), (452 + 785).to_string());
+  fn distance_works() {
+    let p1 = MapPoint { id: 1, lat: 55.7558, lon: 37.6173, path_id: None };
+    let p2 = MapPoint { id: 2, lat: 55.7522, lon: 37.6156, path_id: None };
+    // ~415 meters between Red Square and Borovitskaya
+    assert!((distance(&p1, &p2) as i64 - 41_500).abs() < 500);
+    assert_eq!(distance(&p1, &p1), 0);
+  }
+
+  #[test]
+  fn car_respects_oneway() {
+    let mut ms = service_from_xml("oneway", ONEWAY_XML);
+    assert_eq!(path_ids(&mut ms, 1, 3, TransportKind::Car), vec![1, 2, 3]);
+    assert_eq!(path_ids(&mut ms, 3, 1, TransportKind::Car), vec![3, 4, 1]);
+    assert_eq!(path_ids(&mut ms, 3, 1, TransportKind::Foot), vec![3, 2, 1]);
+  }
+
+  #[test]
+  fn car_prefers_faster_roads() {
+    let xml = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.610"/>
+ <node id="3" lat="55.751" lon="37.605"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/><tag k="maxspeed" v="RU:living_street"/></way>
+ <way id="11"><nd ref="1"/><nd ref="3"/><nd ref="2"/><tag k="highway" v="motorway"/></way>
+</osm>"#;
+    let mut ms = service_from_xml("speed", xml);
+    assert_eq!(path_ids(&mut ms, 1, 2, TransportKind::Car), vec![1, 3, 2]);
+    assert_eq!(path_ids(&mut ms, 1, 2, TransportKind::Foot), vec![1, 2]);
+    // motorway is implied oneway
+    assert_eq!(path_ids(&mut ms, 2, 1, TransportKind::Car), vec![2, 1]);
+  }
+
+  #[test]
+  fn roundabout_and_foot_oneway() {
+    let xml = ONEWAY_XML
+      .replace(r#"<tag k="oneway" v="yes"/>"#, r#"<tag k="junction" v="roundabout"/><tag k="oneway:foot" v="-1"/>"#);
+    let mut ms = service_from_xml("roundabout", &xml);
+    assert_eq!(path_ids(&mut ms, 3, 1, TransportKind::Car), vec![3, 4, 1]);
+    assert_eq!(path_ids(&mut ms, 3, 1, TransportKind::Foot), vec![3, 2, 1]);
+    assert_eq!(path_ids(&mut ms, 1, 3, TransportKind::Foot), vec![1, 4, 3]);
+  }
+
+  const TURN_XML: &str = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.601"/>
+ <node id="3" lat="55.750" lon="37.602"/>
+ <node id="4" lat="55.751" lon="37.601"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+ <way id="11"><nd ref="2"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <way id="12"><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <relation id="100">
+  <member type="way" ref="10" role="from"/>
+  <member type="node" ref="2" role="via"/>
+  <member type="way" ref="11" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="no_left_turn"/>
+ </relation>
+</osm>"#;
+
+  #[test]
+  fn car_respects_via_node_restrictions() {
+    let mut ms = service_from_xml("no_turn", TURN_XML);
+    assert_eq!(path_ids(&mut ms, 1, 4, TransportKind::Car), vec![1, 2, 3, 4]);
+    assert_eq!(path_ids(&mut ms, 4, 1, TransportKind::Car), vec![4, 2, 1]);
+    assert_eq!(path_ids(&mut ms, 3, 4, TransportKind::Car), vec![3, 4]);
+    assert_eq!(path_ids(&mut ms, 1, 4, TransportKind::Foot), vec![1, 2, 4]);
+
+    let xml = TURN_XML
+      .replace(r#"<member type="way" ref="11" role="to"/>"#, r#"<member type="way" ref="10" role="to"/>"#)
+      .replace("no_left_turn", "only_straight_on");
+    let mut ms = service_from_xml("only_turn", &xml);
+    assert_eq!(path_ids(&mut ms, 1, 4, TransportKind::Car), vec![1, 2, 3, 4]);
+    assert_eq!(path_ids(&mut ms, 1, 3, TransportKind::Car), vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn car_respects_via_way_restrictions() {
+    let xml = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.601"/>
+ <node id="3" lat="55.750" lon="37.602"/>
+ <node id="4" lat="55.750" lon="37.603"/>
+ <node id="5" lat="55.751" lon="37.6015"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+ <way id="11"><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+ <way id="12"><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <way id="13"><nd ref="1"/><nd ref="5"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <relation id="100">
+  <member type="way" ref="10" role="from"/>
+  <member type="way" ref="11" role="via"/>
+  <member type="way" ref="12" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="no_straight_on"/>
+ </relation>
+</osm>"#;
+    let mut ms = service_from_xml("via_way", xml);
+    assert_eq!(path_ids(&mut ms, 1, 4, TransportKind::Car), vec![1, 5, 4]);
+    assert_eq!(path_ids(&mut ms, 2, 4, TransportKind::Car), vec![2, 3, 4]);
+    assert_eq!(path_ids(&mut ms, 1, 4, TransportKind::Foot), vec![1, 2, 3, 4]);
+  }
+
+  #[test]
+  fn via_way_restriction_keeps_legal_path_to_its_via_way() {
+    // the restricted path 1-2-3-4 is the fastest one, the legal path enters the via way from the side street
+    let xml = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.601"/>
+ <node id="3" lat="55.750" lon="37.602"/>
+ <node id="4" lat="55.750" lon="37.603"/>
+ <node id="6" lat="55.7503" lon="37.6005"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+ <way id="11"><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+ <way id="12"><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <way id="14"><nd ref="1"/><nd ref="6"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+ <relation id="100">
+  <member type="way" ref="10" role="from"/>
+  <member type="way" ref="11" role="via"/>
+  <member type="way" ref="12" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="no_straight_on"/>
+ </relation>
+</osm>"#;
+    let mut ms = service_from_xml("via_way_side", xml);
+    assert_eq!(path_ids(&mut ms, 1, 4, TransportKind::Car), vec![1, 6, 2, 3, 4]);
+    assert_eq!(path_ids(&mut ms, 1, 3, TransportKind::Car), vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn snaps_to_road_segments() {
+    let ms = service_from_xml("snap", ONEWAY_XML);
+    let point = |lat, lon| MapPoint { id: 0, lat, lon, path_id: None };
+    let route = |from: MapPoint, to: MapPoint, kind| {
+      let profile = ms.graph.default_profile(kind).unwrap();
+      ms.build_path_rust(vec![&from, &to], profile)
+    };
+
+    let pr = route(point(55.7501, 37.6005), point(55.751, 37.601), TransportKind::Car);
+    assert_eq!(pr.points.iter().map(|p| p.id).collect::<Vec<_>>(), vec![0, 2, 3, 4]);
+    assert!((pr.snapped_points[0].lat - 55.750).abs() < 1e-9 && (pr.snapped_points[0].lon - 37.6005).abs() < 1e-9);
+    assert_eq!(pr.snap_distances, vec![11, 0]);
+    assert_eq!((pr.points[0].lat, pr.points[0].lon), (pr.snapped_points[0].lat, pr.snapped_points[0].lon));
+
+    // the oneway segment is split into oneway parts
+    let ids = |pr: PathResult| pr.points.iter().map(|p| p.id).collect::<Vec<_>>();
+    assert_eq!(ids(route(point(55.750, 37.6005), point(55.750, 37.600), TransportKind::Car)), vec![0, 2, 3, 4, 1]);
+    assert_eq!(ids(route(point(55.750, 37.6005), point(55.750, 37.600), TransportKind::Foot)), vec![0, 1]);
+    assert_eq!(ids(route(point(55.750, 37.6003), point(55.750, 37.6007), TransportKind::Car)), vec![0, 0]);
+    assert_eq!(ids(route(point(55.750, 37.6007), point(55.750, 37.6003), TransportKind::Car)), vec![0, 2, 3, 4, 1, 0]);
+  }
+
+  /// Straight street with 1 km between nodes.
+  const STREET_XML: &str = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.616"/>
+ <node id="3" lat="55.750" lon="37.632"/>
+ <node id="4" lat="55.750" lon="37.648"/>
+ <node id="5" lat="55.750" lon="37.664"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="5"/><tag k="highway" v="residential"/></way>
+</osm>"#;
+
+  /// Service with [`STREET_XML`] loaded, `points[i]` is the node `i + 1` of the street.
+  struct Street {
+    ms: MapService,
+    points: Vec<MapPoint>
+  }
+
+  impl Street {
+    fn new(name: &str) -> Self {
+      let ms = service_from_xml(name, STREET_XML);
+      let points = (1..=5).map(|id| {
+        let p = ms.graph.map_point(ms.graph.node_id_by_osm_id(id).unwrap());
+        MapPoint { id, lat: p.lat, lon: p.lon, path_id: None }
+      }).collect();
+      Street { ms, points }
+    }
+
+    fn profile(&self, kind: TransportKind) -> ProfileId {
+      self.ms.graph.default_profile(kind).unwrap()
+    }
+
+    /// Adds or replaces the car path `id` through `points[range]` starting at `start_at` seconds.
+    fn set_car(&self, id: u64, start_at: i64, range: Range<usize>, seats: &[u8]) {
+      let path = PlainMapCarPath { id, start_at, path: self.points[range].iter().collect(), seats };
+      match self.ms.update_car_path_rust(&path) {
+        Err(CarPathError::Unknown(_)) => self.ms.add_car_path_rust(&path).unwrap(),
+        r => r.unwrap()
+      }
+    }
+
+    /// Path on foot using the cars through `points` with indexes `via` departing at 0.
+    fn route_with(&self, via: &[usize], boarding: Boarding, transfers: Transfers) -> PathResult {
+      let via = via.iter().map(|i| &self.points[*i]).collect();
+      self.ms.build_path_using_cars_rust(0, via, boarding, transfers, self.profile(TransportKind::Foot))
+    }
+
+    fn route(&self, via: &[usize]) -> PathResult {
+      self.route_with(via, Boarding::default(), Transfers::default())
+    }
+  }
+
+  /// Ids of the cars ridden in order.
+  fn cars_ridden(pr: &PathResult) -> Vec<u64> {
+    let mut cars: Vec<u64> = pr.points.iter().filter_map(|p| p.path_id).collect();
+    cars.dedup();
+    cars
+  }
+
+  #[test]
+  fn cars_on_later_legs_use_arrival_time() {
+    let street = Street::new("car_arrival");
+    // the first leg takes about 24 minutes of walking
+    let route = |car_start_at: i64| {
+      street.set_car(7, car_start_at, 2..5, &[1, 1]);
+      street.route(&[0, 2, 4])
+    };
+
+    let pr = route(30 * 60);
+    assert_eq!(cars_ridden(&pr), vec![7]);
+    assert_eq!(pr.points.first().unwrap().id, 1);
+    assert_eq!(pr.points.last().unwrap().id, 5);
+    assert!(pr.total_time < 40 * 60 * 1000);
+
+    // the car is gone by the time the rider gets to the second waypoint
+    let pr = route(10 * 60);
+    assert!(cars_ridden(&pr).is_empty());
+    assert!(pr.total_time > 45 * 60 * 1000);
+    assert_eq!(pr.snapped_points.len(), 3);
+  }
+
+  #[test]
+  fn full_car_segments_are_not_ridden() {
+    let street = Street::new("car_seats");
+    let ms = &street.ms;
+    let route = |seats: &[u8]| {
+      street.set_car(7, 60, 0..5, seats);
+      street.route(&[0, 4])
+    };
+    let car_points = |pr: &PathResult| pr.points.iter().filter(|p| p.path_id.is_some()).map(|p| p.id).collect::<Vec<_>>();
+
+    assert_eq!(car_points(&route(&[2, 2, 2, 2])), vec![1, 2, 3, 4, 5]);
+    // the rider could only get to the full segment
+    let pr = route(&[2, 0, 2, 2]);
+    assert_eq!(car_points(&pr), vec![1, 2]);
+    assert_eq!(pr.points.last().unwrap().id, 5);
+
+    // seats taken by other passengers
+    let id = ms.reservations.write().unwrap().commit(7, &[2, 2, 2, 2], 1, 3, 2).unwrap();
+    assert_eq!(car_points(&route(&[2, 2, 2, 2])), vec![1, 2]);
+    ms.reservations.write().unwrap().cancel(id).unwrap();
+    assert_eq!(car_points(&route(&[2, 2, 2, 2])), vec![1, 2, 3, 4, 5]);
+
+    ms.reservations.write().unwrap().commit(7, &[2, 2, 2, 2], 1, 3, 2).unwrap();
+    ms.remove_car_path_rust(7).unwrap();
+    assert!(ms.reservations.read().unwrap().free_seats(7, &[2, 2, 2, 2]).iter().all(|s| *s == 2));
+    assert!(car_points(&street.route(&[0, 4])).is_empty());
+  }
+
+  #[test]
+  fn cars_are_ridden_forward_only() {
+    let street = Street::new("car_direction");
+    street.set_car(7, 60, 0..5, &[1, 1, 1, 1]);
+
+    let forward = street.route(&[1, 3]);
+    assert_eq!(cars_ridden(&forward), vec![7]);
+    assert!(forward.eta_list.windows(2).all(|w| w[0] <= w[1]));
+    // the car reaches the start later than the end
+    assert!(cars_ridden(&street.route(&[3, 1])).is_empty());
+  }
+
+  #[test]
+  fn boarding_respects_waiting_windows() {
+    let street = Street::new("car_boarding");
+    let rides = |car_start_at: i64, boarding: Boarding| {
+      street.set_car(7, car_start_at, 0..5, &[1, 1, 1, 1]);
+      !cars_ridden(&street.route_with(&[0, 4], boarding, Transfers::default())).is_empty()
+    };
+    let minutes = |m: u32| m * 60 * 1000;
+
+    // the rider would have to wait 5 minutes, walking along the street doesn't help
+    assert!(!rides(5 * 60, Boarding { max_wait: minutes(2), ..Boarding::default() }));
+    assert!(rides(5 * 60, Boarding { max_wait: minutes(10), ..Boarding::default() }));
+    // the car has passed by a minute
+    assert!(!rides(-60, Boarding::default()));
+    assert!(rides(-60, Boarding { driver_tolerance: minutes(2), ..Boarding::default() }));
+
+    // waiting and dwell times are a part of the path
+    street.set_car(7, 5 * 60, 0..5, &[1, 1, 1, 1]);
+    let dwell = |d: u32| street.route_with(&[0, 4], Boarding { dwell: d, ..Boarding::default() }, Transfers::default()).total_time;
+    assert_eq!(dwell(30_000) - dwell(0), 60_000);
+    assert!(dwell(0) > minutes(5));
+  }
+
+  #[test]
+  fn transfers_are_limited_and_penalized() {
+    let street = Street::new("car_transfers");
+    street.set_car(1, 60, 0..3, &[1, 1]);
+    street.set_car(2, 5 * 60, 2..5, &[1, 1]);
+    let route = |transfers: Transfers| {
+      let pr = street.route_with(&[0, 4], Boarding::default(), transfers);
+      (cars_ridden(&pr), pr.transfers)
+    };
+
+    assert_eq!(route(Transfers::default()), (vec![1, 2], 1));
+    // the second car leaves before the rider walks to it
+    assert_eq!(route(Transfers { max: 0, ..Transfers::default() }), (vec![1], 0));
+    assert_eq!(route(Transfers { penalty: 60 * 60 * 1000, ..Transfers::default() }), (vec![1], 0));
+  }
+
+  #[test]
+  fn journeys_trade_time_for_walking_and_transfers() {
+    let street = Street::new("car_journeys");
+    let points = &street.points;
+    street.set_car(1, 60, 0..3, &[1, 1]);
+    street.set_car(2, 5 * 60, 2..5, &[1, 1]);
+    // the direct ride starts later and from the second point
+    street.set_car(3, 20 * 60, 1..5, &[1, 1, 1]);
+    let foot = street.profile(TransportKind::Foot);
+    let journeys = |max| street.ms.build_journeys_using_cars_rust(0, &points[0], &points[4], Boarding::default(), Transfers { max, penalty: 0 }, foot);
+    let summary = |found: &[PathResult]| -> Vec<(Vec<u64>, u32, u32)> {
+      found.iter().map(|pr| (cars_ridden(pr), pr.transfers, pr.walk_distance)).collect()
+    };
+
+    // the fastest one with a transfer and the direct ride, walking all the way or riding
+    // the first car only are slower and walk more
+    let found = journeys(1);
+    assert_eq!(summary(&found), vec![(vec![1, 2], 1, 0), (vec![3], 0, 1001)]);
+    assert!(found[0].total_time < found[1].total_time);
+    assert_eq!(summary(&journeys(0)), vec![(vec![3], 0, 1001)]);
+  }
+
+  #[test]
+  fn legs_split_walking_and_rides() {
+    let street = Street::new("car_legs");
+    let points = &street.points;
+    street.set_car(7, 15 * 60, 1..4, &[1, 1]);
+    let pr = street.route(&[0, 4]);
+
+    let legs: Vec<(LegMode, Option<u64>, u64, u64, u32)> = pr.legs.iter().map(|l| (l.mode, l.path_id, l.from.id, l.to.id, l.distance)).collect();
+    assert_eq!(legs, vec![
+      (LegMode::Walk, None, 1, 2, 1001),
+      (LegMode::Ride, Some(7), 2, 4, 2002),
+      (LegMode::Walk, None, 4, 5, 1001)
+    ]);
+    assert_eq!(pr.legs[0].start_time, 0);
+    assert!(pr.legs.windows(2).all(|w| w[0].end_time == w[1].start_time));
+    assert_eq!(pr.legs.last().unwrap().end_time, pr.total_time);
+    // the rider walks to the second point for 12 minutes and waits there for the car
+    let ride = &pr.legs[1];
+    assert_eq!(ride.wait_time, 15 * 60 * 1000 - ride.start_time);
+    let drive = distance_t(&points[1], &points[2], CAR_PATH_SPEED) + distance_t(&points[2], &points[3], CAR_PATH_SPEED);
+    assert_eq!(ride.end_time - ride.start_time, ride.wait_time + 2 * 30 * 1000 + drive);
+
+    let pr = street.ms.build_path_rust(vec![&points[0], &points[2], &points[4]], street.profile(TransportKind::Car));
+    let legs: Vec<(LegMode, u64, u64, u32)> = pr.legs.iter().map(|l| (l.mode, l.from.id, l.to.id, l.start_time)).collect();
+    assert_eq!(legs, vec![(LegMode::Drive, 1, 3, 0), (LegMode::Drive, 3, 5, pr.eta_list[2])]);
+  }
+
+  #[test]
+  fn batch_matching_respects_seats() {
+    let street = Street::new("car_batch");
+    let (ms, points) = (&street.ms, &street.points);
+    let car = street.profile(TransportKind::Car);
+    street.set_car(1, 0, 0..5, &[1, 1, 1, 1]);
+    street.set_car(2, 0, 0..3, &[2, 2]);
+    let request = |from: usize, to: usize, seats| PlainMapRideRequest { start: &points[from], end: &points[to], seats };
+    let requests = [request(1, 4, 1), request(0, 2, 2)];
+    let detour = Detour { max_time: Some(5 * 60 * 1000), max_percent: None };
+    let batch = || -> Vec<Option<u64>> {
+      ms.match_batch_rust(&[1, 2], &requests, detour, car).unwrap().iter().map(|m| m.as_ref().map(|m| m.path_id)).collect()
+    };
+
+    // the second car could take the first rider with a detour, but only it has seats for the second one
+    assert_eq!(batch(), vec![Some(1), Some(2)]);
+    let found = ms.match_detour_rust(2, &requests[0], detour, car).unwrap().unwrap();
+    assert!(found.added_time > 0);
+    assert_eq!(found.path.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 4, 3]);
+
+    // the first car is full and it is too far to come back from the end for the first rider,
+    // every driver makes one detour and the one without it is cheaper
+    ms.reservations.write().unwrap().commit(1, &[1, 1, 1, 1], 0, 4, 1).unwrap();
+    assert_eq!(batch(), vec![None, Some(2)]);
+    assert!(matches!(ms.match_batch_rust(&[3], &requests, detour, car), Err(CarPathError::Unknown(3))));
+  }
+
+  #[test]
+  fn batch_matching_takes_several_riders_per_driver() {
+    let street = Street::new("car_batch_seats");
+    let (ms, points) = (&street.ms, &street.points);
+    let car = street.profile(TransportKind::Car);
+    let request = |from: usize, to: usize| PlainMapRideRequest { start: &points[from], end: &points[to], seats: 1 };
+    let detour = Detour { max_time: Some(60 * 1000), max_percent: None };
+
+    // one seat is enough for the riders one after another
+    street.set_car(1, 0, 0..5, &[1, 1, 1, 1]);
+    let found = ms.match_batch_rust(&[1], &[request(0, 2), request(2, 4)], detour, car).unwrap();
+    let found: Vec<_> = found.iter().map(|m| m.as_ref().unwrap()).collect();
+    assert!(found.iter().all(|m| m.path_id == 1 && m.added_time == 0));
+    let ids = |path: &[MapPoint]| path.iter().map(|p| p.id).collect::<Vec<_>>();
+    assert_eq!(ids(&found[0].path), vec![1, 2, 3, 4, 5]);
+    assert_eq!(ids(&found[1].path), ids(&found[0].path));
+    assert_eq!(found[0].dropoff_at, found[1].pickup_at);
+
+    // every rider passes the third segment with two seats, so only two of them are taken
+    street.set_car(1, 0, 0..5, &[2, 2, 2, 2]);
+    let requests = [request(0, 3), request(1, 4), request(2, 4)];
+    let found = ms.match_batch_rust(&[1], &requests, detour, car).unwrap();
+    assert_eq!(found.iter().filter(|m| m.as_ref().is_some_and(|m| m.path_id == 1)).count(), 2);
+  }
+
+  #[test]
+  fn concurrent_queries() {
+    let ms = std::sync::Arc::new(service_from_xml("concurrent", ONEWAY_XML));
+    fn route(ms: &MapService) -> Vec<u64> {
+      let point = |lat, lon| MapPoint { id: 0, lat, lon, path_id: None };
+      let points = [point(55.750, 37.600), point(55.750, 37.602), point(55.751, 37.601)];
+      let car = ms.graph.default_profile(TransportKind::Car).unwrap();
+      ms.build_path_rust(points.iter().collect(), car).points.iter().map(|p| p.id).collect()
+    }
+    let expected = route(&ms);
+    assert_eq!(expected, vec![1, 2, 3, 4]);
+
+    let threads: Vec<_> = (0..4).map(|_| {
+      let ms = ms.clone();
+      std::thread::spawn(move || (0..50).all(|_| route(&ms) == vec![1, 2, 3, 4]))
+    }).collect();
+    assert!(threads.into_iter().all(|t| t.join().unwrap()));
+  }
+
+  #[test]
+  fn snapshot_round_trip() {
+    let mut ms = service_from_xml("snapshot", TURN_XML);
+    let path = std::env::temp_dir().join(format!("map_service_test_{}.snapshot", std::process::id()));
+    let path = path.to_str().unwrap();
+    ms.save_snapshot_rust(path).unwrap();
+
+    let mut restored = MapService::default();
+    restored.load_snapshot_rust(path).unwrap();
+    assert!(restored.graph.hierarchies().is_empty() && restored.graph.all_landmarks().is_empty());
+    let foot = ms.graph.default_profile(TransportKind::Foot).unwrap();
+    let car = ms.graph.default_profile(TransportKind::Car).unwrap();
+    ms.prepare_hierarchies_rust(&[foot]);
+    ms.prepare_landmarks_rust(&[car], 2);
+    ms.save_snapshot_rust(path).unwrap();
+    restored.load_snapshot_rust(path).unwrap();
+    assert!(restored.graph.hierarchy(foot).is_some());
+    let landmarks = restored.graph.landmarks(car).unwrap();
+    assert!(!landmarks.nodes.is_empty());
+    assert_eq!(landmarks.nodes, ms.graph.landmarks(car).unwrap().nodes);
+    assert_eq!(&landmarks.from[..], &ms.graph.landmarks(car).unwrap().from[..]);
+    assert_eq!(restored.graph.nodes_num(), ms.graph.nodes_num());
+    assert_eq!(restored.graph.links_num(), ms.graph.links_num());
+    assert_eq!(restored.graph.turn_restrictions.len(), ms.graph.turn_restrictions.len());
+    for kind in [TransportKind::Car, TransportKind::Foot].iter() {
+      assert_eq!(path_ids(&mut restored, 1, 4, *kind), path_ids(&mut ms, 1, 4, *kind));
+    }
+
+    // `restored` maps the snapshot, so broken copies are written to another file
+    let broken = format!("{}.broken", path);
+    let mut data = std::fs::read(path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    std::fs::write(&broken, &data).unwrap();
+    assert!(matches!(MapService::default().load_snapshot_rust(&broken), Err(SnapshotError::ChecksumMismatch)));
+
+    data[8] = 0;
+    std::fs::write(&broken, &data).unwrap();
+    assert!(matches!(MapService::default().load_snapshot_rust(&broken), Err(SnapshotError::UnsupportedVersion(_))));
+    std::fs::remove_file(&broken).unwrap();
+    std::fs::remove_file(path).unwrap();
   }
 }
@@ -2,12 +2,20 @@ use std::rc::Rc;
 use std::ops::{Deref, DerefMut};
 use crate::utils::{u64_parse, f64_parse};
 use quick_xml::events::{Event, BytesStart};
-use std::io::BufReader;
+use std::io::{BufReader, BufRead, ErrorKind};
+use std::borrow::Cow;
 use flate2::read::GzDecoder;
 use std::fs::File;
 use quick_xml::Reader;
-use std::collections::HashMap;
-use crate::TransportKind;
+use std::collections::{HashMap, HashSet};
+use crate::{TransportKind, Kmh, EarthPoint};
+use crate::speed::parse_maxspeed;
+use crate::profile::ROUTING_TAGS;
+use crate::error::MapLoadError;
+use crate::area::Area;
+use serde::Serialize;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 pub struct OsmNode(pub Rc<InnerNode>);
 
@@ -30,6 +38,16 @@ pub struct InnerNode {
   pub lon: f64
 }
 
+impl EarthPoint for InnerNode {
+  fn lat(&self) -> f64 {
+    self.lat
+  }
+
+  fn lon(&self) -> f64 {
+    self.lon
+  }
+}
+
 impl OsmNode {
   pub fn new(id: u64, lat: f64, lon: f64) -> Self {
     OsmNode(Rc::new(InnerNode {
@@ -63,128 +81,908 @@ impl OsmWay {
       Rc::new(InnerWay {
         id,
         nodes: Vec::new(),
-        road_kind: TransportKind::Car
+        highway: String::new(),
+        maxspeed: None,
+        oneway: None,
+        car_oneway: None,
+        foot_oneway: Oneway::No,
+        roundabout: false,
+        tags: Vec::new()
       })
     )
   }
 }
 
+#[derive(Clone)]
 pub struct InnerWay {
   pub id: u64,
   pub nodes: Vec<OsmNode>,
-  pub road_kind: TransportKind
+  /// `highway` tag value, road class
+  pub highway: String,
+  /// `maxspeed` tag or implicit speed limit from `maxspeed:type`
+  pub maxspeed: Option<Kmh>,
+  /// `oneway` tag
+  pub oneway: Option<Oneway>,
+  /// `oneway:motor_vehicle` or `oneway:motorcar`, overrides `oneway` for cars
+  pub car_oneway: Option<Oneway>,
+  /// `oneway:foot` tag, plain `oneway` doesn't apply to pedestrians
+  pub foot_oneway: Oneway,
+  /// `junction=roundabout` or `junction=circular`, implies oneway for cars as `highway=motorway` does
+  pub roundabout: bool,
+  /// Other tags checked by routing profiles, see [`ROUTING_TAGS`]
+  pub tags: Vec<(String, String)>
+}
+
+impl InnerWay {
+  /// Direction of the way for the given transport kind.
+  pub fn direction(&self, kind: TransportKind) -> Oneway {
+    match kind {
+      TransportKind::Foot => self.foot_oneway,
+      TransportKind::Car => self.car_oneway
+        .or(self.oneway)
+        .unwrap_or(if self.roundabout || self.highway == "motorway" { Oneway::Forward } else { Oneway::No })
+    }
+  }
+}
+
+/// Allowed direction of movement along the way nodes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Oneway {
+  No,
+  /// Only in the order of way nodes
+  Forward,
+  /// Only against the order of way nodes (`oneway=-1`)
+  Backward,
+  /// Direction changes during the day, such ways are not used for routing
+  Reversible
+}
+
+impl Oneway {
+  fn parse(value: &[u8]) -> Option<Self> {
+    match value {
+      b"yes" | b"true" | b"1" => Some(Oneway::Forward),
+      b"-1" | b"reverse" => Some(Oneway::Backward),
+      b"no" | b"false" | b"0" => Some(Oneway::No),
+      b"reversible" | b"alternating" => Some(Oneway::Reversible),
+      _ => None
+    }
+  }
+
+  pub fn allows_forward(&self) -> bool {
+    matches!(self, Oneway::No | Oneway::Forward)
+  }
+
+  pub fn allows_backward(&self) -> bool {
+    matches!(self, Oneway::No | Oneway::Backward)
+  }
+}
+
+/// Restriction relation applying to cars, see <https://wiki.openstreetmap.org/wiki/Relation:restriction>.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restriction {
+  pub id: u64,
+  pub kind: RestrictionKind,
+  /// `from` ways, several ones are allowed for `no_entry`
+  pub from: Vec<u64>,
+  pub via: Via,
+  /// `to` ways, several ones are allowed for `no_exit`
+  pub to: Vec<u64>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum RestrictionKind {
+  /// `no_*`, the turn is forbidden
+  No,
+  /// `only_*`, the turn is the only allowed one
+  Only
+}
+
+impl RestrictionKind {
+  fn parse(value: &[u8]) -> Option<Self> {
+    if value.starts_with(b"no_") {
+      Some(RestrictionKind::No)
+    } else if value.starts_with(b"only_") {
+      Some(RestrictionKind::Only)
+    } else {
+      None
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Via {
+  Node(u64),
+  /// Chain of ways in the order of movement
+  Ways(Vec<u64>)
+}
+
+impl Restriction {
+  /// Turns covered by the restriction as osm node ids: the node before the via part,
+  /// via nodes and the node after them. Empty if restriction doesn't match the ways.
+  pub fn turns(&self, ways: &HashMap<u64, OsmWay>) -> Vec<Vec<u64>> {
+    let from: Vec<&OsmWay> = self.from.iter().filter_map(|id| ways.get(id)).collect();
+    let to: Vec<&OsmWay> = self.to.iter().filter_map(|id| ways.get(id)).collect();
+
+    let via_nodes = match &self.via {
+      Via::Node(id) => vec![*id],
+      Via::Ways(ids) => match via_chain(&from, ids, ways) {
+        Some(nodes) => nodes,
+        None => return Vec::new()
+      }
+    };
+
+    let first = via_nodes[0];
+    let last = *via_nodes.last().unwrap();
+    let mut turns = Vec::new();
+    for before in from.iter().flat_map(|w| neighbours(w, first)) {
+      for after in to.iter().flat_map(|w| neighbours(w, last)) {
+        let mut turn = Vec::with_capacity(via_nodes.len() + 2);
+        turn.push(before);
+        turn.extend_from_slice(&via_nodes);
+        turn.push(after);
+        turns.push(turn);
+      }
+    }
+
+    turns
+  }
+}
+
+/// Nodes of the via ways in the order of movement, starting at the node shared with `from` ways.
+fn via_chain(from: &[&OsmWay], via: &[u64], ways: &HashMap<u64, OsmWay>) -> Option<Vec<u64>> {
+  let first_way = ways.get(via.first()?)?;
+  let ends = [first_way.nodes.first()?.id, first_way.nodes.last()?.id];
+  let mut curr = *ends.iter().find(|id| from.iter().any(|w| w.nodes.iter().any(|n| n.id == **id)))?;
+
+  let mut nodes = vec![curr];
+  for id in via {
+    let way_nodes: Vec<u64> = ways.get(id)?.nodes.iter().map(|n| n.id).collect();
+    if way_nodes.first() == Some(&curr) {
+      nodes.extend_from_slice(&way_nodes[1..]);
+    } else if way_nodes.last() == Some(&curr) {
+      nodes.extend(way_nodes.iter().rev().skip(1));
+    } else {
+      return None;
+    }
+    curr = *nodes.last().unwrap();
+  }
+
+  Some(nodes)
+}
+
+/// Ids of the nodes adjacent to `node_id` along the way.
+fn neighbours(way: &OsmWay, node_id: u64) -> Vec<u64> {
+  let mut res = Vec::new();
+  for (i, n) in way.nodes.iter().enumerate() {
+    if n.id == node_id {
+      if i > 0 {
+        res.push(way.nodes[i - 1].id);
+      }
+      if let Some(next) = way.nodes.get(i + 1) {
+        res.push(next.id);
+      }
+    }
+  }
+
+  res
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum MemberType {
+  Node,
+  Way,
+  Relation
+}
+
+/// Collects members and tags of a relation, builds restriction if the relation is one.
+pub(crate) struct RelationBuilder {
+  id: u64,
+  is_restriction: bool,
+  /// restriction kind with the priority of the tag it came from
+  kind: Option<(u8, RestrictionKind)>,
+  /// `except` tag lists cars
+  except_cars: bool,
+  from: Vec<u64>,
+  via_nodes: Vec<u64>,
+  via_ways: Vec<u64>,
+  to: Vec<u64>
+}
+
+impl RelationBuilder {
+  pub fn new(id: u64) -> Self {
+    Self {
+      id,
+      is_restriction: false,
+      kind: None,
+      except_cars: false,
+      from: Vec::new(),
+      via_nodes: Vec::new(),
+      via_ways: Vec::new(),
+      to: Vec::new()
+    }
+  }
+
+  pub fn apply_tag(&mut self, key: &[u8], value: &[u8]) {
+    let priority = match key {
+      b"type" => {
+        self.is_restriction = value == b"restriction";
+        return;
+      },
+      b"except" => {
+        self.except_cars = value.split(|c| *c == b';').any(|v| v == b"motorcar" || v == b"motor_vehicle");
+        return;
+      },
+      b"restriction" => 0,
+      b"restriction:motor_vehicle" => 1,
+      b"restriction:motorcar" => 2,
+      _ => return
+    };
+
+    if let Some(kind) = RestrictionKind::parse(value) {
+      if self.kind.is_none_or(|(p, _)| p < priority) {
+        self.kind = Some((priority, kind));
+      }
+    }
+  }
+
+  pub fn add_member(&mut self, kind: MemberType, id: u64, role: &[u8]) {
+    match (kind, role) {
+      (MemberType::Way, b"from") => self.from.push(id),
+      (MemberType::Way, b"to") => self.to.push(id),
+      (MemberType::Way, b"via") => self.via_ways.push(id),
+      (MemberType::Node, b"via") => self.via_nodes.push(id),
+      _ => {}
+    }
+  }
+
+  /// Returns `None` if the relation is not a valid car restriction.
+  pub fn build(self) -> Option<Restriction> {
+    if !self.is_restriction || self.except_cars || self.from.is_empty() || self.to.is_empty() {
+      return None;
+    }
+
+    let via = match (self.via_nodes.as_slice(), self.via_ways.is_empty()) {
+      ([node], true) => Via::Node(*node),
+      ([], false) => Via::Ways(self.via_ways),
+      _ => return None
+    };
+
+    Some(Restriction {
+      id: self.id,
+      kind: self.kind?.1,
+      from: self.from,
+      via,
+      to: self.to
+    })
+  }
+}
+
+/// Loaded highways and their nodes by osm ids, and turn restrictions.
+#[derive(Default)]
+pub struct MapData {
+  pub nodes: HashMap<u64, OsmNode>,
+  pub ways: HashMap<u64, OsmWay>,
+  pub restrictions: Vec<Restriction>
+}
+
+/// Ids of nodes and ways created by clipping start from this value to not clash with osm ones.
+pub const CLIPPED_ID_START: u64 = 1 << 62;
+
+/// Region of a node inside of the area, never returned by [`Area::outcode`].
+const INSIDE: u8 = 1 << 4;
+
+/// Collects parsed elements dropping everything outside of the area if it's set.
+///
+/// Clipping needs coordinates of the nodes outside of the area referenced by the ways crossing
+/// its border, but ways come after nodes in osm files. So the file is read twice: the first pass
+/// of a [`MapBuilder::scanner`] only finds these nodes, the second one loads the map.
+pub(crate) struct MapBuilder {
+  data: MapData,
+  area: Option<Area>,
+  /// [`Area::outcode`] of every node or [`INSIDE`] for nodes inside of the area,
+  /// set only for the first pass
+  regions: Option<HashMap<u64, u8>>,
+  /// ids of nodes outside of the area referenced by ways crossing the border
+  border: HashSet<u64>,
+  /// coordinates of the `border` nodes
+  outside: HashMap<u64, (f64, f64)>,
+  /// ids of the parts of ways cut into several ones, except the first part keeping the way id
+  pieces: HashMap<u64, Vec<u64>>,
+  next_clipped_id: u64
+}
+
+impl MapBuilder {
+  pub fn new(area: Option<Area>) -> Self {
+    Self {
+      data: MapData::default(),
+      area,
+      regions: None,
+      border: HashSet::new(),
+      outside: HashMap::new(),
+      pieces: HashMap::new(),
+      next_clipped_id: CLIPPED_ID_START
+    }
+  }
+
+  /// Builder of the first pass, see [`MapBuilder::finish_scan`].
+  pub fn scanner(area: Area) -> Self {
+    Self { regions: Some(HashMap::new()), ..Self::new(Some(area)) }
+  }
+
+  /// Builder of the second pass keeping the border nodes found by the first one.
+  pub fn finish_scan(self) -> Self {
+    Self { border: self.border, ..Self::new(self.area) }
+  }
+
+  pub fn add_node(&mut self, node: OsmNode) {
+    let area = match self.area {
+      Some(ref area) => area,
+      None => {
+        self.data.nodes.insert(node.id, node);
+        return;
+      }
+    };
+    let is_inside = area.contains((node.lat, node.lon));
+    if let Some(ref mut regions) = self.regions {
+      regions.insert(node.id, if is_inside { INSIDE } else { area.outcode((node.lat, node.lon)) });
+    } else if is_inside {
+      self.data.nodes.insert(node.id, node);
+    } else if self.border.contains(&node.id) {
+      self.outside.insert(node.id, (node.lat, node.lon));
+    }
+  }
+
+  /// Adds highway consisting of `refs` nodes, parts of it outside of the area are cut off
+  /// and every part left inside becomes a separate way, ways crossing the area without nodes
+  /// inside of it are kept too.
+  /// Returns id of the first missing node as error.
+  pub fn add_way(&mut self, way: OsmWay, refs: &[u64]) -> Result<(), u64> {
+    if let Some(ref regions) = self.regions {
+      // Segments which may cross the area are checked exactly by the second pass
+      let region = |id: &u64| regions.get(id).copied();
+      let crosses = refs.iter().any(|id| region(id) == Some(INSIDE)) || refs.windows(2).any(|pair| {
+        matches!((region(&pair[0]), region(&pair[1])), (Some(a), Some(b)) if a & b == 0)
+      });
+      if crosses {
+        self.border.extend(refs.iter().filter(|id| region(id) != Some(INSIDE)));
+      }
+      return Ok(());
+    }
+
+    let area = match self.area.take() {
+      Some(area) => area,
+      None => {
+        let nodes = refs.iter()
+          .map(|id| self.data.nodes.get(id).cloned().ok_or(*id))
+          .collect::<Result<_, _>>()?;
+        self.insert_way(way, nodes);
+        return Ok(());
+      }
+    };
+    if !refs.iter().any(|id| self.data.nodes.contains_key(id)) && !refs.iter().all(|id| self.border.contains(id)) {
+      self.area = Some(area);
+      return Ok(());
+    }
+
+    let mut points = Vec::with_capacity(refs.len());
+    for id in refs {
+      let point = match (self.data.nodes.get(id), self.outside.get(id)) {
+        (Some(node), _) => ((node.lat, node.lon), Some(node.clone())),
+        (None, Some(coords)) => (*coords, None),
+        (None, None) => {
+          self.area = Some(area);
+          return Err(*id);
+        }
+      };
+      points.push(point);
+    }
+
+    let mut parts = Vec::new();
+    let mut part: Vec<OsmNode> = Vec::new();
+    for pair in points.windows(2) {
+      let ((p, ref p_node), (q, ref q_node)) = (pair[0].clone(), pair[1].clone());
+      let mut cuts = vec![0.0];
+      cuts.extend(area.crossings(p, q));
+      cuts.push(1.0);
+
+      for cut in cuts.windows(2) {
+        let at = |t: f64| (p.0 + (q.0 - p.0) * t, p.1 + (q.1 - p.1) * t);
+        if area.contains(at((cut[0] + cut[1]) / 2.0)) {
+          if part.is_empty() {
+            part.push(match p_node {
+              Some(node) if cut[0] == 0.0 => node.clone(),
+              _ => self.clipped_node(at(cut[0]))
+            });
+          }
+          part.push(match q_node {
+            Some(node) if cut[1] == 1.0 => node.clone(),
+            _ => self.clipped_node(at(cut[1]))
+          });
+        } else if !part.is_empty() {
+          parts.push(std::mem::take(&mut part));
+        }
+      }
+    }
+    parts.push(part);
+    self.area = Some(area);
+
+    let mut parts = parts.into_iter().filter(|p| p.len() >= 2);
+    if let Some(first) = parts.next() {
+      for other in parts {
+        let mut piece = OsmWay(Rc::new(InnerWay { id: self.next_id(), ..(*way).clone() }));
+        piece.nodes = other;
+        self.pieces.entry(way.id).or_default().push(piece.id);
+        self.insert_way(piece, Vec::new());
+      }
+      self.insert_way(way, first);
+    }
+
+    Ok(())
+  }
+
+  pub fn add_restriction(&mut self, restriction: Restriction) {
+    if self.regions.is_none() {
+      self.data.restrictions.push(restriction);
+    }
+  }
+
+  /// Returns loaded data, `from` and `to` ways of restrictions are extended by the parts of the cut ways.
+  /// Cut via ways are left as is, the restricted path leaves the area, so restriction doesn't match.
+  pub fn finish(mut self) -> MapData {
+    let pieces = &self.pieces;
+    let with_pieces = |ids: &mut Vec<u64>| {
+      let extra: Vec<u64> = ids.iter().filter_map(|id| pieces.get(id)).flatten().copied().collect();
+      ids.extend(extra);
+    };
+    for r in self.data.restrictions.iter_mut() {
+      with_pieces(&mut r.from);
+      with_pieces(&mut r.to);
+    }
+    self.data
+  }
+
+  fn insert_way(&mut self, mut way: OsmWay, nodes: Vec<OsmNode>) {
+    if !nodes.is_empty() {
+      way.nodes = nodes;
+    }
+    self.data.ways.insert(way.id, way);
+  }
+
+  /// Creates node on the area border.
+  fn clipped_node(&mut self, (lat, lon): (f64, f64)) -> OsmNode {
+    let node = OsmNode::new(self.next_id(), lat, lon);
+    self.data.nodes.insert(node.id, node.clone());
+    node
+  }
+
+  fn next_id(&mut self) -> u64 {
+    self.next_clipped_id += 1;
+    self.next_clipped_id - 1
+  }
 }
 
-pub fn load(path: String) -> (HashMap<u64, OsmNode>, HashMap<u64, OsmWay>) {
-  let reader = BufReader::new(GzDecoder::new(BufReader::new(File::open(path).unwrap())));
+enum MapFormat {
+  Pbf,
+  GzipXml,
+  Xml
+}
+
+/// Loads highways, their nodes and turn restrictions from an OSM file.
+/// Both `.osm.pbf` and (gzipped) OSM XML are supported, format is detected by the file content
+/// falling back to the extension.
+/// If `area` is set, only the part of the map inside it is loaded, the file is read twice then.
+pub fn load(path: String, area: Option<Area>) -> Result<MapData, MapLoadError> {
+  let mut builder = match area {
+    Some(area) => {
+      let mut scanner = MapBuilder::scanner(area);
+      read(&path, &mut scanner)?;
+      scanner.finish_scan()
+    },
+    None => MapBuilder::new(None)
+  };
+  read(&path, &mut builder)?;
+
+  let mut data = builder.finish();
+  data.nodes.retain(|_, v| Rc::strong_count(&v.0) > 1);
+  Ok(data)
+}
+
+fn read(path: &str, builder: &mut MapBuilder) -> Result<(), MapLoadError> {
+  let mut reader = BufReader::new(File::open(path)?);
+  let head = reader.fill_buf()?;
+  let format = if crate::pbf::is_pbf(head) || path.ends_with(".pbf") {
+    MapFormat::Pbf
+  } else if head.starts_with(&GZIP_MAGIC) {
+    MapFormat::GzipXml
+  } else {
+    MapFormat::Xml
+  };
+
+  match format {
+    MapFormat::Pbf => crate::pbf::load(reader, builder),
+    MapFormat::GzipXml => load_xml(BufReader::new(GzDecoder::new(reader)), true, builder),
+    MapFormat::Xml => load_xml(reader, false, builder)
+  }
+}
+
+/// Applies way tag to `way`, returns true if the tag marks way as a highway.
+pub(crate) fn apply_way_tag(way: &mut OsmWay, key: &[u8], value: &[u8]) -> bool {
+  match key {
+    b"highway" => {
+      way.highway = String::from_utf8_lossy(value).into_owned();
+      return true;
+    },
+    b"maxspeed" => way.maxspeed = parse_maxspeed(&String::from_utf8_lossy(value)),
+    b"maxspeed:type" | b"source:maxspeed" => {
+      if way.maxspeed.is_none() {
+        way.maxspeed = parse_maxspeed(&String::from_utf8_lossy(value));
+      }
+    },
+    b"oneway" => way.oneway = Oneway::parse(value),
+    b"oneway:motor_vehicle" | b"oneway:motorcar" => way.car_oneway = Oneway::parse(value),
+    b"oneway:foot" => way.foot_oneway = Oneway::parse(value).unwrap_or(Oneway::No),
+    b"junction" => way.roundabout = value == b"roundabout" || value == b"circular",
+    _ => {
+      if let Some(tag) = ROUTING_TAGS.iter().find(|t| t.as_bytes() == key) {
+        way.tags.push((tag.to_string(), String::from_utf8_lossy(value).into_owned()));
+      }
+    }
+  }
+
+  false
+}
+
+fn load_xml<R: BufRead>(reader: R, compressed: bool, builder: &mut MapBuilder) -> Result<(), MapLoadError> {
   let mut event_reader = Reader::from_reader(reader);
   let mut buf = Vec::new();
-  let mut nodes = HashMap::new();
-  let mut ways = HashMap::new();
 
+  let mut current_relation: Option<RelationBuilder> = None;
   let mut current_way: Option<OsmWay> = None;
   let mut is_current_way_highway = false;
+  let mut current_way_refs = Vec::new();
   loop {
+    let offset = event_reader.buffer_position() as u64;
     match event_reader.read_event(&mut buf) {
       Ok(Event::Start(ref e)) => {
         match e.name() {
-          b"node" => {
-            let node = handle_node(e);
-            nodes.insert(node.id, node);
-          },
+          b"node" => builder.add_node(handle_node(e, offset)?),
           b"way" => {
-            let mut id = 0u64;
-            if let Some(res) = e.attributes().find(|v| {
-              v.as_ref().map_or(false, |vv| vv.key == b"id")
-            }) {
-              id = u64_parse(res.unwrap().value.as_ref());
-            }
-
+            let id = element_id(&event_reader, e, offset, compressed)?;
             current_way = Some(OsmWay::new(id));
-          }
+            is_current_way_highway = false;
+            current_way_refs.clear();
+          },
+          b"relation" => {
+            let id = element_id(&event_reader, e, offset, compressed)?;
+            current_relation = Some(RelationBuilder::new(id));
+          },
           _ => {}
         }
       },
       Ok(Event::End(ref e)) => {
-        if e.name() == b"way" {
-          if let Some(way) = current_way.take() {
-            if is_current_way_highway {
-              ways.insert(way.id, way);
-              is_current_way_highway = false;
+        match e.name() {
+          b"way" => {
+            if let Some(way) = current_way.take() {
+              if is_current_way_highway {
+                let way_id = way.id;
+                builder.add_way(way, &current_way_refs)
+                  .map_err(|node_id| MapLoadError::MissingNode { way_id, node_id })?;
+              }
             }
-          }
+          },
+          b"relation" => {
+            if let Some(restriction) = current_relation.take().and_then(RelationBuilder::build) {
+              builder.add_restriction(restriction);
+            }
+          },
+          _ => {}
         }
       },
       Ok(Event::Empty(ref e)) => {
         match e.name() {
-          b"node" => {
-            let node = handle_node(e);
-            nodes.insert(node.id, node);
-          },
+          b"node" => builder.add_node(handle_node(e, offset)?),
           b"nd" => {
-            let nd_ref =
-                e.attributes().find(|a| a.as_ref().map_or(false, |a| a.key == b"ref"))
-                    .map(|v| u64_parse(v.unwrap().value.as_ref()));
-            if let Some(ref nd_id) = nd_ref {
-              if let Some(node) = nodes.get(nd_id) {
-                if let Some(w) = current_way.as_mut() { w.nodes.push(node.clone()) }
+            if let Some(w) = current_way.as_mut() {
+              for attr in e.attributes() {
+                let a = attr.map_err(|source| xml_error(&event_reader, source, compressed))?;
+                if a.key == b"ref" {
+                  let nd_id = u64_parse(a.value.as_ref())
+                    .ok_or_else(|| MapLoadError::bad_attribute(offset, Some(w.id), a.key, a.value.as_ref()))?;
+                  current_way_refs.push(nd_id);
+                }
               }
             }
           },
-          b"tag" => {
-            if let Some(ref mut way) = current_way {
-              let mut is_current_tag_highway = false;
-              e.attributes().for_each(|attr| {
-                attr.map(|a| {
-                  match a.key {
-                    b"k" => { is_current_tag_highway = a.value.as_ref() == b"highway" },
-                    b"v" => {
-                      if is_current_tag_highway {
-                        is_current_way_highway = true;
-
-                        way.road_kind = TransportKind::from(std::str::from_utf8(a.value.as_ref()).unwrap());
-                      }
-                    },
-                    _ => {}
-                  }
-                }).unwrap();
-              });
+          b"member" => {
+            if let Some(ref mut relation) = current_relation {
+              let mut kind = None;
+              let mut id = None;
+              let mut role = Cow::Borrowed(&b""[..]);
+              for attr in e.attributes() {
+                let a = attr.map_err(|source| xml_error(&event_reader, source, compressed))?;
+                match a.key {
+                  b"type" => kind = match a.value.as_ref() {
+                    b"node" => Some(MemberType::Node),
+                    b"way" => Some(MemberType::Way),
+                    b"relation" => Some(MemberType::Relation),
+                    _ => return Err(MapLoadError::bad_attribute(offset, Some(relation.id), a.key, a.value.as_ref()))
+                  },
+                  b"ref" => id = Some(u64_parse(a.value.as_ref())
+                    .ok_or_else(|| MapLoadError::bad_attribute(offset, Some(relation.id), a.key, a.value.as_ref()))?),
+                  b"role" => role = a.value,
+                  _ => {}
+                }
+              }
+              if let (Some(kind), Some(id)) = (kind, id) {
+                relation.add_member(kind, id, role.as_ref());
+              }
             }
-          }
+          },
+          b"tag" if current_way.is_some() || current_relation.is_some() => {
+            let mut key = None;
+            let mut value = None;
+            for attr in e.attributes() {
+              let a = attr.map_err(|source| xml_error(&event_reader, source, compressed))?;
+              match a.key {
+                b"k" => key = Some(a.value),
+                b"v" => value = Some(a.value),
+                _ => {}
+              }
+            }
+            if let (Some(k), Some(v)) = (key, value) {
+              if let Some(ref mut way) = current_way {
+                is_current_way_highway |= apply_way_tag(way, k.as_ref(), v.as_ref());
+              } else if let Some(ref mut relation) = current_relation {
+                relation.apply_tag(k.as_ref(), v.as_ref());
+              }
+            }
+          },
           _ => {}
         }
       },
       Ok(Event::Eof) => break,
-      Err(e) => panic!("{:?}", e),
+      Err(source) => return Err(xml_error(&event_reader, source, compressed)),
       _ => {}
     }
+    buf.clear();
   }
-  nodes.retain(|_, v| Rc::strong_count(&v.0) > 1);
-  // self.ways = ways;
-  // let cnt = self.nodes.values().filter(|v| Rc::strong_count(&v.0) == 2).count();
-  // println!("useless nodes: {}/{}", cnt, self.nodes.len());
-  // self.build_graph();
-  (nodes, ways)
-}
-
-fn handle_node(e: &BytesStart) -> OsmNode {
-  let mut id = 0; let mut lat = 0.0; let mut lon = 0.0;
-  e.attributes().for_each(|v| {
-    if let Ok(a) = v {
-      match a.key {
-        b"id" => {
-          id = u64_parse(a.value.as_ref());
-        },
-        b"lat" => {
-          lat = f64_parse(a.value.as_ref());
-        },
-        b"lon" => {
-          lon = f64_parse(a.value.as_ref());
-        },
-        _ => {}
-      }
+
+  Ok(())
+}
+
+/// Reads `id` attribute of way or relation element.
+fn element_id<R: BufRead>(reader: &Reader<R>, e: &BytesStart, offset: u64, compressed: bool) -> Result<u64, MapLoadError> {
+  for attr in e.attributes() {
+    let a = attr.map_err(|source| xml_error(reader, source, compressed))?;
+    if a.key == b"id" {
+      return u64_parse(a.value.as_ref())
+        .ok_or_else(|| MapLoadError::bad_attribute(offset, None, a.key, a.value.as_ref()));
+    }
+  }
+
+  Err(MapLoadError::bad_attribute(offset, None, b"id", b""))
+}
+
+/// Wraps xml error, io errors from gzip stream are reported as decompression errors.
+fn xml_error<R: BufRead>(reader: &Reader<R>, source: quick_xml::Error, compressed: bool) -> MapLoadError {
+  let offset = reader.buffer_position();
+  match source {
+    quick_xml::Error::Io(e) if compressed && matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::InvalidInput) =>
+      MapLoadError::Decompression { offset: offset as u64, source: e },
+    quick_xml::Error::Io(e) => MapLoadError::Io(e),
+    source => MapLoadError::Xml { offset, source }
+  }
+}
+
+fn handle_node(e: &BytesStart, offset: u64) -> Result<OsmNode, MapLoadError> {
+  let mut id = None; let mut lat = None; let mut lon = None;
+  for attr in e.attributes() {
+    let a = attr.map_err(|source| MapLoadError::Xml { offset: offset as usize, source })?;
+    match a.key {
+      b"id" => {
+        id = Some(u64_parse(a.value.as_ref()).ok_or_else(|| MapLoadError::bad_attribute(offset, None, a.key, a.value.as_ref()))?);
+      },
+      b"lat" => lat = Some(a.value),
+      b"lon" => lon = Some(a.value),
+      _ => {}
     }
-  });
+  }
+
+  let parse_coord = |name: &[u8], value: Option<Cow<[u8]>>| {
+    let value = value.unwrap_or_default();
+    f64_parse(value.as_ref()).ok_or_else(|| MapLoadError::bad_attribute(offset, id, name, value.as_ref()))
+  };
+  let lat = parse_coord(b"lat", lat)?;
+  let lon = parse_coord(b"lon", lon)?;
+  let id = id.ok_or_else(|| MapLoadError::bad_attribute(offset, None, b"id", b""))?;
+
+  Ok(OsmNode::new(id, lat, lon))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Reads the xml the same way as [`load`] does, returns the builder of the last pass.
+  fn read_xml(xml: &str, area: Option<Area>) -> Result<MapBuilder, MapLoadError> {
+    let mut builder = match area {
+      Some(area) => {
+        let mut scanner = MapBuilder::scanner(area);
+        load_xml(xml.as_bytes(), false, &mut scanner)?;
+        scanner.finish_scan()
+      },
+      None => MapBuilder::new(None)
+    };
+    load_xml(xml.as_bytes(), false, &mut builder)?;
+    Ok(builder)
+  }
 
-  OsmNode::new(id, lat, lon)
+  const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+ <node id="1" lat="55.75" lon="37.61"/>
+ <node id="2" lat="55.76" lon="37.62"/>
+ <way id="10">
+  <nd ref="1"/>
+  <nd ref="2"/>
+  <tag k="highway" v="residential"/>
+ </way>
+</osm>"#;
+
+  #[test]
+  fn loads_xml() {
+    let data = read_xml(XML, None).unwrap().finish();
+    assert_eq!(data.nodes.len(), 2);
+    assert_eq!(data.ways[&10].nodes.len(), 2);
+  }
+
+  #[test]
+  fn reports_bad_attribute() {
+    let xml = XML.replace(r#"lat="55.76""#, r#"lat="55,76""#);
+    match read_xml(&xml, None) {
+      Err(MapLoadError::BadAttribute { element_id: Some(2), name, .. }) => assert_eq!(name, "lat"),
+      r => panic!("unexpected result {:?}", r.map(|_| ()))
+    }
+  }
+
+  #[test]
+  fn reports_missing_node() {
+    let xml = XML.replace(r#"<nd ref="2"/>"#, r#"<nd ref="3"/>"#);
+    match read_xml(&xml, None) {
+      Err(MapLoadError::MissingNode { way_id: 10, node_id: 3 }) => {},
+      r => panic!("unexpected result {:?}", r.map(|_| ()))
+    }
+  }
+
+  const RESTRICTION_XML: &str = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.601"/>
+ <node id="3" lat="55.750" lon="37.602"/>
+ <node id="4" lat="55.751" lon="37.602"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+ <way id="11"><nd ref="3"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+ <way id="12"><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <relation id="100">
+  <member type="way" ref="10" role="from"/>
+  <member type="way" ref="11" role="via"/>
+  <member type="way" ref="12" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="no_left_turn"/>
+ </relation>
+ <relation id="101">
+  <member type="way" ref="10" role="from"/>
+  <member type="node" ref="2" role="via"/>
+  <member type="way" ref="11" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="only_straight_on"/>
+  <tag k="except" v="bicycle;motorcar"/>
+ </relation>
+ <relation id="102">
+  <member type="way" ref="12" role="from"/>
+  <member type="node" ref="3" role="via"/>
+  <member type="way" ref="11" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="only_right_turn"/>
+  <tag k="restriction:motorcar" v="no_right_turn"/>
+ </relation>
+</osm>"#;
+
+  #[test]
+  fn loads_restrictions() {
+    let data = read_xml(RESTRICTION_XML, None).unwrap().finish();
+    // 101 doesn't apply to cars
+    assert_eq!(data.restrictions.len(), 2);
+
+    let via_way = &data.restrictions[0];
+    assert_eq!(via_way.kind, RestrictionKind::No);
+    assert_eq!(via_way.via, Via::Ways(vec![11]));
+    assert_eq!(via_way.turns(&data.ways), vec![vec![1, 2, 3, 4]]);
+
+    let via_node = &data.restrictions[1];
+    assert_eq!(via_node.kind, RestrictionKind::No);
+    assert_eq!(via_node.via, Via::Node(3));
+    assert_eq!(via_node.turns(&data.ways), vec![vec![4, 3, 2]]);
+  }
+
+  const CLIP_XML: &str = r#"<osm version="0.6">
+ <node id="1" lat="55.750" lon="37.600"/>
+ <node id="2" lat="55.750" lon="37.602"/>
+ <node id="3" lat="55.750" lon="37.604"/>
+ <node id="4" lat="55.750" lon="37.606"/>
+ <node id="5" lat="55.760" lon="37.600"/>
+ <node id="6" lat="55.7505" lon="37.606"/>
+ <node id="7" lat="55.760" lon="37.610"/>
+ <node id="8" lat="55.740" lon="37.600"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+ <way id="11"><nd ref="5"/><nd ref="1"/><tag k="highway" v="residential"/></way>
+ <way id="12"><nd ref="4"/><nd ref="6"/><tag k="highway" v="residential"/></way>
+ <way id="13"><nd ref="5"/><nd ref="7"/><tag k="highway" v="residential"/></way>
+ <relation id="100">
+  <member type="way" ref="10" role="from"/>
+  <member type="node" ref="4" role="via"/>
+  <member type="way" ref="12" role="to"/>
+  <tag k="type" v="restriction"/>
+  <tag k="restriction" v="no_left_turn"/>
+ </relation>
+</osm>"#;
+
+  /// The hole covers node 3, so way 10 is cut into two parts
+  const HOLE_POLY: &str = "area\n1\n 37.601 55.749\n 37.6065 55.749\n 37.6065 55.751\n 37.601 55.751\nEND\n!2\n 37.603 55.749\n 37.605 55.749\n 37.605 55.751\n 37.603 55.751\nEND\nEND\n";
+
+  #[test]
+  fn clips_ways_by_area() {
+    let data = read_xml(CLIP_XML, Some(Area::from_poly(HOLE_POLY).unwrap())).unwrap().finish();
+
+    assert!(!data.nodes.contains_key(&1) && !data.nodes.contains_key(&3));
+    assert!(!data.ways.contains_key(&11) && !data.ways.contains_key(&13));
+    assert_eq!(data.ways.len(), 3);
+
+    let coords = |w: &OsmWay| w.nodes.iter().map(|n| ((n.lon * 1e4).round() / 1e4, n.id < CLIPPED_ID_START)).collect::<Vec<_>>();
+    assert_eq!(coords(&data.ways[&10]), vec![(37.601, false), (37.602, true), (37.603, false)]);
+    let other = data.ways.values().find(|w| w.id != 10 && w.id != 12).unwrap();
+    assert!(other.id >= CLIPPED_ID_START);
+    assert_eq!(coords(other), vec![(37.605, false), (37.606, true)]);
+  }
+
+  #[test]
+  fn keeps_only_border_nodes_outside_of_area() {
+    let builder = read_xml(CLIP_XML, Some(Area::from_poly(HOLE_POLY).unwrap())).unwrap();
+    // 5, 7 and 8 are not referenced by ways crossing the border
+    let mut outside: Vec<u64> = builder.outside.keys().copied().collect();
+    outside.sort_unstable();
+    assert_eq!(outside, vec![1, 3]);
+  }
+
+  #[test]
+  fn restrictions_apply_to_all_parts_of_cut_ways() {
+    let data = read_xml(CLIP_XML, Some(Area::from_poly(HOLE_POLY).unwrap())).unwrap().finish();
+    let piece = data.ways.values().find(|w| w.id >= CLIPPED_ID_START).unwrap();
+
+    assert_eq!(data.restrictions.len(), 1);
+    assert_eq!(data.restrictions[0].from, vec![10, piece.id]);
+    // only the second part of way 10 reaches the via node
+    assert_eq!(data.restrictions[0].turns(&data.ways), vec![vec![piece.nodes[0].id, 4, 6]]);
+  }
+
+  #[test]
+  fn keeps_ways_crossing_area_without_inside_nodes() {
+    let xml = r#"<osm version="0.6">
+ <node id="1" lat="55.745" lon="37.602"/>
+ <node id="2" lat="55.755" lon="37.602"/>
+ <node id="3" lat="55.745" lon="37.610"/>
+ <node id="4" lat="55.745" lon="37.600"/>
+ <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="motorway"/></way>
+ <way id="11"><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+</osm>"#;
+    let builder = read_xml(xml, Some(Area::from_poly(HOLE_POLY).unwrap())).unwrap();
+    // both ends of way 11 are below the area
+    let mut outside: Vec<u64> = builder.outside.keys().copied().collect();
+    outside.sort_unstable();
+    assert_eq!(outside, vec![1, 2]);
+
+    let data = builder.finish();
+    assert_eq!(data.ways.len(), 1);
+    let lats: Vec<f64> = data.ways[&10].nodes.iter().map(|n| (n.lat * 1e4).round() / 1e4).collect();
+    assert_eq!(lats, vec![55.749, 55.751]);
+    assert!(data.ways[&10].nodes.iter().all(|n| n.id >= CLIPPED_ID_START));
+  }
 }
@@ -0,0 +1,658 @@
+//! Minimal `.osm.pbf` reader.
+//!
+//! Only what the map service needs is decoded: plain and dense nodes, ways with their
+//! node refs and tags, turn restriction relations. Node tags and metadata are skipped.
+use std::io::{self, Read};
+use flate2::read::ZlibDecoder;
+use crate::osm_map::{OsmNode, OsmWay, MapBuilder, RelationBuilder, MemberType, Restriction, apply_way_tag};
+use crate::error::MapLoadError;
+
+/// Blob headers larger than this are considered broken (spec says 32 KiB max).
+const MAX_BLOB_HEADER_SIZE: u32 = 64 * 1024;
+/// Blobs larger than this are considered broken (spec says 32 MiB max).
+const MAX_BLOB_SIZE: usize = 64 * 1024 * 1024;
+
+/// Checks if `head` looks like the beginning of a pbf file,
+/// i.e. the first blob header has type `OSMHeader`.
+pub fn is_pbf(head: &[u8]) -> bool {
+  head.len() >= 15 && head[4] == 0x0A && head[5] == 9 && &head[6..15] == b"OSMHeader"
+}
+
+/// Reads the file passing its elements to `builder`.
+pub(crate) fn load<R: Read>(mut reader: R, builder: &mut MapBuilder) -> Result<(), MapLoadError> {
+
+  let mut header_buf = Vec::new();
+  let mut blob_buf = Vec::new();
+  let mut data_buf = Vec::new();
+  let mut offset = 0u64;
+  while let Some(header_len) = read_header_len(&mut reader).map_err(|e| read_error(e, offset))? {
+    let res = (|| {
+      if header_len > MAX_BLOB_HEADER_SIZE {
+        return Err(invalid_data("blob header is too large"));
+      }
+      header_buf.resize(header_len as usize, 0);
+      reader.read_exact(&mut header_buf)?;
+      let header = BlobHeader::parse(&header_buf)?;
+
+      if header.data_size > MAX_BLOB_SIZE {
+        return Err(invalid_data("blob is too large"));
+      }
+      blob_buf.resize(header.data_size, 0);
+      reader.read_exact(&mut blob_buf)?;
+      let blob = read_blob(&blob_buf, &mut data_buf)?;
+
+      match header.kind {
+        b"OSMHeader" => check_header_block(blob),
+        b"OSMData" => read_primitive_block(blob, builder),
+        _ => Ok(())
+      }
+    })();
+    res.map_err(|e| e.at(offset))?;
+
+    offset += 4 + header_len as u64 + blob_buf.len() as u64;
+  }
+
+  Ok(())
+}
+
+enum ParseError {
+  Io(io::Error),
+  Decompression(io::Error),
+  Malformed(String),
+  MissingNode {
+    way_id: u64,
+    node_id: u64
+  }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+impl ParseError {
+  /// Converts to [`MapLoadError`] adding offset of the blob where error happened.
+  fn at(self, offset: u64) -> MapLoadError {
+    match self {
+      ParseError::Io(e) => read_error(e, offset),
+      ParseError::Decompression(source) => MapLoadError::Decompression { offset, source },
+      ParseError::Malformed(reason) => MapLoadError::Pbf { offset, reason },
+      ParseError::MissingNode { way_id, node_id } => MapLoadError::MissingNode { way_id, node_id }
+    }
+  }
+}
+
+impl From<io::Error> for ParseError {
+  fn from(e: io::Error) -> Self {
+    ParseError::Io(e)
+  }
+}
+
+fn read_error(e: io::Error, offset: u64) -> MapLoadError {
+  if e.kind() == io::ErrorKind::UnexpectedEof {
+    MapLoadError::Pbf { offset, reason: "unexpected end of file".to_string() }
+  } else {
+    MapLoadError::Io(e)
+  }
+}
+
+fn read_header_len<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+  let mut buf = [0u8; 4];
+  let mut read = 0;
+  while read < buf.len() {
+    match reader.read(&mut buf[read..]) {
+      Ok(0) if read == 0 => return Ok(None),
+      Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated blob header length")),
+      Ok(n) => read += n,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+      Err(e) => return Err(e)
+    }
+  }
+
+  Ok(Some(u32::from_be_bytes(buf)))
+}
+
+struct BlobHeader<'a> {
+  kind: &'a [u8],
+  data_size: usize
+}
+
+impl<'a> BlobHeader<'a> {
+  fn parse(buf: &'a [u8]) -> ParseResult<Self> {
+    let mut kind: &[u8] = &[];
+    let mut data_size = 0;
+    for field in Message::new(buf) {
+      match field? {
+        (1, Value::Bytes(b)) => kind = b,
+        (3, Value::Varint(v)) => data_size = v as usize,
+        _ => {}
+      }
+    }
+
+    Ok(Self { kind, data_size })
+  }
+}
+
+/// Returns uncompressed blob content, `data_buf` is used as storage for zlib blobs.
+fn read_blob<'a>(buf: &'a [u8], data_buf: &'a mut Vec<u8>) -> ParseResult<&'a [u8]> {
+  let mut raw = None;
+  let mut zlib = None;
+  let mut raw_size = 0;
+  for field in Message::new(buf) {
+    match field? {
+      (1, Value::Bytes(b)) => raw = Some(b),
+      (2, Value::Varint(v)) => raw_size = v as usize,
+      (3, Value::Bytes(b)) => zlib = Some(b),
+      (4, _) | (5, _) | (6, _) | (7, _) => {
+        return Err(invalid_data("unsupported blob compression"));
+      },
+      _ => {}
+    }
+  }
+
+  if let Some(raw) = raw {
+    Ok(raw)
+  } else if let Some(zlib) = zlib {
+    if raw_size > MAX_BLOB_SIZE {
+      return Err(invalid_data("blob is too large"));
+    }
+    data_buf.clear();
+    data_buf.reserve(raw_size);
+    // The declared size can't be trusted, so the output is bounded by it too.
+    ZlibDecoder::new(zlib).take(raw_size as u64 + 1).read_to_end(data_buf).map_err(ParseError::Decompression)?;
+    if data_buf.len() != raw_size {
+      return Err(invalid_data("blob size differs from declared"));
+    }
+    Ok(data_buf.as_slice())
+  } else {
+    Err(invalid_data("blob has no data"))
+  }
+}
+
+fn check_header_block(buf: &[u8]) -> ParseResult<()> {
+  for field in Message::new(buf) {
+    // required_features
+    if let (4, Value::Bytes(feature)) = field? {
+      match feature {
+        b"OsmSchema-V0.6" | b"DenseNodes" => {},
+        _ => return Err(invalid_data(&format!("unsupported pbf feature {}", String::from_utf8_lossy(feature))))
+      }
+    }
+  }
+
+  Ok(())
+}
+
+struct BlockParams {
+  granularity: i64,
+  lat_offset: i64,
+  lon_offset: i64
+}
+
+impl BlockParams {
+  fn lat(&self, v: i64) -> f64 {
+    (self.lat_offset + self.granularity * v) as f64 * 1e-9
+  }
+
+  fn lon(&self, v: i64) -> f64 {
+    (self.lon_offset + self.granularity * v) as f64 * 1e-9
+  }
+}
+
+fn read_primitive_block(buf: &[u8], builder: &mut MapBuilder) -> ParseResult<()> {
+  let mut strings = Vec::new();
+  let mut groups = Vec::new();
+  let mut params = BlockParams { granularity: 100, lat_offset: 0, lon_offset: 0 };
+  for field in Message::new(buf) {
+    match field? {
+      (1, Value::Bytes(b)) => {
+        for s in Message::new(b) {
+          if let (1, Value::Bytes(s)) = s? {
+            strings.push(s);
+          }
+        }
+      },
+      (2, Value::Bytes(b)) => groups.push(b),
+      (17, Value::Varint(v)) => params.granularity = v as i64,
+      (19, Value::Varint(v)) => params.lat_offset = v as i64,
+      (20, Value::Varint(v)) => params.lon_offset = v as i64,
+      _ => {}
+    }
+  }
+
+  for group in groups {
+    for field in Message::new(group) {
+      match field? {
+        (1, Value::Bytes(b)) => builder.add_node(read_node(b, &params)?),
+        (2, Value::Bytes(b)) => read_dense_nodes(b, &params, builder)?,
+        (3, Value::Bytes(b)) => {
+          if let Some((way, refs)) = read_way(b, &strings)? {
+            let way_id = way.id;
+            builder.add_way(way, &refs).map_err(|node_id| ParseError::MissingNode { way_id, node_id })?;
+          }
+        },
+        (4, Value::Bytes(b)) => {
+          if let Some(restriction) = read_relation(b, &strings)? {
+            builder.add_restriction(restriction);
+          }
+        },
+        _ => {}
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn read_node(buf: &[u8], params: &BlockParams) -> ParseResult<OsmNode> {
+  let mut id = 0; let mut lat = 0; let mut lon = 0;
+  for field in Message::new(buf) {
+    match field? {
+      (1, Value::Varint(v)) => id = zigzag(v),
+      (8, Value::Varint(v)) => lat = zigzag(v),
+      (9, Value::Varint(v)) => lon = zigzag(v),
+      _ => {}
+    }
+  }
+
+  Ok(OsmNode::new(id as u64, params.lat(lat), params.lon(lon)))
+}
+
+fn read_dense_nodes(buf: &[u8], params: &BlockParams, builder: &mut MapBuilder) -> ParseResult<()> {
+  let mut ids: &[u8] = &[];
+  let mut lats: &[u8] = &[];
+  let mut lons: &[u8] = &[];
+  for field in Message::new(buf) {
+    match field? {
+      (1, Value::Bytes(b)) => ids = b,
+      (8, Value::Bytes(b)) => lats = b,
+      (9, Value::Bytes(b)) => lons = b,
+      _ => {}
+    }
+  }
+
+  let (mut id, mut lat, mut lon) = (0i64, 0i64, 0i64);
+  let mut lats = Packed::new(lats);
+  let mut lons = Packed::new(lons);
+  for id_delta in Packed::new(ids) {
+    let (lat_delta, lon_delta) = match (lats.next(), lons.next()) {
+      (Some(lat), Some(lon)) => (lat?, lon?),
+      _ => return Err(invalid_data("dense nodes arrays have different length"))
+    };
+    id += zigzag(id_delta?);
+    lat += zigzag(lat_delta);
+    lon += zigzag(lon_delta);
+
+    builder.add_node(OsmNode::new(id as u64, params.lat(lat), params.lon(lon)));
+  }
+
+  Ok(())
+}
+
+/// Returns way with its node refs or `None` if way is not a highway.
+fn read_way(buf: &[u8], strings: &[&[u8]]) -> ParseResult<Option<(OsmWay, Vec<u64>)>> {
+  let mut id = 0;
+  let mut keys: &[u8] = &[];
+  let mut vals: &[u8] = &[];
+  let mut refs: &[u8] = &[];
+  for field in Message::new(buf) {
+    match field? {
+      (1, Value::Varint(v)) => id = v,
+      (2, Value::Bytes(b)) => keys = b,
+      (3, Value::Bytes(b)) => vals = b,
+      (8, Value::Bytes(b)) => refs = b,
+      _ => {}
+    }
+  }
+
+  let mut way = OsmWay::new(id);
+  let mut is_highway = false;
+  for (k, v) in Packed::new(keys).zip(Packed::new(vals)) {
+    is_highway |= apply_way_tag(&mut way, string(strings, k?)?, string(strings, v?)?);
+  }
+  if !is_highway {
+    return Ok(None);
+  }
+
+  let mut node_id = 0i64;
+  let refs = Packed::new(refs)
+    .map(|delta| {
+      node_id += zigzag(delta?);
+      Ok(node_id as u64)
+    })
+    .collect::<ParseResult<_>>()?;
+
+  Ok(Some((way, refs)))
+}
+
+/// Returns `None` if relation is not a car turn restriction.
+fn read_relation(buf: &[u8], strings: &[&[u8]]) -> ParseResult<Option<Restriction>> {
+  let mut id = 0;
+  let mut keys: &[u8] = &[];
+  let mut vals: &[u8] = &[];
+  let mut roles: &[u8] = &[];
+  let mut member_ids: &[u8] = &[];
+  let mut types: &[u8] = &[];
+  for field in Message::new(buf) {
+    match field? {
+      (1, Value::Varint(v)) => id = v,
+      (2, Value::Bytes(b)) => keys = b,
+      (3, Value::Bytes(b)) => vals = b,
+      (8, Value::Bytes(b)) => roles = b,
+      (9, Value::Bytes(b)) => member_ids = b,
+      (10, Value::Bytes(b)) => types = b,
+      _ => {}
+    }
+  }
+
+  let mut relation = RelationBuilder::new(id);
+  for (k, v) in Packed::new(keys).zip(Packed::new(vals)) {
+    relation.apply_tag(string(strings, k?)?, string(strings, v?)?);
+  }
+
+  let mut member_id = 0i64;
+  let mut roles = Packed::new(roles);
+  let mut types = Packed::new(types);
+  for delta in Packed::new(member_ids) {
+    let (role, kind) = match (roles.next(), types.next()) {
+      (Some(role), Some(kind)) => (role?, kind?),
+      _ => return Err(invalid_data("relation member arrays have different length"))
+    };
+    member_id += zigzag(delta?);
+    let kind = match kind {
+      0 => MemberType::Node,
+      1 => MemberType::Way,
+      2 => MemberType::Relation,
+      _ => return Err(invalid_data("unknown relation member type"))
+    };
+    relation.add_member(kind, member_id as u64, string(strings, role)?);
+  }
+
+  Ok(relation.build())
+}
+
+fn string<'a>(strings: &[&'a [u8]], index: u64) -> ParseResult<&'a [u8]> {
+  strings.get(index as usize).copied().ok_or_else(|| invalid_data("string index out of bounds"))
+}
+
+fn invalid_data(msg: &str) -> ParseError {
+  ParseError::Malformed(msg.to_string())
+}
+
+fn zigzag(v: u64) -> i64 {
+  ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> ParseResult<u64> {
+  let mut res = 0u64;
+  for shift in (0..64).step_by(7) {
+    let b = *buf.get(*pos).ok_or_else(|| invalid_data("truncated varint"))?;
+    *pos += 1;
+    res |= ((b & 0x7F) as u64) << shift;
+    if b & 0x80 == 0 {
+      return Ok(res);
+    }
+  }
+
+  Err(invalid_data("varint is too long"))
+}
+
+enum Value<'a> {
+  Varint(u64),
+  Bytes(&'a [u8]),
+  /// fixed32 and fixed64 values, never used by osm so only skipped
+  Fixed
+}
+
+/// Iterator over protobuf message fields as `(field number, value)`.
+struct Message<'a> {
+  buf: &'a [u8],
+  pos: usize
+}
+
+impl<'a> Message<'a> {
+  fn new(buf: &'a [u8]) -> Self {
+    Self { buf, pos: 0 }
+  }
+
+  fn read_field(&mut self) -> ParseResult<(u32, Value<'a>)> {
+    let key = read_varint(self.buf, &mut self.pos)?;
+    let value = match key & 0x7 {
+      0 => Value::Varint(read_varint(self.buf, &mut self.pos)?),
+      1 => self.skip_fixed(8)?,
+      2 => {
+        let len = read_varint(self.buf, &mut self.pos)? as usize;
+        if self.buf.len() - self.pos < len {
+          return Err(invalid_data("truncated field"));
+        }
+        self.pos += len;
+        Value::Bytes(&self.buf[self.pos - len..self.pos])
+      },
+      5 => self.skip_fixed(4)?,
+      _ => return Err(invalid_data("unsupported wire type"))
+    };
+
+    Ok(((key >> 3) as u32, value))
+  }
+
+  fn skip_fixed(&mut self, len: usize) -> ParseResult<Value<'a>> {
+    if self.buf.len() - self.pos < len {
+      return Err(invalid_data("truncated field"));
+    }
+    self.pos += len;
+    Ok(Value::Fixed)
+  }
+}
+
+impl<'a> Iterator for Message<'a> {
+  type Item = ParseResult<(u32, Value<'a>)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.buf.len() {
+      return None;
+    }
+    let res = self.read_field();
+    if res.is_err() {
+      self.pos = self.buf.len();
+    }
+
+    Some(res)
+  }
+}
+
+/// Iterator over packed repeated varint field.
+struct Packed<'a> {
+  buf: &'a [u8],
+  pos: usize
+}
+
+impl<'a> Packed<'a> {
+  fn new(buf: &'a [u8]) -> Self {
+    Self { buf, pos: 0 }
+  }
+}
+
+impl<'a> Iterator for Packed<'a> {
+  type Item = ParseResult<u64>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.buf.len() {
+      return None;
+    }
+    let res = read_varint(self.buf, &mut self.pos);
+    if res.is_err() {
+      self.pos = self.buf.len();
+    }
+
+    Some(res)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Write;
+  use flate2::write::ZlibEncoder;
+  use flate2::Compression;
+  use crate::osm_map::{RestrictionKind, Via};
+
+  fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+      out.push((v as u8) | 0x80);
+      v >>= 7;
+    }
+    out.push(v as u8);
+  }
+
+  fn zz(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+  }
+
+  fn varint_field(out: &mut Vec<u8>, num: u64, v: u64) {
+    varint(out, num << 3);
+    varint(out, v);
+  }
+
+  fn bytes_field(out: &mut Vec<u8>, num: u64, b: &[u8]) {
+    varint(out, (num << 3) | 2);
+    varint(out, b.len() as u64);
+    out.extend_from_slice(b);
+  }
+
+  fn packed(values: &[u64]) -> Vec<u8> {
+    let mut out = Vec::new();
+    values.iter().for_each(|v| varint(&mut out, *v));
+    out
+  }
+
+  fn deltas(values: &[i64]) -> Vec<u64> {
+    let mut prev = 0;
+    values.iter().map(|v| { let d = v - prev; prev = *v; zz(d) }).collect()
+  }
+
+  fn blob(out: &mut Vec<u8>, kind: &[u8], data: &[u8], compress: bool) {
+    blob_with_size(out, kind, data, compress, data.len());
+  }
+
+  fn blob_with_size(out: &mut Vec<u8>, kind: &[u8], data: &[u8], compress: bool, raw_size: usize) {
+    let mut blob = Vec::new();
+    if compress {
+      let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+      enc.write_all(data).unwrap();
+      varint_field(&mut blob, 2, raw_size as u64);
+      bytes_field(&mut blob, 3, &enc.finish().unwrap());
+    } else {
+      bytes_field(&mut blob, 1, data);
+    }
+    let mut header = Vec::new();
+    bytes_field(&mut header, 1, kind);
+    varint_field(&mut header, 3, blob.len() as u64);
+
+    out.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&blob);
+  }
+
+  fn sample_file() -> Vec<u8> {
+    let mut header_block = Vec::new();
+    bytes_field(&mut header_block, 4, b"OsmSchema-V0.6");
+    bytes_field(&mut header_block, 4, b"DenseNodes");
+
+    let mut strings = Vec::new();
+    for s in [&b""[..], b"highway", b"residential", b"building", b"yes", b"type", b"restriction",
+      b"no_u_turn", b"from", b"via", b"to"].iter() {
+      bytes_field(&mut strings, 1, s);
+    }
+
+    let mut dense = Vec::new();
+    bytes_field(&mut dense, 1, &packed(&deltas(&[1, 2, 5])));
+    bytes_field(&mut dense, 8, &packed(&deltas(&[557_000_000, 557_000_100, 557_001_000])));
+    bytes_field(&mut dense, 9, &packed(&deltas(&[376_000_000, 376_000_050, 376_000_000])));
+    let mut plain_node = Vec::new();
+    varint_field(&mut plain_node, 1, zz(7));
+    varint_field(&mut plain_node, 8, zz(557_002_000));
+    varint_field(&mut plain_node, 9, zz(376_002_000));
+    let mut nodes_group = Vec::new();
+    bytes_field(&mut nodes_group, 2, &dense);
+    bytes_field(&mut nodes_group, 1, &plain_node);
+
+    let mut road = Vec::new();
+    varint_field(&mut road, 1, 10);
+    bytes_field(&mut road, 2, &packed(&[1]));
+    bytes_field(&mut road, 3, &packed(&[2]));
+    bytes_field(&mut road, 8, &packed(&deltas(&[1, 2, 7])));
+    let mut building = Vec::new();
+    varint_field(&mut building, 1, 11);
+    bytes_field(&mut building, 2, &packed(&[3]));
+    bytes_field(&mut building, 3, &packed(&[4]));
+    bytes_field(&mut building, 8, &packed(&deltas(&[5, 1])));
+    let mut ways_group = Vec::new();
+    bytes_field(&mut ways_group, 3, &road);
+    bytes_field(&mut ways_group, 3, &building);
+
+    let mut u_turn = Vec::new();
+    varint_field(&mut u_turn, 1, 100);
+    bytes_field(&mut u_turn, 2, &packed(&[5, 6]));
+    bytes_field(&mut u_turn, 3, &packed(&[6, 7]));
+    bytes_field(&mut u_turn, 8, &packed(&[8, 9, 10]));
+    bytes_field(&mut u_turn, 9, &packed(&deltas(&[10, 7, 10])));
+    bytes_field(&mut u_turn, 10, &packed(&[1, 0, 1]));
+    let mut relations_group = Vec::new();
+    bytes_field(&mut relations_group, 4, &u_turn);
+
+    let mut block = Vec::new();
+    bytes_field(&mut block, 1, &strings);
+    bytes_field(&mut block, 2, &nodes_group);
+    bytes_field(&mut block, 2, &ways_group);
+    bytes_field(&mut block, 2, &relations_group);
+
+    let mut file = Vec::new();
+    blob(&mut file, b"OSMHeader", &header_block, false);
+    blob(&mut file, b"OSMData", &block, true);
+    file
+  }
+
+  #[test]
+  fn reads_nodes_and_highways() {
+    let file = sample_file();
+    assert!(is_pbf(&file));
+
+    let mut builder = MapBuilder::new(None);
+    load(file.as_slice(), &mut builder).unwrap();
+    let data = builder.finish();
+    assert_eq!(data.nodes.len(), 4);
+    assert!((data.nodes[&2].lat - 55.70001).abs() < 1e-9);
+    assert!((data.nodes[&2].lon - 37.600005).abs() < 1e-9);
+    assert!((data.nodes[&7].lat - 55.7002).abs() < 1e-9);
+
+    assert_eq!(data.ways.len(), 1);
+    let road = &data.ways[&10];
+    assert_eq!(road.nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![1, 2, 7]);
+    assert_eq!(road.highway, "residential");
+
+    assert_eq!(data.restrictions, vec![Restriction {
+      id: 100,
+      kind: RestrictionKind::No,
+      from: vec![10],
+      via: Via::Node(7),
+      to: vec![10]
+    }]);
+  }
+
+  #[test]
+  fn rejects_truncated_file() {
+    let file = sample_file();
+    assert!(load(&file[..file.len() - 3], &mut MapBuilder::new(None)).is_err());
+  }
+
+  #[test]
+  fn rejects_blob_larger_than_declared() {
+    let mut header_block = Vec::new();
+    bytes_field(&mut header_block, 4, b"OsmSchema-V0.6");
+    let block = vec![0; 4096];
+    for raw_size in [16, 8192].iter() {
+      let mut file = Vec::new();
+      blob(&mut file, b"OSMHeader", &header_block, false);
+      blob_with_size(&mut file, b"OSMData", &block, true, *raw_size);
+      let err = load(file.as_slice(), &mut MapBuilder::new(None)).unwrap_err();
+      assert!(err.to_string().contains("blob size differs from declared"), "{}", err);
+    }
+  }
+}
@@ -0,0 +1,163 @@
+//! Routing profiles: which roads could be used by a transport kind and how fast.
+//!
+//! Profiles are loaded from json, see `profiles.json` for the format and default values.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use serde::Deserialize;
+use crate::{Kmh, TransportKind};
+use crate::graph::RoadClass;
+use crate::error::ProfileLoadError;
+
+/// Way tags kept by the loader for profiles to check, besides `highway` and `maxspeed`.
+pub const ROUTING_TAGS: [&str; 6] = ["access", "vehicle", "motor_vehicle", "motorcar", "foot", "surface"];
+
+const DEFAULT_PROFILES: &str = include_str!("../profiles.json");
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutingProfile {
+  pub name: String,
+  /// Kind of the transport, defines which oneway restrictions apply
+  pub mode: TransportKind,
+  /// Allowed `highway` values, other roads are not used
+  pub highways: HashMap<String, HighwayRule>,
+  /// Access tags from the most specific to the least, the first present one is checked
+  #[serde(default)]
+  pub access_tags: Vec<String>,
+  /// Access tag values forbidding the road
+  #[serde(default)]
+  pub forbidden_access: Vec<String>,
+  /// Speed factors by `surface`, 0 forbids the road
+  #[serde(default)]
+  pub surfaces: HashMap<String, f64>,
+  /// If true `maxspeed` of the road is used instead of highway speed
+  #[serde(default)]
+  pub use_maxspeed: bool,
+  /// Part of `maxspeed` which is really achievable, in percents
+  #[serde(default = "default_maxspeed_percent")]
+  pub maxspeed_percent: u32
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HighwayRule {
+  pub speed: Kmh,
+  /// Multiplier of the travel time used to make the road less attractive
+  #[serde(default = "default_penalty")]
+  pub penalty: f64
+}
+
+fn default_maxspeed_percent() -> u32 {
+  100
+}
+
+fn default_penalty() -> f64 {
+  1.0
+}
+
+#[derive(Deserialize)]
+struct ProfilesFile {
+  profiles: Vec<RoutingProfile>
+}
+
+impl RoutingProfile {
+  /// Travel speed on the road or `None` if the road is forbidden.
+  pub fn speed(&self, class: &RoadClass) -> Option<Kmh> {
+    let rule = self.highways.get(&class.highway)?;
+
+    let access = self.access_tags.iter().find_map(|k| class.tag(k));
+    if access.is_some_and(|v| self.forbidden_access.iter().any(|f| f == v)) {
+      return None;
+    }
+
+    let speed = match class.maxspeed {
+      Some(Kmh(max)) if self.use_maxspeed => max * self.maxspeed_percent / 100,
+      _ => rule.speed.0
+    };
+    let factor = class.tag("surface").and_then(|s| self.surfaces.get(s)).copied().unwrap_or(1.0);
+    let speed = (speed as f64 * factor).round() as u32;
+
+    if speed > 0 {
+      Some(Kmh(speed))
+    } else {
+      None
+    }
+  }
+
+  /// Search cost of the road in ms per cm or `None` if the road is forbidden.
+  pub fn cost_per_cm(&self, class: &RoadClass) -> Option<f64> {
+    let penalty = self.highways.get(&class.highway)?.penalty.max(1.0);
+    self.speed(class).map(|s| penalty / s.as_cm_per_millisecond())
+  }
+
+  fn validate(&self) -> Result<(), ProfileLoadError> {
+    if let Some(tag) = self.access_tags.iter().find(|t| !ROUTING_TAGS.contains(&t.as_str())) {
+      return Err(ProfileLoadError::Invalid(format!("profile {}: unsupported access tag {}", self.name, tag)));
+    }
+    if let Some((highway, _)) = self.highways.iter().find(|(_, r)| r.speed.0 == 0 || !r.penalty.is_finite()) {
+      return Err(ProfileLoadError::Invalid(format!("profile {}: bad rule for highway {}", self.name, highway)));
+    }
+
+    Ok(())
+  }
+}
+
+pub fn parse_profiles(json: &str) -> Result<Vec<RoutingProfile>, ProfileLoadError> {
+  check_profiles(serde_json::from_str::<ProfilesFile>(json)?.profiles)
+}
+
+pub fn load_profiles(path: &str) -> Result<Vec<RoutingProfile>, ProfileLoadError> {
+  let file: ProfilesFile = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+  check_profiles(file.profiles)
+}
+
+pub fn default_profiles() -> Vec<RoutingProfile> {
+  parse_profiles(DEFAULT_PROFILES).expect("default profiles are broken")
+}
+
+fn check_profiles(profiles: Vec<RoutingProfile>) -> Result<Vec<RoutingProfile>, ProfileLoadError> {
+  for (i, p) in profiles.iter().enumerate() {
+    p.validate()?;
+    if profiles[..i].iter().any(|o| o.name == p.name) {
+      return Err(ProfileLoadError::Invalid(format!("duplicate profile {}", p.name)));
+    }
+  }
+  if profiles.is_empty() {
+    return Err(ProfileLoadError::Invalid("no profiles".to_string()));
+  }
+
+  Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn class(highway: &str, tags: &[(&str, &str)]) -> RoadClass {
+    RoadClass {
+      highway: highway.to_string(),
+      maxspeed: None,
+      tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+  }
+
+  #[test]
+  fn default_profiles_rules() {
+    let profiles = default_profiles();
+    let car = profiles.iter().find(|p| p.name == "car").unwrap();
+    let foot = profiles.iter().find(|p| p.name == "foot").unwrap();
+
+    assert_eq!(foot.speed(&class("motorway", &[])), None);
+    assert_eq!(car.speed(&class("footway", &[])), None);
+    assert_eq!(car.speed(&class("residential", &[])), Some(Kmh(25)));
+    assert_eq!(car.speed(&class("residential", &[("access", "private")])), None);
+    assert_eq!(car.speed(&class("residential", &[("access", "no"), ("motor_vehicle", "yes")])), Some(Kmh(25)));
+    assert_eq!(car.speed(&class("residential", &[("surface", "unpaved")])), Some(Kmh(15)));
+    assert_eq!(foot.speed(&class("residential", &[("motor_vehicle", "no")])), Some(Kmh(5)));
+  }
+
+  #[test]
+  fn rejects_unknown_access_tag() {
+    let json = r#"{"profiles": [{"name": "bike", "mode": "car", "highways": {}, "access_tags": ["bicycle"]}]}"#;
+    assert!(parse_profiles(json).is_err());
+  }
+}
@@ -0,0 +1,121 @@
+//! Seats taken by passengers on car paths.
+//!
+//! Reservation takes seats on a range of segments of a car path, segment `i` is between points
+//! `i` and `i + 1` of the path. Seat capacity of the segments is kept by the car path itself and
+//! is given on every call, the registry only counts the taken seats.
+use std::collections::HashMap;
+use crate::error::ReservationError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+  pub path_id: u64,
+  /// first point of the range
+  pub from: usize,
+  /// last point of the range
+  pub to: usize,
+  pub seats: u8
+}
+
+#[derive(Default)]
+pub struct Reservations {
+  next_id: u64,
+  by_id: HashMap<u64, Reservation>,
+  by_path: HashMap<u64, Vec<u64>>,
+  /// seats taken on the segments of the paths, kept up to date by `commit` and `cancel`
+  taken: HashMap<u64, Vec<u8>>
+}
+
+impl Reservations {
+  /// Seats left on the segments of the path with `capacity` seats on them.
+  pub fn free_seats(&self, path_id: u64, capacity: &[u8]) -> Vec<u8> {
+    (0..capacity.len()).map(|i| capacity[i].saturating_sub(self.taken(path_id, i))).collect()
+  }
+
+  /// Seats taken on the segment of the path.
+  pub fn taken(&self, path_id: u64, segment: usize) -> u8 {
+    self.taken.get(&path_id).and_then(|taken| taken.get(segment)).copied().unwrap_or(0)
+  }
+
+  /// Takes `seats` on the segments between points `from` and `to`, returns the reservation id.
+  pub fn commit(&mut self, path_id: u64, capacity: &[u8], from: usize, to: usize, seats: u8) -> Result<u64, ReservationError> {
+    if from >= to || to > capacity.len() || seats == 0 {
+      return Err(ReservationError::BadRange { path_id, from, to, segments: capacity.len() });
+    }
+    let free = self.free_seats(path_id, capacity);
+    if let Some(segment) = (from..to).find(|i| free[*i] < seats) {
+      return Err(ReservationError::Conflict { path_id, segment, free: free[segment], requested: seats });
+    }
+
+    let id = self.next_id;
+    self.next_id += 1;
+    self.by_id.insert(id, Reservation { path_id, from, to, seats });
+    self.by_path.entry(path_id).or_default().push(id);
+    let taken = self.taken.entry(path_id).or_default();
+    if taken.len() < to {
+      taken.resize(to, 0);
+    }
+    for t in taken[from..to].iter_mut() {
+      *t = t.saturating_add(seats);
+    }
+
+    Ok(id)
+  }
+
+  /// Drops reservations of the removed path.
+  pub fn remove_path(&mut self, path_id: u64) {
+    for id in self.by_path.remove(&path_id).into_iter().flatten() {
+      self.by_id.remove(&id);
+    }
+    self.taken.remove(&path_id);
+  }
+
+  /// Returns seats of the reservation.
+  pub fn cancel(&mut self, id: u64) -> Result<Reservation, ReservationError> {
+    let r = self.by_id.remove(&id).ok_or(ReservationError::UnknownReservation(id))?;
+    let ids = self.by_path.get_mut(&r.path_id).unwrap();
+    ids.retain(|i| *i != id);
+    if ids.is_empty() {
+      self.by_path.remove(&r.path_id);
+      self.taken.remove(&r.path_id);
+    } else {
+      let taken = self.taken.get_mut(&r.path_id).unwrap();
+      for t in taken[r.from..r.to].iter_mut() {
+        *t = t.saturating_sub(r.seats);
+      }
+    }
+
+    Ok(r)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn conflicts_are_found_per_segment() {
+    let mut reservations = Reservations::default();
+    let capacity = [2, 2, 1, 2];
+
+    let first = reservations.commit(1, &capacity, 0, 2, 2).unwrap();
+    assert_eq!(reservations.free_seats(1, &capacity), vec![0, 0, 1, 2]);
+    assert_eq!((reservations.taken(1, 1), reservations.taken(1, 2)), (2, 0));
+    assert_eq!(reservations.free_seats(2, &capacity), capacity.to_vec());
+    assert!(matches!(
+      reservations.commit(1, &capacity, 1, 3, 1),
+      Err(ReservationError::Conflict { segment: 1, free: 0, requested: 1, .. })
+    ));
+
+    let second = reservations.commit(1, &capacity, 2, 4, 1).unwrap();
+    assert!(matches!(reservations.commit(1, &capacity, 3, 4, 2), Err(ReservationError::Conflict { segment: 3, .. })));
+    assert!(matches!(reservations.commit(1, &capacity, 2, 5, 1), Err(ReservationError::BadRange { .. })));
+    assert!(matches!(reservations.commit(1, &capacity, 2, 2, 1), Err(ReservationError::BadRange { .. })));
+
+    assert_eq!(reservations.cancel(first).unwrap().seats, 2);
+    assert!(matches!(reservations.cancel(first), Err(ReservationError::UnknownReservation(_))));
+    assert_eq!(reservations.free_seats(1, &capacity), vec![2, 2, 0, 1]);
+    assert_eq!((reservations.taken(1, 1), reservations.taken(1, 2)), (0, 1));
+    reservations.cancel(second).unwrap();
+    assert_eq!(reservations.free_seats(1, &capacity), capacity.to_vec());
+  }
+}
@@ -0,0 +1,327 @@
+//! Binary snapshot of the built map, loading it is much faster than parsing the osm file.
+//!
+//! The file is a 32 byte header (magic, format version, crc32 of the payload and its length)
+//! followed by the payload of little-endian fields: counts of graph nodes and links, the graph arrays
+//! aligned to 8 bytes (osm ids, lats, lons, link offsets, links), road classes, turn restrictions
+//! contraction hierarchies (profile name, costs checksum, link counts and aligned arrays of
+//! ranks, up offsets, up links, down offsets and down links) and landmarks (profile name, costs
+//! checksum, landmark nodes and aligned arrays of costs from and to them).
+//! The arrays are used right from the mapped file, so processes loading the same snapshot share them.
+//! Snapshots of other versions are rejected, not converted.
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Arc;
+use memmap2::Mmap;
+use crate::graph::{RoadGraph, NodeId, RoadClass, TurnRestriction};
+use crate::csr::{Csr, Slab, Pod, RawLink};
+use crate::ch::{Hierarchy, ChLink};
+use crate::landmarks::Landmarks;
+use crate::osm_map::RestrictionKind;
+use crate::profile::RoutingProfile;
+use crate::error::SnapshotError;
+use crate::Kmh;
+
+pub const SNAPSHOT_VERSION: u32 = 4;
+const MAGIC: &[u8; 8] = b"MAPSNAP\0";
+const HEADER_LEN: usize = 32;
+const ALIGN: usize = 8;
+
+/// Writes snapshot of the `graph`, the file is replaced atomically.
+pub fn save(path: &str, graph: &RoadGraph) -> Result<(), SnapshotError> {
+  let csr = graph.csr();
+  let mut w = Writer(Vec::new());
+
+  w.u64(csr.len() as u64);
+  w.u64(csr.links.len() as u64);
+  w.align();
+  csr.osm_ids.iter().for_each(|v| w.u64(*v));
+  csr.lats.iter().for_each(|v| w.f64(*v));
+  csr.lons.iter().for_each(|v| w.f64(*v));
+  csr.offsets.iter().for_each(|v| w.u32(*v));
+  w.align();
+  for link in csr.links.iter() {
+    w.u32(link.to);
+    w.u32(link.len);
+    w.u32(link.class);
+    w.u32(link.access);
+  }
+
+  w.u32(graph.road_classes.len() as u32);
+  for c in graph.road_classes.iter() {
+    w.str(&c.highway);
+    w.u32(c.maxspeed.map_or(0, |s| s.0));
+    w.tags(&c.tags);
+  }
+
+  w.u32(graph.turn_restrictions.len() as u32);
+  for r in graph.turn_restrictions.iter() {
+    w.u8(matches!(r.kind, RestrictionKind::Only) as u8);
+    w.u32(r.nodes.len() as u32);
+    r.nodes.iter().for_each(|n| w.u32(n.0 as u32));
+  }
+
+  w.u32(graph.hierarchies().len() as u32);
+  for h in graph.hierarchies() {
+    w.str(&h.profile);
+    w.u32(h.costs_checksum);
+    w.u64(h.up_links.len() as u64);
+    w.u64(h.down_links.len() as u64);
+    for (offsets, links) in [(&h.ranks, None), (&h.up_offsets, Some(&h.up_links)), (&h.down_offsets, Some(&h.down_links))].iter() {
+      w.align();
+      offsets.iter().for_each(|v| w.u32(*v));
+      if let Some(links) = links {
+        w.align();
+        for link in links.iter() {
+          w.u32(link.to);
+          w.u32(link.cost);
+          w.u32(link.middle);
+        }
+      }
+    }
+  }
+
+  w.u32(graph.all_landmarks().len() as u32);
+  for l in graph.all_landmarks() {
+    w.str(&l.profile);
+    w.u32(l.costs_checksum);
+    w.u32(l.nodes.len() as u32);
+    l.nodes.iter().for_each(|n| w.u32(n.0 as u32));
+    for costs in [&l.from, &l.to].iter() {
+      w.align();
+      costs.iter().for_each(|v| w.u32(*v));
+    }
+  }
+
+  let payload = w.0;
+  let mut header = Vec::with_capacity(HEADER_LEN);
+  header.extend_from_slice(MAGIC);
+  header.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+  header.extend_from_slice(&checksum(&payload).to_le_bytes());
+  header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+  header.resize(HEADER_LEN, 0);
+
+  let tmp_path = format!("{}.tmp", path);
+  let mut file = File::create(&tmp_path)?;
+  file.write_all(&header)?;
+  file.write_all(&payload)?;
+  file.sync_all()?;
+  fs::rename(&tmp_path, path)?;
+
+  Ok(())
+}
+
+/// Maps snapshot into memory, the graph gets `profiles` as they are not a part of the snapshot.
+///
+/// The file must not be changed in place while it's mapped, [`save`] replaces it with a new file
+/// so graphs loaded earlier keep the old one.
+pub fn load(path: &str, profiles: Vec<RoutingProfile>) -> Result<RoadGraph, SnapshotError> {
+  if cfg!(target_endian = "big") {
+    return Err(malformed("snapshots can't be mapped on big-endian platforms"));
+  }
+
+  let file = File::open(path)?;
+  // the file is replaced by rename only, see above
+  let map = Arc::new(unsafe { Mmap::map(&file)? });
+  let buf: &[u8] = &map;
+  if buf.len() < HEADER_LEN || &buf[..8] != MAGIC {
+    return Err(SnapshotError::BadMagic);
+  }
+  let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+  if version != SNAPSHOT_VERSION {
+    return Err(SnapshotError::UnsupportedVersion(version));
+  }
+  let payload_checksum = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+  let payload_len = u64::from_le_bytes(buf[16..24].try_into().unwrap());
+  let payload = &buf[HEADER_LEN..];
+  if payload.len() as u64 != payload_len || checksum(payload) != payload_checksum {
+    return Err(SnapshotError::ChecksumMismatch);
+  }
+
+  let mut r = Reader { buf: payload, pos: 0 };
+  let nodes_num = r.u64()? as usize;
+  let links_num = r.u64()? as usize;
+  let base = Csr {
+    osm_ids: r.slab(&map, nodes_num)?,
+    lats: r.slab(&map, nodes_num)?,
+    lons: r.slab(&map, nodes_num)?,
+    offsets: r.slab(&map, nodes_num.checked_add(1).ok_or_else(|| malformed("too many nodes"))?)?,
+    links: r.slab::<RawLink>(&map, links_num)?
+  };
+
+  let classes_num = r.u32()?;
+  let mut road_classes = Vec::with_capacity(classes_num as usize);
+  for _ in 0..classes_num {
+    road_classes.push(RoadClass {
+      highway: r.str()?,
+      maxspeed: r.kmh()?,
+      tags: r.tags()?
+    });
+  }
+  base.validate(road_classes.len()).map_err(SnapshotError::Malformed)?;
+
+  let restrictions_num = r.u32()?;
+  let mut restrictions = Vec::new();
+  for _ in 0..restrictions_num {
+    let kind = if r.u8()? == 0 { RestrictionKind::No } else { RestrictionKind::Only };
+    let len = r.u32()?;
+    if len < 3 {
+      return Err(malformed("turn restriction is too short"));
+    }
+    let nodes = (0..len).map(|_| r.node_id(nodes_num)).collect::<Result<_, _>>()?;
+    restrictions.push(TurnRestriction { kind, nodes });
+  }
+
+  let hierarchies_num = r.u32()?;
+  let mut hierarchies = Vec::new();
+  for _ in 0..hierarchies_num {
+    let profile = r.str()?;
+    let costs_checksum = r.u32()?;
+    let up_num = r.u64()? as usize;
+    let down_num = r.u64()? as usize;
+    let hierarchy = Hierarchy {
+      profile,
+      costs_checksum,
+      ranks: r.slab(&map, nodes_num)?,
+      up_offsets: r.slab(&map, nodes_num + 1)?,
+      up_links: r.slab::<ChLink>(&map, up_num)?,
+      down_offsets: r.slab(&map, nodes_num + 1)?,
+      down_links: r.slab::<ChLink>(&map, down_num)?
+    };
+    hierarchy.validate(nodes_num).map_err(SnapshotError::Malformed)?;
+    hierarchies.push(hierarchy);
+  }
+
+  let landmarks_num = r.u32()?;
+  let mut landmarks = Vec::new();
+  for _ in 0..landmarks_num {
+    let profile = r.str()?;
+    let costs_checksum = r.u32()?;
+    let count = r.u32()? as usize;
+    let nodes: Vec<NodeId> = (0..count).map(|_| r.node_id(nodes_num)).collect::<Result<_, _>>()?;
+    let costs_len = count.checked_mul(nodes_num).ok_or_else(|| malformed("too many landmarks"))?;
+    landmarks.push(Landmarks {
+      profile,
+      costs_checksum,
+      nodes,
+      from: r.slab(&map, costs_len)?,
+      to: r.slab(&map, costs_len)?
+    });
+  }
+
+  if r.pos != payload.len() {
+    return Err(malformed("trailing data"));
+  }
+
+  let mut graph = RoadGraph::from_parts(base, road_classes, restrictions, profiles);
+  graph.set_hierarchies(hierarchies);
+  graph.set_landmarks(landmarks);
+  Ok(graph)
+}
+
+fn checksum(data: &[u8]) -> u32 {
+  let mut hasher = crc32fast::Hasher::new();
+  hasher.update(data);
+  hasher.finalize()
+}
+
+fn malformed(reason: &str) -> SnapshotError {
+  SnapshotError::Malformed(reason.to_string())
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+  fn u8(&mut self, v: u8) {
+    self.0.push(v);
+  }
+
+  /// Pads to [`ALIGN`], the payload starts aligned as header length is a multiple of it.
+  fn align(&mut self) {
+    let len = self.0.len().div_ceil(ALIGN) * ALIGN;
+    self.0.resize(len, 0);
+  }
+
+  fn u32(&mut self, v: u32) {
+    self.0.extend_from_slice(&v.to_le_bytes());
+  }
+
+  fn u64(&mut self, v: u64) {
+    self.0.extend_from_slice(&v.to_le_bytes());
+  }
+
+  fn f64(&mut self, v: f64) {
+    self.0.extend_from_slice(&v.to_le_bytes());
+  }
+
+  fn str(&mut self, s: &str) {
+    self.u32(s.len() as u32);
+    self.0.extend_from_slice(s.as_bytes());
+  }
+
+  fn tags(&mut self, tags: &[(String, String)]) {
+    self.u32(tags.len() as u32);
+    for (k, v) in tags {
+      self.str(k);
+      self.str(v);
+    }
+  }
+}
+
+struct Reader<'a> {
+  buf: &'a [u8],
+  pos: usize
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+    if self.buf.len().saturating_sub(self.pos) < len {
+      return Err(malformed("unexpected end of payload"));
+    }
+    self.pos += len;
+    Ok(&self.buf[self.pos - len..self.pos])
+  }
+
+  /// Array of `len` items starting at the next aligned position, it points into the `map`.
+  fn slab<T: Pod>(&mut self, map: &Arc<Mmap>, len: usize) -> Result<Slab<T>, SnapshotError> {
+    self.pos = self.pos.div_ceil(ALIGN) * ALIGN;
+    let offset = HEADER_LEN + self.pos;
+    let size = len.checked_mul(std::mem::size_of::<T>()).ok_or_else(|| malformed("array is too long"))?;
+    self.bytes(size)?;
+    Slab::mapped(map.clone(), offset, len).ok_or_else(|| malformed("misaligned array"))
+  }
+
+  fn u8(&mut self) -> Result<u8, SnapshotError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u32(&mut self) -> Result<u32, SnapshotError> {
+    Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  fn u64(&mut self) -> Result<u64, SnapshotError> {
+    Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+  }
+
+  fn str(&mut self) -> Result<String, SnapshotError> {
+    let len = self.u32()? as usize;
+    String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| malformed("bad utf-8 string"))
+  }
+
+  fn tags(&mut self) -> Result<Vec<(String, String)>, SnapshotError> {
+    (0..self.u32()?).map(|_| Ok((self.str()?, self.str()?))).collect()
+  }
+
+  fn kmh(&mut self) -> Result<Option<Kmh>, SnapshotError> {
+    Ok(Some(self.u32()?).filter(|v| *v != 0).map(Kmh))
+  }
+
+  fn node_id(&mut self, nodes_num: usize) -> Result<NodeId, SnapshotError> {
+    let id = self.u32()? as usize;
+    if id < nodes_num {
+      Ok(NodeId(id))
+    } else {
+      Err(malformed("node index out of bounds"))
+    }
+  }
+}
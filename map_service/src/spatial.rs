@@ -0,0 +1,264 @@
+//! Grid index of road nodes and segments for snapping points to the road network.
+//!
+//! Nodes and segments are put into cells of [`CELL_DEG`] degrees of latitude, cells are stretched in longitude
+//! to be about square at the mean latitude of the map. Every transport kind has its own grid
+//! with the nodes and segments it could use, distances are metric.
+use std::collections::HashMap;
+use crate::{distance, MapPoint, TransportKind};
+use crate::graph::{RoadGraph, NodeId};
+
+/// Cell height, about 220 m
+const CELL_DEG: f64 = 0.002;
+/// Length of a degree of latitude in cm, matches the earth radius of [`distance`].
+const CM_PER_DEG: f64 = 637_130_200.0 * std::f64::consts::PI / 180.0;
+
+type Cell = (i32, i32);
+
+#[derive(Default)]
+pub struct SpatialIndex {
+  lat_step: f64,
+  lon_step: f64,
+  foot: KindGrids,
+  car: KindGrids
+}
+
+#[derive(Default)]
+struct KindGrids {
+  nodes: Grid<NodeId>,
+  /// road segments as node pairs, a segment is put into every cell of its bounding box
+  edges: Grid<(NodeId, NodeId)>
+}
+
+struct Grid<T> {
+  cells: HashMap<Cell, Vec<T>>,
+  /// min and max cells with items
+  bounds: Option<(Cell, Cell)>
+}
+
+impl<T> Default for Grid<T> {
+  fn default() -> Self {
+    Self { cells: HashMap::new(), bounds: None }
+  }
+}
+
+impl<T: Copy> Grid<T> {
+  fn add(&mut self, cell: Cell, item: T) {
+    self.cells.entry(cell).or_default().push(item);
+    self.bounds = Some(match self.bounds {
+      Some((min, max)) => ((min.0.min(cell.0), min.1.min(cell.1)), (max.0.max(cell.0), max.1.max(cell.1))),
+      None => (cell, cell)
+    });
+  }
+}
+
+/// Point of a road segment nearest to the query point.
+#[derive(Debug, Copy, Clone)]
+pub struct EdgePoint {
+  pub from: NodeId,
+  pub to: NodeId,
+  /// position on the segment from 0 at `from` to 1 at `to`
+  pub t: f64,
+  pub lat: f64,
+  pub lon: f64,
+  /// distance from the query point in cm
+  pub distance: u32
+}
+
+impl SpatialIndex {
+  /// Indexes road nodes and segments usable by each transport kind with any of its profiles.
+  pub fn build(graph: &RoadGraph) -> Self {
+    let nodes_num = graph.nodes_num().max(1) as f64;
+    let mean_lat = graph.road_nodes().map(|id| graph.map_point(id).lat).sum::<f64>() / nodes_num;
+    let mut index = SpatialIndex {
+      lat_step: CELL_DEG,
+      lon_step: CELL_DEG / mean_lat.to_radians().cos().max(0.1),
+      foot: KindGrids::default(),
+      car: KindGrids::default()
+    };
+
+    for id in graph.road_nodes() {
+      let p = graph.map_point(id);
+      let cell = index.cell(p.lat, p.lon);
+      for kind in [TransportKind::Foot, TransportKind::Car].iter().copied() {
+        if graph.is_usable_by(id, kind) {
+          index.grids_mut(kind).nodes.add(cell, id);
+        }
+      }
+
+      for link in graph.links(id) {
+        // two way roads are indexed once
+        if link.node.0 < id.0 && graph.links(link.node).any(|l| l.node == id) {
+          continue;
+        }
+        let q = graph.map_point(link.node);
+        let (min, max) = (index.cell(p.lat.min(q.lat), p.lon.min(q.lon)), index.cell(p.lat.max(q.lat), p.lon.max(q.lon)));
+        for kind in [TransportKind::Foot, TransportKind::Car].iter().copied() {
+          if !graph.is_edge_usable_by(id, link.node, kind) {
+            continue;
+          }
+          for y in min.0..=max.0 {
+            for x in min.1..=max.1 {
+              index.grids_mut(kind).edges.add((y, x), (id, link.node));
+            }
+          }
+        }
+      }
+    }
+
+    index
+  }
+
+  fn grids(&self, kind: TransportKind) -> &KindGrids {
+    match kind {
+      TransportKind::Foot => &self.foot,
+      TransportKind::Car => &self.car
+    }
+  }
+
+  fn grids_mut(&mut self, kind: TransportKind) -> &mut KindGrids {
+    match kind {
+      TransportKind::Foot => &mut self.foot,
+      TransportKind::Car => &mut self.car
+    }
+  }
+
+  fn cell(&self, lat: f64, lon: f64) -> Cell {
+    ((lat / self.lat_step).floor() as i32, (lon / self.lon_step).floor() as i32)
+  }
+
+  /// Up to `k` nodes of the `kind` passing the `filter` nearest to the point, with distances in cm,
+  /// ordered by distance.
+  pub fn nearest(&self, graph: &RoadGraph, lat: f64, lon: f64, k: usize, kind: TransportKind, filter: impl Fn(NodeId) -> bool) -> Vec<(NodeId, u32)> {
+    let target = MapPoint { id: 0, lat, lon, path_id: None };
+    self.search(&self.grids(kind).nodes, lat, lon, k, |id| {
+      Some(id).filter(|id| filter(*id)).map(|id| (id, distance(&graph.map_point(id), &target)))
+    })
+  }
+
+  /// Nearest to the point segment of the `kind` passing the `filter`, which gets segment ends.
+  pub fn nearest_edge(&self, graph: &RoadGraph, lat: f64, lon: f64, kind: TransportKind, filter: impl Fn(NodeId, NodeId) -> bool) -> Option<EdgePoint> {
+    let found = self.search(&self.grids(kind).edges, lat, lon, 1, |(from, to)| {
+      if !filter(from, to) {
+        return None;
+      }
+      let p = EdgePoint { from, to, ..project((lat, lon), &graph.map_point(from), &graph.map_point(to)) };
+      Some((p, p.distance))
+    });
+
+    found.first().map(|(p, _)| *p)
+  }
+
+  /// Searches rings of cells around the point until the `k` nearest items are found,
+  /// `measure` gives the item to return and its distance in cm, `None` to skip the item.
+  fn search<T: Copy, R>(&self, grid: &Grid<T>, lat: f64, lon: f64, k: usize, mut measure: impl FnMut(T) -> Option<(R, u32)>) -> Vec<(R, u32)> {
+    let (min, max) = match grid.bounds {
+      Some(bounds) if k > 0 => bounds,
+      _ => return Vec::new()
+    };
+
+    let center = self.cell(lat, lon);
+    // rings closer than the grid bounds are empty
+    let first_ring = [min.0 - center.0, center.0 - max.0, min.1 - center.1, center.1 - max.1, 0].iter().copied().max().unwrap();
+
+    let mut found = Vec::new();
+    for r in first_ring.. {
+      for y in (center.0 - r).max(min.0)..=(center.0 + r).min(max.0) {
+        let xs: Vec<i32> = if y == center.0 - r || y == center.0 + r {
+          ((center.1 - r).max(min.1)..=(center.1 + r).min(max.1)).collect()
+        } else {
+          vec![center.1 - r, center.1 + r]
+        };
+        for x in xs {
+          found.extend(grid.cells.get(&(y, x)).into_iter().flatten().filter_map(|item| measure(*item)));
+        }
+      }
+      found.sort_by_key(|(_, d)| *d);
+      found.truncate(k);
+
+      let covers_grid = center.0 - r <= min.0 && center.0 + r >= max.0 && center.1 - r <= min.1 && center.1 + r >= max.1;
+      if covers_grid || (found.len() == k && found[k - 1].1 as f64 <= self.ring_bound(lat, r)) {
+        break;
+      }
+    }
+
+    found
+  }
+
+  /// Min distance in cm from a point to items outside of `r` rings of cells around its cell.
+  fn ring_bound(&self, lat: f64, r: i32) -> f64 {
+    // cells are the narrowest at the farthest from equator latitude of the rings
+    let max_lat = (lat.abs() + (r + 1) as f64 * self.lat_step).min(90.0);
+    let cell_cm = (self.lat_step * CM_PER_DEG).min(self.lon_step * CM_PER_DEG * max_lat.to_radians().cos());
+    r as f64 * cell_cm
+  }
+}
+
+/// Projects the point onto segment `a`-`b`, the segment is treated as straight on a local flat map.
+fn project((lat, lon): (f64, f64), a: &MapPoint, b: &MapPoint) -> EdgePoint {
+  let lon_scale = lat.to_radians().cos();
+  let (bx, by) = ((b.lon - a.lon) * lon_scale, b.lat - a.lat);
+  let (px, py) = ((lon - a.lon) * lon_scale, lat - a.lat);
+  let len2 = bx * bx + by * by;
+  let t = if len2 > 0.0 { ((px * bx + py * by) / len2).clamp(0.0, 1.0) } else { 0.0 };
+
+  let point = MapPoint { id: 0, lat: a.lat + t * (b.lat - a.lat), lon: a.lon + t * (b.lon - a.lon), path_id: None };
+  EdgePoint {
+    from: NodeId(0),
+    to: NodeId(0),
+    t,
+    lat: point.lat,
+    lon: point.lon,
+    distance: distance(&point, &MapPoint { id: 0, lat, lon, path_id: None })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::graph::{GraphBuilder, RoadClass, Access};
+  use crate::profile::default_profiles;
+
+  #[test]
+  fn nearest_matches_brute_force() {
+    let mut builder = GraphBuilder::new();
+    let mut class = |highway: &str| Some(builder.road_class_id(RoadClass { highway: highway.to_string(), maxspeed: None, tags: Vec::new() }));
+    let (residential, footway) = (class("residential"), class("footway"));
+    let id = |x: u64, y: u64| y * 100 + x + 1;
+    for y in 0..30 {
+      for x in 0..30 {
+        builder.add_node(id(x, y), 55.70 + y as f64 * 0.0013, 37.60 + x as f64 * 0.0021);
+      }
+      for x in 0..29 {
+        let highway = if y % 3 == 0 { footway } else { residential };
+        builder.connect_two_way(id(x, y), id(x + 1, y), 100, Access { foot: true, car: true }, highway);
+      }
+    }
+    let graph = builder.build(default_profiles());
+
+    // simple lcg, the test should be deterministic
+    let mut seed = 42u64;
+    let mut random = || {
+      seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+      (seed >> 11) as f64 / (1u64 << 53) as f64
+    };
+    for _ in 0..100 {
+      let (lat, lon) = (55.69 + random() * 0.06, 37.59 + random() * 0.08);
+      for kind in [TransportKind::Foot, TransportKind::Car].iter().copied() {
+        let target = MapPoint { id: 0, lat, lon, path_id: None };
+        let mut expected: Vec<u32> = graph.road_nodes()
+          .filter(|n| graph.is_usable_by(*n, kind))
+          .map(|n| distance(&graph.map_point(n), &target))
+          .collect();
+        expected.sort();
+        expected.truncate(3);
+
+        let found = graph.nearest_nodes(lat, lon, 3, kind, |_| true);
+        assert_eq!(found.iter().map(|(_, d)| *d).collect::<Vec<_>>(), expected);
+        if kind.is_car() {
+          assert!(found.iter().all(|(n, _)| !(graph.osm_id(*n) / 100).is_multiple_of(3)));
+        }
+      }
+    }
+    assert!(graph.nearest_nodes(55.75, 37.6, 0, TransportKind::Foot, |_| true).is_empty());
+  }
+}
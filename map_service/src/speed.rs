@@ -0,0 +1,63 @@
+use crate::Kmh;
+
+/// Parses `maxspeed` tag value: plain km/h, mph and implicit country values like `RU:urban`.
+pub fn parse_maxspeed(value: &str) -> Option<Kmh> {
+  let value = value.split(';').next()?.trim();
+  match value {
+    "walk" => return Some(Kmh(5)),
+    "none" | "signals" | "variable" => return None,
+    _ => {}
+  }
+
+  if let Some(pos) = value.find(':') {
+    return implicit_maxspeed(&value[..pos], &value[pos + 1..]);
+  }
+
+  if let Some(mph) = value.strip_suffix("mph") {
+    let mph: f64 = mph.trim().parse().ok()?;
+    return Some(Kmh((mph * 1.609_344).round() as u32));
+  }
+
+  let kmh: f64 = value.trim_end_matches("km/h").trim().parse().ok()?;
+  if kmh > 0.0 {
+    Some(Kmh(kmh.round() as u32))
+  } else {
+    None
+  }
+}
+
+fn implicit_maxspeed(country: &str, zone: &str) -> Option<Kmh> {
+  let kmh = match (country, zone) {
+    ("RU", "motorway") | ("BY", "motorway") => 110,
+    ("UA", "motorway") => 130,
+    ("RU", "rural") | ("BY", "rural") | ("UA", "rural") => 90,
+    (_, "motorway") => 120,
+    (_, "trunk") => 100,
+    (_, "rural") => 90,
+    (_, "urban") => 60,
+    ("RU", "living_street") | ("BY", "living_street") | ("UA", "living_street") => 20,
+    (_, "living_street") | (_, "walk") => 7,
+    (_, "zone30") => 30,
+    (_, "zone20") => 20,
+    _ => return None
+  };
+
+  Some(Kmh(kmh))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_maxspeed() {
+    assert_eq!(parse_maxspeed("60"), Some(Kmh(60)));
+    assert_eq!(parse_maxspeed("30 mph"), Some(Kmh(48)));
+    assert_eq!(parse_maxspeed("RU:urban"), Some(Kmh(60)));
+    assert_eq!(parse_maxspeed("RU:motorway"), Some(Kmh(110)));
+    assert_eq!(parse_maxspeed("RU:living_street"), Some(Kmh(20)));
+    assert_eq!(parse_maxspeed("40;60"), Some(Kmh(40)));
+    assert_eq!(parse_maxspeed("none"), None);
+    assert_eq!(parse_maxspeed("fast"), None);
+  }
+}
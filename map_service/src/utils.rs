@@ -1,9 +1,9 @@
 use std::str::FromStr;
 
-pub fn u64_parse(s: &[u8]) -> u64 {
-    u64::from_str(std::str::from_utf8(s).unwrap()).unwrap()
+pub fn u64_parse(s: &[u8]) -> Option<u64> {
+    std::str::from_utf8(s).ok().and_then(|s| u64::from_str(s).ok())
 }
 
-pub fn f64_parse(s: &[u8]) -> f64 {
-    f64::from_str(std::str::from_utf8(s).unwrap()).unwrap()
-}
\ No newline at end of file
+pub fn f64_parse(s: &[u8]) -> Option<f64> {
+    std::str::from_utf8(s).ok().and_then(|s| f64::from_str(s).ok())
+}
@@ -29,6 +29,19 @@ ALLOWED_HOSTS = []
 
 LOGIN_REDIRECT_URL = '/main'
 
+# .poly or GeoJSON polygon of the served area, the whole map is loaded if not set
+MAP_AREA = os.getenv('MAP_AREA')
+MAP_FILE = os.getenv('MAP_FILE') or './Moscow.osm.gz'
+# built map is saved here and loaded by workers instead of parsing MAP_FILE,
+# it's rebuilt when MAP_FILE is newer
+MAP_SNAPSHOT = os.getenv('MAP_SNAPSHOT') or './map.snapshot'
+# comma separated profiles to prepare contraction hierarchies for when the snapshot is built,
+# plain paths of these profiles are searched faster
+MAP_HIERARCHIES = [p for p in (os.getenv('MAP_HIERARCHIES') or '').split(',') if p]
+# comma separated profiles to prepare landmarks for when the snapshot is built, they speed up plain paths
+# of profiles without hierarchies
+MAP_LANDMARKS = [p for p in (os.getenv('MAP_LANDMARKS') or '').split(',') if p]
+
 # Application definition
 INSTALLED_APPS = [
     'web_map.apps.WebMapConfig',
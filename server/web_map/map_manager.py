@@ -1,11 +1,26 @@
+import logging
+import os
+
+from django.conf import settings
+
 try:
-    from map_service import MapService, MapPoint, MapCarPath
+    from map_service import MapService, MapPoint, MapCarPath, MapRideRequest, MapLoadError, ProfileLoadError, \
+        SnapshotError, ReservationError, CarPathError
 except Exception:
-    from web_map.map_service_mock import MapService, MapPoint, MapCarPath
+    from web_map.map_service_mock import MapService, MapPoint, MapCarPath, MapRideRequest, MapLoadError, \
+        ProfileLoadError, SnapshotError, ReservationError, CarPathError
 
 MapService = MapService
 MapPoint = MapPoint
 MapCarPath = MapCarPath
+MapRideRequest = MapRideRequest
+MapLoadError = MapLoadError
+ProfileLoadError = ProfileLoadError
+SnapshotError = SnapshotError
+ReservationError = ReservationError
+CarPathError = CarPathError
+
+logger = logging.getLogger(__name__)
 
 
 class MapManager:
@@ -14,6 +29,41 @@ class MapManager:
     @staticmethod
     def get_service() -> MapService:
         if not MapManager._map_service:
-            MapManager._map_service = MapService()
-            MapManager._map_service.load('./Moscow.osm.gz')
+            service = MapManager._load_service()
+            MapManager._add_car_paths(service)
+            MapManager._map_service = service
         return MapManager._map_service
+
+    @staticmethod
+    def _load_service() -> MapService:
+        """Graph is mapped from the snapshot, so workers loading the same snapshot share its memory."""
+        service = MapService()
+        snapshot = settings.MAP_SNAPSHOT
+        if os.path.exists(snapshot) and (not os.path.exists(settings.MAP_FILE)
+                                         or os.path.getmtime(snapshot) >= os.path.getmtime(settings.MAP_FILE)):
+            try:
+                service.load_snapshot(snapshot)
+                return service
+            except SnapshotError as e:
+                logger.warning('map snapshot %s is unusable, rebuilding: %s', snapshot, e)
+
+        service.load(settings.MAP_FILE, polygon=settings.MAP_AREA)
+        if settings.MAP_HIERARCHIES:
+            service.prepare_hierarchies(settings.MAP_HIERARCHIES)
+        if settings.MAP_LANDMARKS:
+            service.prepare_landmarks(settings.MAP_LANDMARKS)
+        service.save_snapshot(snapshot)
+        # switch to the mapped copy to share it with other workers
+        service.load_snapshot(snapshot)
+        return service
+
+    @staticmethod
+    def _add_car_paths(service: MapService):
+        """Car paths are kept by the service between queries, published paths are added once it's loaded."""
+        from web_map.models import UserPath
+
+        for path in UserPath.objects.all().prefetch_related('points'):
+            try:
+                service.add_car_path(path.to_car_path())
+            except CarPathError as e:
+                logger.warning('car path %s is skipped: %s', path.id, e)
@@ -1,6 +1,29 @@
 from typing import List
 
 
+class MapLoadError(Exception):
+    offset = None
+    element_id = None
+    way_id = None
+    node_id = None
+
+
+class ProfileLoadError(Exception):
+    pass
+
+
+class SnapshotError(Exception):
+    pass
+
+
+class ReservationError(Exception):
+    pass
+
+
+class CarPathError(Exception):
+    pass
+
+
 class MapPoint:
     id: int
     lat: float
@@ -21,21 +44,79 @@ class MapCarPath:
     id: int
     start_at: int
     path: List[MapPoint]
+    seats: List[int]
 
-    def __init__(self, id, start_at, path):
+    def __init__(self, id, start_at, path, seats=None):
         self.id = id
         self.start_at = start_at
         self.path = path
+        self.seats = seats if seats is not None else [255] * max(len(path) - 1, 0)
+
+
+class MapRideRequest:
+    start: MapPoint
+    end: MapPoint
+    seats: int
 
+    def __init__(self, start, end, seats=1):
+        self.start = start
+        self.end = end
+        self.seats = seats
 
 
 class MapService:
-    def build_path(self, points: List[MapPoint]):
+    profiles = ['car', 'foot']
+
+    def __init__(self, profiles_path=None):
+        pass
+
+    def nearest_nodes(self, lat, lon, k, mode):
+        return []
+
+    def build_path(self, points: List[MapPoint], profile=None):
         return [points[0], points[-1]]
 
-    def build_path_using_cars(self, start_at, points, car_paths):
+    def build_path_using_cars(self, start_at, points, profile=None, max_wait=None, dwell=None, driver_tolerance=None,
+                              max_transfers=None, transfer_penalty=None):
         return [points[0], points[-1]]
 
-    def load(self, _s: str):
+    def build_journeys_using_cars(self, start_at, start, end, profile=None, max_wait=None, dwell=None,
+                                  driver_tolerance=None, max_transfers=None):
+        return []
+
+    def add_car_path(self, car_path: MapCarPath):
+        return
+
+    def update_car_path(self, car_path: MapCarPath):
+        return
+
+    def remove_car_path(self, _id: int):
+        return
+
+    def match_detour(self, path_id, start, end, seats=1, max_detour=None, max_detour_percent=None, profile=None):
+        return None
+
+    def match_batch(self, path_ids, requests, max_detour=None, max_detour_percent=None, profile=None):
+        return [None] * len(requests)
+
+    def commit_reservation(self, path_id, from_point, to_point, seats=1):
+        return 0
+
+    def cancel_reservation(self, _id: int):
+        return
+
+    def load(self, _s: str, bbox=None, polygon=None):
+        return
+
+    def save_snapshot(self, _s: str):
+        return
+
+    def load_snapshot(self, _s: str):
+        return
+
+    def prepare_hierarchies(self, profiles=None):
+        return
+
+    def prepare_landmarks(self, profiles=None, count=8):
         return
 
@@ -47,7 +47,11 @@ class UserPath(models.Model):
         }
 
     def to_car_path(self):
-        return MapCarPath(self.id, round(self.starts_at.timestamp()), [p.to_map_point() for p in self.points.all()])
+        points = [p.to_map_point() for p in self.points.all()]
+        seats = None
+        if self.transport_id is not None:
+            seats = [self.transport.place] * max(len(points) - 1, 0)
+        return MapCarPath(self.id, round(self.starts_at.timestamp()), points, seats)
 
 
 class PathPoint(models.Model):
@@ -15,4 +15,7 @@ urlpatterns = [
     path('user_paths', views.user_paths, name='user_paths'),
     path('user_map_view', views.user_map_view, name='user_map_view'),
     path('build_user_path', views.build_user_path, name='build_user_path'),
+    path('build_user_journeys', views.build_user_journeys, name='build_user_journeys'),
+    path('match_detour', views.match_detour, name='match_detour'),
+    path('match_batch', views.match_batch, name='match_batch'),
 ]
@@ -6,7 +6,7 @@ from django.utils import timezone
 from django.utils.dateparse import parse_datetime
 from django.views.decorators.csrf import csrf_exempt
 import json
-from web_map.map_manager import MapPoint, MapManager
+from web_map.map_manager import MapPoint, MapRideRequest, MapManager, CarPathError
 from .models import Transport
 from web_map.models import PathPoint, UserPath
 
@@ -91,6 +91,10 @@ def path_publish(req: HttpRequest):
         PathPoint.objects.bulk_create([
             PathPoint(osm_id=p.id, lat=p.lat, lon=p.lon, user_path=user_path) for p in path_points
         ], batch_size=200)
+        try:
+            MapManager.get_service().add_car_path(user_path.to_car_path())
+        except CarPathError as e:
+            return HttpResponseBadRequest(str(e))
 
         return redirect('map')
 
@@ -117,13 +121,81 @@ def build_user_path(req: HttpRequest):
         points = [MapPoint(v.get('id', 0), v['lat'], v['lon']) for v in points_json]
 
         paths = list(p for p in UserPath.objects.all().prefetch_related('points').order_by('id').reverse())
-        ppaths = [p.to_car_path() for p in paths]
-        path = MapManager.get_service().build_path_using_cars(round(start_at.timestamp()), points, ppaths)
+        # boarding and transfer penalty times in seconds, service defaults are used for missing ones
+        keys = ('max_wait', 'dwell', 'driver_tolerance', 'max_transfers', 'transfer_penalty')
+        boarding = {k: int(req.POST[k]) for k in keys if req.POST.get(k)}
+        path = MapManager.get_service().build_path_using_cars(round(start_at.timestamp()), points, **boarding)
 
         return JsonResponse({'car_paths': [p.to_json() for p in paths], 'path': path.to_json()}, safe=False)
     return HttpResponseBadRequest()
 
 
+@csrf_exempt
+def build_user_journeys(req: HttpRequest):
+    """
+        Строит варианты маршрута между первой и последней точками запроса: самый быстрый,
+        с наименьшей ходьбой пешком и с наименьшим числом пересадок.
+    """
+    if req.method == 'POST':
+        points_json = json.loads(req.POST['points'])
+        start_at = parse_datetime(req.POST.get('start_at', timezone.now().isoformat()))
+
+        points = [MapPoint(v.get('id', 0), v['lat'], v['lon']) for v in points_json]
+        if len(points) < 2:
+            return HttpResponseBadRequest()
+
+        keys = ('max_wait', 'dwell', 'driver_tolerance', 'max_transfers')
+        boarding = {k: int(req.POST[k]) for k in keys if req.POST.get(k)}
+        journeys = MapManager.get_service().build_journeys_using_cars(
+            round(start_at.timestamp()), points[0], points[-1], **boarding
+        )
+
+        return JsonResponse({'journeys': [j.to_json() for j in journeys]}, safe=False)
+    return HttpResponseBadRequest()
+
+
+@csrf_exempt
+def match_detour(req: HttpRequest):
+    """
+        Ищет объезд водителя по маршруту path_id, чтобы забрать пассажира в первой точке запроса
+        и высадить в последней.
+    """
+    if req.method == 'POST':
+        points = [MapPoint(v.get('id', 0), v['lat'], v['lon']) for v in json.loads(req.POST['points'])]
+        if len(points) < 2:
+            return HttpResponseBadRequest()
+
+        # max_detour in seconds, max_detour_percent of the driver path time
+        limits = {k: int(req.POST[k]) for k in ('max_detour', 'max_detour_percent') if req.POST.get(k)}
+        try:
+            found = MapManager.get_service().match_detour(int(req.POST['path_id']), points[0], points[-1], **limits)
+        except CarPathError as e:
+            return HttpResponseBadRequest(str(e))
+
+        return JsonResponse({'detour': found.to_json() if found else None}, safe=False)
+    return HttpResponseBadRequest()
+
+
+@csrf_exempt
+def match_batch(req: HttpRequest):
+    """
+        Распределяет пассажиров по водителям маршрутов path_ids, водитель берёт пассажиров,
+        пока есть свободные места и объезды для всех них укладываются в ограничения.
+    """
+    if req.method == 'POST':
+        body = json.loads(req.body)
+        point = lambda v: MapPoint(v.get('id', 0), v['lat'], v['lon'])
+        requests = [MapRideRequest(point(r['start']), point(r['end']), r.get('seats', 1)) for r in body['requests']]
+        limits = {k: int(body[k]) for k in ('max_detour', 'max_detour_percent') if body.get(k)}
+        try:
+            found = MapManager.get_service().match_batch(body['path_ids'], requests, **limits)
+        except CarPathError as e:
+            return HttpResponseBadRequest(str(e))
+
+        return JsonResponse({'detours': [m.to_json() if m else None for m in found]}, safe=False)
+    return HttpResponseBadRequest()
+
+
 @login_required
 def user_map_view(req: HttpRequest):
     return render(req, 'user_map_view.html')